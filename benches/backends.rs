@@ -0,0 +1,67 @@
+// 用两个后端分别运行 benches/programs/ 下的每个程序，由 criterion 计时和统计。
+// 程序在进程内运行，从扫描一直到执行结束，print 的输出丢进 io::sink()，不计进程启动和读文件：
+//
+//     cargo bench                   # 所有程序，两个后端
+//     cargo bench -- fib            # 只跑名字里带 fib 的
+//     cargo bench -- /vm            # 只跑虚拟机
+//
+// `cargo test --benches` 也会运行它，这时每个程序只跑一次，只检查能不能跑完
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rjlox::vm::Vm;
+use rjlox::{run_source, Interpreter, Parser, Resolver, Scanner};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn programs() -> Vec<(String, String)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/programs");
+    let mut programs: Vec<(String, String)> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+            (name, fs::read_to_string(&path).unwrap())
+        })
+        .collect();
+    programs.sort();
+    programs
+}
+
+fn tree(source: &str) {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_writer(Box::new(io::sink()));
+    if let Err(errors) = run_source(source, &mut interpreter) {
+        panic!("tree backend failed: {errors:?}");
+    }
+}
+
+// 和命令行一样，虚拟机也用解释器做变量解析
+fn vm(source: &str) {
+    let tokens = Scanner::new(source.to_string()).scan_tokens();
+    let (statements, errors) = Parser::new(tokens).parse();
+    assert!(errors.is_empty(), "{errors:?}");
+    Resolver::new(&mut Interpreter::new(), true)
+        .resolve(&statements)
+        .unwrap();
+
+    let mut vm = Vm::new();
+    vm.set_writer(Box::new(io::sink()));
+    if let Err(error) = vm.interpret(&statements) {
+        panic!("vm backend failed: {error}");
+    }
+}
+
+fn backends(c: &mut Criterion) {
+    for (name, source) in programs() {
+        let mut group = c.benchmark_group(&name);
+        group.sample_size(10);
+        group.bench_function("tree", |b| b.iter(|| tree(&source)));
+        group.bench_function("vm", |b| b.iter(|| vm(&source)));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, backends);
+criterion_main!(benches);
@@ -0,0 +1,183 @@
+use super::expr;
+use super::expr::{Acceptor as ExprAcceptor, Expr};
+use super::stmt;
+use super::stmt::{Acceptor as StmtAcceptor, Stmt};
+use super::token::{format_number, Literal, Span, Token, TokenType};
+use std::rc::Rc;
+
+// 把语法树打印成带括号的前缀形式，比如 `(+ 1 (* 2 3))`，用来检查优先级和 for 循环的脱糖结果。
+// 每条语句占一行，块、分支和函数体里的语句缩进两格
+#[derive(Default)]
+pub struct AstPrinter {
+    spans: bool, // 每个节点后面加上它在源码中的范围，比如 `(+ 1@1:7-1:8 2@1:11-1:12)@1:7-1:12`
+}
+
+impl AstPrinter {
+    pub fn with_spans() -> AstPrinter {
+        AstPrinter { spans: true }
+    }
+
+    pub fn print(&mut self, stmts: &[Stmt]) -> String {
+        stmts.iter().map(|stmt| self.stmt(stmt) + "\n").collect()
+    }
+
+    // REPL 里不带分号的单个表达式
+    pub fn print_expr(&mut self, expr: &Expr) -> String {
+        self.expr(expr)
+    }
+
+    // 只占一行的简写：复合语句只保留第一行，比如 `(while (< i 3) ...)`
+    pub fn print_compact(&mut self, stmt: &Stmt) -> String {
+        let text = self.stmt(stmt);
+        match text.split_once('\n') {
+            Some((first, _)) => format!("{first} ...)"),
+            None => text,
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> String {
+        let text = expr.accept(self);
+        self.with_span(text, expr.span())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> String {
+        let text = stmt.accept(self);
+        self.with_span(text, stmt.span())
+    }
+
+    fn with_span(&self, text: String, span: Span) -> String {
+        if self.spans {
+            format!("{text}@{span}")
+        } else {
+            text
+        }
+    }
+
+    fn parenthesize(&mut self, name: &str, exprs: &[&Expr]) -> String {
+        let mut text = format!("({name}");
+        for expr in exprs {
+            text.push(' ');
+            text.push_str(&self.expr(expr));
+        }
+        text.push(')');
+        text
+    }
+
+    // head 之后的每条语句单独一行并缩进，最后补上右括号
+    fn nested(&mut self, head: String, stmts: &[&Stmt]) -> String {
+        let mut text = head;
+        for stmt in stmts {
+            let stmt = self.stmt(stmt);
+            for line in stmt.lines() {
+                text.push_str("\n  ");
+                text.push_str(line);
+            }
+        }
+        text.push(')');
+        text
+    }
+}
+
+// 和错误信息里的写法一致：`at 'x'`，文件末尾是 `at end`
+fn location(token: &Token) -> String {
+    match token.token_type {
+        TokenType::EOF => String::from("at end"),
+        _ => format!("at '{}'", token.lexeme),
+    }
+}
+
+impl expr::Visitor<String> for AstPrinter {
+    fn visit_binary_expr(&mut self, left: &Expr, operator: &Token, right: &Expr) -> String {
+        self.parenthesize(&operator.lexeme, &[left, right])
+    }
+
+    fn visit_grouping_expr(&mut self, expression: &Expr) -> String {
+        self.parenthesize("group", &[expression])
+    }
+
+    fn visit_literal_expr(&mut self, expr: &Literal) -> String {
+        match expr {
+            Literal::Str(string) => format!("{string:?}"), // 加上引号，和变量名区分开
+            Literal::Num(num) => format_number(*num),
+            literal => literal.to_string(),
+        }
+    }
+
+    fn visit_unary_expr(&mut self, operator: &Token, right: &Expr) -> String {
+        self.parenthesize(&operator.lexeme, &[right])
+    }
+
+    fn visit_var_expr(&mut self, _id: usize, name: &Token) -> String {
+        name.lexeme.to_string()
+    }
+
+    fn visit_assign_expr(&mut self, _id: usize, name: &Token, value: &Expr) -> String {
+        self.parenthesize(&format!("= {}", name.lexeme), &[value])
+    }
+
+    fn visit_logic_expr(&mut self, left: &Expr, operator: &Token, right: &Expr) -> String {
+        self.parenthesize(&operator.lexeme, &[left, right])
+    }
+
+    fn visit_call_expr(&mut self, callee: &Expr, _paren: &Token, arguments: &Vec<Expr>) -> String {
+        let exprs: Vec<&Expr> = std::iter::once(callee).chain(arguments).collect();
+        self.parenthesize("call", &exprs)
+    }
+
+    fn visit_error_expr(&mut self, token: &Token) -> String {
+        format!("(error {})", location(token))
+    }
+}
+
+impl stmt::Visitor<String> for AstPrinter {
+    fn visit_expression_stmt(&mut self, expression: &Expr) -> String {
+        self.parenthesize(";", &[expression])
+    }
+
+    fn visit_print_stmt(&mut self, expression: &Expr) -> String {
+        self.parenthesize("print", &[expression])
+    }
+
+    fn visit_var_stmt(&mut self, name: &Token, expression: &Option<Expr>) -> String {
+        let exprs: Vec<&Expr> = expression.iter().collect();
+        self.parenthesize(&format!("var {}", name.lexeme), &exprs)
+    }
+
+    fn visit_block_stmt(&mut self, stmts: &Vec<Stmt>) -> String {
+        let stmts: Vec<&Stmt> = stmts.iter().collect();
+        self.nested(String::from("(block"), &stmts)
+    }
+
+    fn visit_if_stmt(
+        &mut self,
+        condition: &Expr,
+        then_branch: &Stmt,
+        else_branch: &Option<Box<Stmt>>,
+    ) -> String {
+        let head = format!("(if {}", self.expr(condition));
+        let branches: Vec<&Stmt> = std::iter::once(then_branch)
+            .chain(else_branch.as_deref())
+            .collect();
+        self.nested(head, &branches)
+    }
+
+    fn visit_while_stmt(&mut self, _keyword: &Token, condition: &Expr, body: &Stmt) -> String {
+        let head = format!("(while {}", self.expr(condition));
+        self.nested(head, &[body])
+    }
+
+    fn visit_fun_stmt(&mut self, name: &Token, params: &Rc<[Token]>, body: &Rc<[Stmt]>) -> String {
+        let params: Vec<&str> = params.iter().map(|param| &*param.lexeme).collect();
+        let head = format!("(fun {} ({})", name.lexeme, params.join(" "));
+        let body: Vec<&Stmt> = body.iter().collect();
+        self.nested(head, &body)
+    }
+
+    fn visit_return_stmt(&mut self, _keyword: &Token, value: &Expr) -> String {
+        self.parenthesize("return", &[value])
+    }
+
+    fn visit_error_stmt(&mut self, token: &Token) -> String {
+        format!("(error {})", location(token))
+    }
+}
@@ -1,5 +1,5 @@
 use super::error::Result;
-use super::interpreter::Interpreter;
+use super::interpreter::{Interpreter, Unwind};
 use super::object::Object;
 use super::token::Literal;
 use std::fmt;
@@ -9,32 +9,56 @@ use crate::environment::Environment;
 use crate::error::Error;
 use crate::stmt::Stmt;
 use crate::token::Token;
+use crate::vm::Closure;
+use chrono::format::{Item, StrftimeItems};
 use chrono::prelude::*;
 
 pub(crate) trait LoxCallable {
     fn arity(&self) -> usize;
-    fn call(&self, interpreter: &mut Interpreter, arguments: Vec<Object>) -> Result<Object>;
+    // 可选参数的个数下限，默认与 arity 相同（即参数个数固定）
+    fn min_arity(&self) -> usize {
+        self.arity()
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        arguments: Vec<Object>,
+    ) -> Result<Object>;
 }
 
 #[derive(Debug, Clone)]
 pub enum Callable {
-    Function(Function),
-    Clock,
+    Function(Rc<Function>), // 函数值按引用比较是否相等，复制函数值时也不用复制函数体
+    Closure(Rc<Closure>),   // 字节码虚拟机里的函数值，只由虚拟机调用
+    Native(Rc<NativeFn>),   // 用 Interpreter::define_native 从 Rust 定义的函数
+    Substring,
+    IndexOf,
+    Replace,
+    CharAt,
+    Ord,
+    Chr,
+    Assert,
+    Now,
+    Random,
+    FormatTime,
+    UtcFormatTime,
+    Breakpoint,
 }
 
 #[derive(Debug, Clone)]
 pub struct Function {
     name: Token,
-    params: Vec<Token>,
-    body: Vec<Stmt>,
+    params: Rc<[Token]>,
+    body: Rc<[Stmt]>,
     closure: Rc<Environment>, // 闭包就是*函数定义*所在的作用域，函数在运行时并不知道自己是谁
 }
 
 impl Function {
     pub fn new(
         name: Token,
-        params: Vec<Token>,
-        body: Vec<Stmt>,
+        params: Rc<[Token]>,
+        body: Rc<[Stmt]>,
         closure: Rc<Environment>,
     ) -> Function {
         Function {
@@ -44,13 +68,77 @@ impl Function {
             closure,
         }
     }
+
+    pub fn closure(&self) -> &Rc<Environment> {
+        &self.closure
+    }
+
+    pub fn into_closure(self) -> Rc<Environment> {
+        self.closure
+    }
+}
+
+// 宿主程序定义的原生函数的函数体。出错时返回 Error::NativeError，由调用处补上调用的位置
+pub type NativeBody = dyn Fn(&mut Interpreter, Vec<Object>) -> Result<Object>;
+
+pub struct NativeFn {
+    name: Rc<str>,
+    arity: usize,
+    body: Box<NativeBody>,
+}
+
+impl NativeFn {
+    pub fn new(
+        name: &str,
+        arity: usize,
+        body: impl Fn(&mut Interpreter, Vec<Object>) -> Result<Object> + 'static,
+    ) -> NativeFn {
+        NativeFn {
+            name: name.into(),
+            arity,
+            body: Box::new(body),
+        }
+    }
+
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        arguments: Vec<Object>,
+    ) -> Result<Object> {
+        (self.body)(interpreter, arguments).map_err(|error| match error {
+            Error::NativeError(message) => Error::RuntimeError(paren.clone(), message),
+            error => error,
+        })
+    }
+}
+
+impl fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
 }
 
 impl fmt::Display for Callable {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Callable::Clock => write!(f, "<native fn>"),
             Callable::Function(function) => write!(f, "<fn {}>", function.name.lexeme),
+            Callable::Closure(closure) => write!(f, "<fn {}>", closure.function.name),
+            Callable::Native(native) => write!(f, "{native:?}"),
+            _ => write!(f, "<native fn>"),
+        }
+    }
+}
+
+// 同一个函数声明每执行一次都会得到一个新的函数值，只有同一个函数值才相等；
+// 原生函数各只有一个
+impl PartialEq for Callable {
+    fn eq(&self, other: &Callable) -> bool {
+        match (self, other) {
+            (Callable::Function(a), Callable::Function(b)) => Rc::ptr_eq(a, b),
+            (Callable::Closure(a), Callable::Closure(b)) => Rc::ptr_eq(a, b),
+            (Callable::Native(a), Callable::Native(b)) => Rc::ptr_eq(a, b),
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
         }
     }
 }
@@ -58,40 +146,340 @@ impl fmt::Display for Callable {
 impl LoxCallable for Callable {
     fn arity(&self) -> usize {
         match self {
-            Callable::Clock => 0,
+            Callable::Substring => 3,
+            Callable::IndexOf => 2,
+            Callable::Replace => 3,
+            Callable::CharAt => 2,
+            Callable::Ord => 1,
+            Callable::Chr => 1,
+            Callable::Assert => 2,
+            Callable::Now => 0,
+            Callable::Random => 0,
+            Callable::FormatTime => 2,
+            Callable::UtcFormatTime => 2,
+            Callable::Breakpoint => 0,
             Callable::Function(function) => function.params.len(),
+            Callable::Closure(closure) => closure.function.arity,
+            Callable::Native(native) => native.arity,
         }
     }
 
-    fn call(&self, interpreter: &mut Interpreter, arguments: Vec<Object>) -> Result<Object> {
+    fn min_arity(&self) -> usize {
+        match self {
+            Callable::Assert => 1,
+            _ => self.arity(),
+        }
+    }
+
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        arguments: Vec<Object>,
+    ) -> Result<Object> {
         match self {
-            Callable::Clock => {
-                let now = Local::now().timestamp_millis() / 1000_i64;
-                Ok(Object::Literal(Literal::Num(now as f32)))
-            }
             Callable::Function(function) => {
-                // 每个函数调用都有自己的环境来存储参数变量（运行时）
-                let env = Environment::new(Some(function.closure.clone()));
-                for i in 0..arguments.len() {
-                    env.define(
-                        function.params.get(i).cloned().unwrap().lexeme,
-                        arguments.get(i).unwrap(),
-                    );
+                interpreter.check_interrupt(paren)?;
+                // 不管函数怎么结束都要出栈，return 也是通过 Err 传出来的
+                interpreter.push_frame(function.name.lexeme.clone(), paren)?;
+                let result = function.call(interpreter, arguments);
+                interpreter.pop_frame(&result);
+                result
+            }
+            // 字节码虚拟机的函数只能在虚拟机里运行。嵌入解释器的程序可能把它交给树遍历解释器
+            Callable::Closure(_) => Err(Error::RuntimeError(
+                paren.to_owned(),
+                String::from("Cannot call a VM closure from the tree interpreter."),
+            )),
+            Callable::Native(native) => native.call(interpreter, paren, arguments),
+            // 时间和随机数从解释器上取，测试和 --deterministic 可以换成固定的
+            Callable::Now => {
+                let now = interpreter.time().now();
+                Ok(Object::Literal(Literal::Num(now as f64)))
+            }
+            Callable::Random => {
+                let random = interpreter.random().random();
+                Ok(Object::Literal(Literal::Num(random)))
+            }
+            Callable::Breakpoint => {
+                interpreter.breakpoint(paren);
+                Ok(Object::Literal(Literal::Nil))
+            }
+            _ => self.call_native(paren, arguments),
+        }
+    }
+}
+
+impl Callable {
+    // 参数个数不对时在调用处报错，两个后端共用
+    pub(crate) fn check_arity(&self, paren: &Token, count: usize) -> Result<()> {
+        if count >= self.min_arity() && count <= self.arity() {
+            return Ok(());
+        }
+
+        let expected = if self.min_arity() == self.arity() {
+            self.arity().to_string()
+        } else {
+            format!("{} to {}", self.min_arity(), self.arity())
+        };
+        let message = format!("Expected {} arguments but got {}.", expected, count);
+        Err(Error::RuntimeError(paren.to_owned(), message))
+    }
+
+    // 内置的原生函数不依赖解释器的状态
+    fn call_native(&self, paren: &Token, arguments: Vec<Object>) -> Result<Object> {
+        match self {
+            Callable::Substring => {
+                let string = string_arg("substring", paren, &arguments, 0)?;
+                let start = index_arg("substring", paren, &arguments, 1)?;
+                let end = index_arg("substring", paren, &arguments, 2)?;
+
+                // 按字符（而不是字节）计算下标，左闭右开，越界时截断到合法范围
+                let len = string.chars().count() as i64;
+                let start = start.clamp(0, len) as usize;
+                let end = end.clamp(0, len) as usize;
+                let result = if start < end {
+                    string.chars().skip(start).take(end - start).collect()
+                } else {
+                    String::new()
+                };
+
+                Ok(Object::Literal(Literal::Str(result.into())))
+            }
+            Callable::IndexOf => {
+                let haystack = string_arg("indexOf", paren, &arguments, 0)?;
+                let needle = string_arg("indexOf", paren, &arguments, 1)?;
+
+                // find 返回的是字节偏移，需要换算成字符下标
+                let index = match haystack.find(needle) {
+                    Some(offset) => haystack[..offset].chars().count() as f64,
+                    None => -1.0,
+                };
+
+                Ok(Object::Literal(Literal::Num(index)))
+            }
+            Callable::Replace => {
+                let string = string_arg("replace", paren, &arguments, 0)?;
+                let from = string_arg("replace", paren, &arguments, 1)?;
+                let to = string_arg("replace", paren, &arguments, 2)?;
+
+                if from.is_empty() {
+                    return Err(native_error(
+                        "replace",
+                        paren,
+                        "argument 2 must not be an empty string.",
+                    ));
                 }
 
-                // 函数调用时通过 Error::Return 判断遇到了 return 语句，立刻返回 return 的值
-                match interpreter.execute_block(&function.body, env) {
-                    Err(e) => {
-                        return match e {
-                            Error::Return(object) => Ok(object),
-                            _ => Err(e),
-                        }
-                    }
-                    _ => (),
+                Ok(Object::Literal(Literal::Str(
+                    string.replace(from, to).into(),
+                )))
+            }
+            Callable::CharAt => {
+                let string = string_arg("charAt", paren, &arguments, 0)?;
+                let index = index_arg("charAt", paren, &arguments, 1)?;
+
+                let c = usize::try_from(index)
+                    .ok()
+                    .and_then(|index| string.chars().nth(index));
+                match c {
+                    Some(c) => Ok(Object::Literal(Literal::Str(c.to_string().into()))),
+                    None => Err(native_error("charAt", paren, "index out of range.")),
                 }
+            }
+            Callable::Ord => {
+                let string = string_arg("ord", paren, &arguments, 0)?;
 
-                Ok(Object::Literal(Literal::Nil))
+                let mut chars = string.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(Object::Literal(Literal::Num(c as u32 as f64))),
+                    _ => Err(native_error(
+                        "ord",
+                        paren,
+                        "argument 1 must be a single-character string.",
+                    )),
+                }
+            }
+            Callable::Chr => {
+                let code = index_arg("chr", paren, &arguments, 0)?;
+
+                // 代理区（U+D800..U+DFFF）和超出 U+10FFFF 的值都不是合法的 Unicode 标量值
+                let c = u32::try_from(code).ok().and_then(char::from_u32);
+                match c {
+                    Some(c) => Ok(Object::Literal(Literal::Str(c.to_string().into()))),
+                    None => Err(native_error(
+                        "chr",
+                        paren,
+                        "argument 1 must be a valid Unicode scalar value.",
+                    )),
+                }
+            }
+            Callable::Assert => {
+                if arguments[0].is_truthy() {
+                    return Ok(Object::Literal(Literal::Nil));
+                }
+
+                let message = match arguments.get(1) {
+                    Some(message) => format!("Assertion failed: {message}"),
+                    None => String::from("Assertion failed."),
+                };
+                Err(Error::RuntimeError(paren.clone(), message))
+            }
+            Callable::FormatTime => {
+                let millis = index_arg("formatTime", paren, &arguments, 0)?;
+                let format = string_arg("formatTime", paren, &arguments, 1)?;
+
+                match Local.timestamp_millis_opt(millis).single() {
+                    Some(time) => format_time("formatTime", paren, time, format),
+                    None => Err(native_error(
+                        "formatTime",
+                        paren,
+                        "argument 1 is out of range.",
+                    )),
+                }
+            }
+            Callable::UtcFormatTime => {
+                let millis = index_arg("utcFormatTime", paren, &arguments, 0)?;
+                let format = string_arg("utcFormatTime", paren, &arguments, 1)?;
+
+                match Utc.timestamp_millis_opt(millis).single() {
+                    Some(time) => format_time("utcFormatTime", paren, time, format),
+                    None => Err(native_error(
+                        "utcFormatTime",
+                        paren,
+                        "argument 1 is out of range.",
+                    )),
+                }
+            }
+            Callable::Function(_)
+            | Callable::Closure(_)
+            | Callable::Native(_)
+            | Callable::Now
+            | Callable::Random
+            | Callable::Breakpoint => {
+                unreachable!("not a built-in native function")
+            }
+        }
+    }
+}
+
+impl Function {
+    fn call(&self, interpreter: &mut Interpreter, mut arguments: Vec<Object>) -> Result<Object> {
+        // 尾调用的函数替换掉当前的函数，在这个循环里接着执行
+        let mut tail_callee: Option<Rc<Function>> = None;
+        loop {
+            let function = tail_callee.as_deref().unwrap_or(self);
+            interpreter.trace_call(&function.name.lexeme, &arguments);
+            interpreter.profile_enter(&function.name.lexeme, function.name.line);
+
+            // 每个函数调用都有自己的环境来存储参数变量（运行时）
+            let env = Environment::new(Some(function.closure.clone()));
+            let debugging = interpreter.debugging();
+            for (param, argument) in function.params.iter().zip(&arguments) {
+                env.define_slot(argument);
+                if debugging {
+                    env.name_slot(param.lexeme.clone());
+                }
             }
+
+            // 函数调用时通过 Unwind::Return 判断遇到了 return 语句，立刻返回 return 的值
+            let value = match interpreter.execute_block(&function.body, env) {
+                Ok(()) => Object::Literal(Literal::Nil),
+                Err(Unwind::Return(object)) => object,
+                Err(Unwind::TailCall(callee, paren, args)) => {
+                    interpreter.profile_exit();
+                    interpreter.check_interrupt(&paren)?; // 尾递归不经过 LoxCallable::call
+                    interpreter.replace_frame(callee.name.lexeme.clone(), &paren);
+                    tail_callee = Some(callee);
+                    arguments = args;
+                    continue;
+                }
+                Err(Unwind::Error(e)) => {
+                    interpreter.profile_exit();
+                    return Err(e);
+                }
+            };
+            interpreter.profile_exit();
+            interpreter.trace_return(&function.name.lexeme, &value);
+            return Ok(value);
         }
     }
 }
+
+// 原生函数都放在这个冻结的环境里，两个后端的全局环境都以它为外层
+pub fn prelude() -> Rc<Environment> {
+    let mut prelude = Environment::new(None);
+    let clock = NativeFn::new("clock", 0, |interpreter, _| {
+        Ok(Object::Literal(Literal::Num(interpreter.time().clock())))
+    });
+    let clock = Callable::Native(Rc::new(clock));
+    prelude.define("clock".into(), &Object::Callable(clock));
+    prelude.define("substring".into(), &Object::Callable(Callable::Substring));
+    prelude.define("indexOf".into(), &Object::Callable(Callable::IndexOf));
+    prelude.define("replace".into(), &Object::Callable(Callable::Replace));
+    prelude.define("charAt".into(), &Object::Callable(Callable::CharAt));
+    prelude.define("ord".into(), &Object::Callable(Callable::Ord));
+    prelude.define("chr".into(), &Object::Callable(Callable::Chr));
+    prelude.define("assert".into(), &Object::Callable(Callable::Assert));
+    prelude.define("now".into(), &Object::Callable(Callable::Now));
+    prelude.define("random".into(), &Object::Callable(Callable::Random));
+    prelude.define("formatTime".into(), &Object::Callable(Callable::FormatTime));
+    prelude.define(
+        "utcFormatTime".into(),
+        &Object::Callable(Callable::UtcFormatTime),
+    );
+    prelude.define("breakpoint".into(), &Object::Callable(Callable::Breakpoint));
+    prelude.freeze();
+
+    Rc::new(prelude)
+}
+
+fn native_error(name: &str, paren: &Token, message: &str) -> Error {
+    Error::RuntimeError(paren.clone(), format!("{name}: {message}"))
+}
+
+fn string_arg<'a>(
+    name: &str,
+    paren: &Token,
+    arguments: &'a [Object],
+    index: usize,
+) -> Result<&'a str> {
+    match arguments.get(index) {
+        Some(Object::Literal(Literal::Str(string))) => Ok(string),
+        _ => Err(native_error(
+            name,
+            paren,
+            &format!("argument {} must be a string.", index + 1),
+        )),
+    }
+}
+
+fn index_arg(name: &str, paren: &Token, arguments: &[Object], index: usize) -> Result<i64> {
+    match arguments.get(index) {
+        Some(Object::Literal(Literal::Num(num))) if num.fract() == 0.0 => Ok(*num as i64),
+        _ => Err(native_error(
+            name,
+            paren,
+            &format!("argument {} must be an integer.", index + 1),
+        )),
+    }
+}
+
+fn format_time<Tz>(name: &str, paren: &Token, time: DateTime<Tz>, format: &str) -> Result<Object>
+where
+    Tz: TimeZone,
+    Tz::Offset: fmt::Display,
+{
+    // 非法的格式说明符会让 chrono 在格式化时 panic，所以要先检查一遍
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return Err(native_error(
+            name,
+            paren,
+            &format!("invalid format string '{format}'."),
+        ));
+    }
+
+    let formatted = time.format_with_items(items.into_iter()).to_string();
+    Ok(Object::Literal(Literal::Str(formatted.into())))
+}
@@ -0,0 +1,66 @@
+use super::object::Object;
+use super::token::Token;
+
+use std::rc::Rc;
+
+// 字节码指令。跳转的目标直接是指令的下标，局部变量的下标相对于当前调用帧在栈上的起点
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Constant(usize),
+    Nil,
+    Uninitialized, // `var a;` 声明的变量在赋值之前的值
+    Pop,
+    GetLocal(usize),
+    SetLocal(usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    // 全局变量的名字就是这条指令的 token
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    Jump(usize),
+    JumpIfFalse(usize), // 条件留在栈上，and/or 还要用它作为表达式的值
+    // argc 是实参个数；被调用的是变量时 callee 是变量名在常量表里的下标，报错时用
+    Call { argc: usize, callee: Option<usize> },
+    TailCall { argc: usize, callee: Option<usize> }, // `return f(...);`，复用当前的调用帧
+    Closure(usize),                                  // Chunk::functions 的下标
+    CloseUpvalue,                                    // 离开作用域的变量被闭包捕获了，移到堆上
+    Return,
+}
+
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub tokens: Vec<Option<Token>>, // 每条指令对应的 token，运行时错误报告在这里
+    pub constants: Vec<Object>,
+    pub functions: Vec<Rc<Prototype>>, // 函数体里声明的函数
+}
+
+// 闭包从外层函数捕获的变量：外层函数的局部变量，或者外层函数自己捕获的变量
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    Local(usize),
+    Upvalue(usize),
+}
+
+// 编译好的函数。每次执行函数声明都会和捕获的变量一起组成一个新的闭包
+#[derive(Debug)]
+pub struct Prototype {
+    pub name: Rc<str>,
+    pub arity: usize,
+    pub chunk: Chunk,
+    pub captures: Vec<Capture>,
+}
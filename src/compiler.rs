@@ -0,0 +1,473 @@
+use super::chunk::{Capture, Chunk, Op, Prototype};
+use super::error::Result;
+use super::expr;
+use super::expr::{Acceptor as ExprAcceptor, Expr};
+use super::interpreter::syntax_error;
+use super::object::Object;
+use super::stmt;
+use super::stmt::{Acceptor as StmtAcceptor, Stmt};
+use super::token::{Literal, Token, TokenType};
+
+use std::rc::Rc;
+
+// 把解析器产生的语法树编译成字节码，交给 vm 执行。
+// 程序在这之前已经通过了解析器的检查（重复声明、在初始化表达式里读自己、顶层 return 等），
+// 这里不再重复报告这些错误
+
+struct Local {
+    name: Rc<str>,
+    depth: usize,
+    captured: bool, // 被闭包捕获的变量离开作用域时要移到堆上
+}
+
+// 正在编译的一个函数
+struct FunctionState {
+    prototype: Prototype,
+    locals: Vec<Local>, // 下标就是变量在调用帧里的位置，第 0 个位置放被调用的函数本身
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(name: Rc<str>, arity: usize) -> FunctionState {
+        FunctionState {
+            prototype: Prototype {
+                name,
+                arity,
+                chunk: Chunk::default(),
+                captures: Vec::new(),
+            },
+            locals: vec![Local {
+                name: "".into(),
+                depth: 0,
+                captured: false,
+            }],
+            scope_depth: 0,
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<usize> {
+        self.locals.iter().rposition(|local| &*local.name == name)
+    }
+
+    fn add_capture(&mut self, capture: Capture) -> usize {
+        let captures = &mut self.prototype.captures;
+        match captures.iter().position(|c| *c == capture) {
+            Some(index) => index,
+            None => {
+                captures.push(capture);
+                captures.len() - 1
+            }
+        }
+    }
+}
+
+pub struct Compiler {
+    functions: Vec<FunctionState>, // 最后一个是最内层的函数，第一个是脚本本身
+    token: Option<Token>,          // 最近遇到的 token，接下来生成的指令都对应它
+}
+
+impl Compiler {
+    fn new() -> Compiler {
+        Compiler {
+            functions: vec![FunctionState::new("script".into(), 0)],
+            token: None,
+        }
+    }
+
+    // 最后一条语句是表达式语句时，脚本返回它的值，和树遍历解释器一致
+    pub fn compile(statements: &[Stmt]) -> Result<Rc<Prototype>> {
+        let mut compiler = Compiler::new();
+        match statements.split_last() {
+            Some((Stmt::Expression { expression, .. }, rest)) => {
+                compiler.statements(rest)?;
+                compiler.expression(expression)?;
+            }
+            _ => {
+                compiler.statements(statements)?;
+                compiler.emit(Op::Nil);
+            }
+        }
+        compiler.emit(Op::Return);
+
+        Ok(Rc::new(compiler.functions.pop().unwrap().prototype))
+    }
+
+    // REPL 里单独输入的表达式编译成返回它的值的脚本
+    pub fn compile_expression(expr: &Expr) -> Result<Rc<Prototype>> {
+        let mut compiler = Compiler::new();
+        compiler.expression(expr)?;
+        compiler.emit(Op::Return);
+
+        Ok(Rc::new(compiler.functions.pop().unwrap().prototype))
+    }
+
+    fn statements(&mut self, statements: &[Stmt]) -> Result<()> {
+        for statement in statements {
+            statement.accept(self)?;
+        }
+
+        Ok(())
+    }
+
+    fn expression(&mut self, expr: &Expr) -> Result<()> {
+        expr.accept(self)
+    }
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.current().prototype.chunk
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let token = self.token.clone();
+        let chunk = self.chunk();
+        chunk.code.push(op);
+        chunk.tokens.push(token);
+        chunk.code.len() - 1
+    }
+
+    // 可能在运行时出错的指令都要带上出错位置的 token
+    fn emit_at(&mut self, op: Op, token: &Token) -> usize {
+        self.token = Some(token.clone());
+        self.emit(op)
+    }
+
+    fn constant(&mut self, value: Object) -> usize {
+        let constants = &mut self.chunk().constants;
+        constants.push(value);
+        constants.len() - 1
+    }
+
+    // 把跳转指令的目标改成下一条要生成的指令
+    fn patch(&mut self, jump: usize) {
+        let chunk = self.chunk();
+        let target = chunk.code.len();
+        chunk.code[jump] = match chunk.code[jump] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(target),
+            op => unreachable!("{op:?} is not a jump"),
+        };
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let function = self.current();
+        function.scope_depth -= 1;
+
+        let depth = function.scope_depth;
+        while let Some(local) = self.current().locals.last() {
+            if local.depth <= depth {
+                break;
+            }
+            let op = if local.captured {
+                Op::CloseUpvalue
+            } else {
+                Op::Pop
+            };
+            self.current().locals.pop();
+            self.emit(op);
+        }
+    }
+
+    // 变量的值已经在栈顶了，局部变量就地成为这个位置，全局变量按名字存起来
+    fn define_variable(&mut self, name: &Token) {
+        if self.current().scope_depth > 0 {
+            let depth = self.current().scope_depth;
+            self.current().locals.push(Local {
+                name: name.lexeme.clone(),
+                depth,
+                captured: false,
+            });
+        } else {
+            self.emit_at(Op::DefineGlobal, name);
+        }
+    }
+
+    // 在第 level 层函数外面找变量，找到时一路记下每一层要捕获的变量
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<usize> {
+        if level == 0 {
+            return None;
+        }
+
+        let enclosing = level - 1;
+        if let Some(slot) = self.functions[enclosing].resolve_local(name) {
+            self.functions[enclosing].locals[slot].captured = true;
+            return Some(self.functions[level].add_capture(Capture::Local(slot)));
+        }
+
+        let index = self.resolve_upvalue(enclosing, name)?;
+        Some(self.functions[level].add_capture(Capture::Upvalue(index)))
+    }
+
+    // 局部变量、捕获的变量、全局变量分别用哪两条指令读写
+    fn variable(&mut self, name: &Token) -> (Op, Op) {
+        if let Some(slot) = self.current().resolve_local(&name.lexeme) {
+            return (Op::GetLocal(slot), Op::SetLocal(slot));
+        }
+
+        let level = self.functions.len() - 1;
+        match self.resolve_upvalue(level, &name.lexeme) {
+            Some(index) => (Op::GetUpvalue(index), Op::SetUpvalue(index)),
+            None => (Op::GetGlobal, Op::SetGlobal),
+        }
+    }
+
+    fn function(&mut self, name: &Token, params: &[Token], body: &[Stmt]) -> Result<()> {
+        self.functions
+            .push(FunctionState::new(name.lexeme.clone(), params.len()));
+        self.begin_scope();
+        for param in params {
+            self.define_variable(param);
+        }
+        let result = self.statements(body);
+        self.emit(Op::Nil);
+        self.emit(Op::Return);
+        let function = self.functions.pop().unwrap();
+        result?;
+
+        let chunk = self.chunk();
+        chunk.functions.push(Rc::new(function.prototype));
+        let index = chunk.functions.len() - 1;
+        self.emit_at(Op::Closure(index), name);
+
+        Ok(())
+    }
+
+    // 被调用的是变量时记下变量名，报“不能调用”的错误时要用
+    fn call(&mut self, callee: &Expr, arguments: &[Expr]) -> Result<(usize, Option<usize>)> {
+        self.expression(callee)?;
+        for argument in arguments {
+            self.expression(argument)?;
+        }
+
+        let callee = match callee {
+            Expr::Variable { name, .. } => {
+                let name = Object::Literal(Literal::Str(name.lexeme.clone()));
+                Some(self.constant(name))
+            }
+            _ => None,
+        };
+
+        Ok((arguments.len(), callee))
+    }
+}
+
+impl expr::Visitor<Result<()>> for Compiler {
+    fn visit_binary_expr(&mut self, left: &Expr, operator: &Token, right: &Expr) -> Result<()> {
+        self.expression(left)?;
+        self.expression(right)?;
+
+        let op = match operator.token_type {
+            TokenType::EqualEqual => Op::Equal,
+            TokenType::BangEqual => Op::NotEqual,
+            TokenType::GREATER => Op::Greater,
+            TokenType::GreaterEqual => Op::GreaterEqual,
+            TokenType::LESS => Op::Less,
+            TokenType::LessEqual => Op::LessEqual,
+            TokenType::PLUS => Op::Add,
+            TokenType::MINUS => Op::Subtract,
+            TokenType::STAR => Op::Multiply,
+            TokenType::SLASH => Op::Divide,
+            _ => unreachable!("{:?} is not a binary operator", operator.token_type),
+        };
+        self.emit_at(op, operator);
+
+        Ok(())
+    }
+
+    fn visit_grouping_expr(&mut self, expression: &Expr) -> Result<()> {
+        self.expression(expression)
+    }
+
+    fn visit_literal_expr(&mut self, expr: &Literal) -> Result<()> {
+        if let Literal::Nil = expr {
+            self.emit(Op::Nil);
+        } else {
+            let index = self.constant(Object::Literal(expr.clone()));
+            self.emit(Op::Constant(index));
+        }
+
+        Ok(())
+    }
+
+    fn visit_unary_expr(&mut self, operator: &Token, right: &Expr) -> Result<()> {
+        self.expression(right)?;
+
+        let op = match operator.token_type {
+            TokenType::BANG => Op::Not,
+            _ => Op::Negate,
+        };
+        self.emit_at(op, operator);
+
+        Ok(())
+    }
+
+    fn visit_var_expr(&mut self, _id: usize, name: &Token) -> Result<()> {
+        let (get, _) = self.variable(name);
+        self.emit_at(get, name);
+
+        Ok(())
+    }
+
+    fn visit_assign_expr(&mut self, _id: usize, name: &Token, value: &Expr) -> Result<()> {
+        self.expression(value)?;
+        let (_, set) = self.variable(name);
+        self.emit_at(set, name);
+
+        Ok(())
+    }
+
+    fn visit_logic_expr(&mut self, left: &Expr, operator: &Token, right: &Expr) -> Result<()> {
+        self.expression(left)?;
+
+        // 左边已经决定了结果时跳过右边，左边的值就是整个表达式的值
+        let end = if operator.token_type == TokenType::OR {
+            let right_side = self.emit(Op::JumpIfFalse(0));
+            let end = self.emit(Op::Jump(0));
+            self.patch(right_side);
+            end
+        } else {
+            self.emit(Op::JumpIfFalse(0))
+        };
+        self.emit(Op::Pop);
+        self.expression(right)?;
+        self.patch(end);
+
+        Ok(())
+    }
+
+    fn visit_call_expr(
+        &mut self,
+        callee: &Expr,
+        paren: &Token,
+        arguments: &Vec<Expr>,
+    ) -> Result<()> {
+        let (argc, callee) = self.call(callee, arguments)?;
+        self.emit_at(Op::Call { argc, callee }, paren);
+
+        Ok(())
+    }
+
+    fn visit_error_expr(&mut self, token: &Token) -> Result<()> {
+        Err(syntax_error(token))
+    }
+}
+
+impl stmt::Visitor<Result<()>> for Compiler {
+    fn visit_expression_stmt(&mut self, expression: &Expr) -> Result<()> {
+        self.expression(expression)?;
+        self.emit(Op::Pop);
+
+        Ok(())
+    }
+
+    fn visit_print_stmt(&mut self, expression: &Expr) -> Result<()> {
+        self.expression(expression)?;
+        self.emit(Op::Print);
+
+        Ok(())
+    }
+
+    fn visit_var_stmt(&mut self, name: &Token, expression: &Option<Expr>) -> Result<()> {
+        match expression {
+            Some(expression) => self.expression(expression)?,
+            None => {
+                self.emit_at(Op::Uninitialized, name);
+            }
+        }
+        self.define_variable(name);
+
+        Ok(())
+    }
+
+    fn visit_block_stmt(&mut self, stmts: &Vec<Stmt>) -> Result<()> {
+        self.begin_scope();
+        let result = self.statements(stmts);
+        self.end_scope();
+
+        result
+    }
+
+    fn visit_if_stmt(
+        &mut self,
+        condition: &Expr,
+        then_branch: &Stmt,
+        else_branch: &Option<Box<Stmt>>,
+    ) -> Result<()> {
+        self.expression(condition)?;
+        let else_jump = self.emit(Op::JumpIfFalse(0));
+        self.emit(Op::Pop);
+        then_branch.accept(self)?;
+        let end_jump = self.emit(Op::Jump(0));
+
+        self.patch(else_jump);
+        self.emit(Op::Pop);
+        if let Some(else_branch) = else_branch {
+            else_branch.accept(self)?;
+        }
+        self.patch(end_jump);
+
+        Ok(())
+    }
+
+    fn visit_while_stmt(&mut self, _keyword: &Token, condition: &Expr, body: &Stmt) -> Result<()> {
+        let loop_start = self.chunk().code.len();
+        self.expression(condition)?;
+        let exit_jump = self.emit(Op::JumpIfFalse(0));
+        self.emit(Op::Pop);
+        body.accept(self)?;
+        self.emit(Op::Jump(loop_start));
+
+        self.patch(exit_jump);
+        self.emit(Op::Pop);
+
+        Ok(())
+    }
+
+    fn visit_fun_stmt(
+        &mut self,
+        name: &Token,
+        params: &Rc<[Token]>,
+        body: &Rc<[Stmt]>,
+    ) -> Result<()> {
+        // 局部函数先占好位置，函数体里才能递归地引用自己
+        if self.current().scope_depth > 0 {
+            self.define_variable(name);
+            self.function(name, params, body)
+        } else {
+            self.function(name, params, body)?;
+            self.define_variable(name);
+            Ok(())
+        }
+    }
+
+    fn visit_return_stmt(&mut self, keyword: &Token, value: &Expr) -> Result<()> {
+        // 和树遍历解释器一样，`return f(...);` 是尾调用，不会加深调用栈
+        if let Expr::Call {
+            callee,
+            paren,
+            arguments,
+            ..
+        } = value
+        {
+            let (argc, callee) = self.call(callee, arguments)?;
+            self.emit_at(Op::TailCall { argc, callee }, paren);
+            return Ok(());
+        }
+
+        self.expression(value)?;
+        self.emit_at(Op::Return, keyword);
+
+        Ok(())
+    }
+
+    fn visit_error_stmt(&mut self, token: &Token) -> Result<()> {
+        Err(syntax_error(token))
+    }
+}
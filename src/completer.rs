@@ -0,0 +1,118 @@
+use rjlox::scanner::{is_identifier_continue, KEYWORDS};
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+use std::rc::Rc;
+
+// REPL 里按 Tab 补全关键字和全局变量名。全局变量的名字是上一条输入执行完之后的快照，
+// 每执行完一条输入由 REPL 更新
+#[derive(Default)]
+pub struct LoxHelper {
+    names: Vec<Rc<str>>,
+}
+
+impl LoxHelper {
+    pub fn set_names(&mut self, names: Vec<Rc<str>>) {
+        self.names = names;
+    }
+
+    // 光标前正在输入的标识符的起始位置，以及能补全成的词，按字母顺序排列。
+    // 属性访问 `a.b` 还不存在，`.` 后面不补全
+    pub fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let before = &line[..pos];
+        let start = before
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| is_identifier_continue(*c))
+            .last()
+            .map_or(pos, |(i, _)| i);
+        let prefix = &before[start..];
+        if prefix.is_empty()
+            || prefix.starts_with(|c: char| c.is_ascii_digit())
+            || before[..start].ends_with('.')
+        {
+            return (pos, Vec::new());
+        }
+
+        let mut words: Vec<String> = KEYWORDS
+            .iter()
+            .copied()
+            .chain(self.names.iter().map(|name| &**name))
+            .filter(|word| word.starts_with(prefix))
+            .map(String::from)
+            .collect();
+        words.sort();
+        words.dedup();
+        (start, words)
+    }
+}
+
+impl Completer for LoxHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Hinter for LoxHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LoxHelper {}
+
+impl Validator for LoxHelper {}
+
+impl Helper for LoxHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper(names: &[&str]) -> LoxHelper {
+        let mut helper = LoxHelper::default();
+        helper.set_names(names.iter().map(|&name| Rc::from(name)).collect());
+        helper
+    }
+
+    #[test]
+    fn completes_keywords_and_globals() {
+        let helper = helper(&["counter", "clock", "print_all"]);
+        assert_eq!(
+            helper.candidates("var x = c", 9),
+            (
+                8,
+                vec![String::from("class"), "clock".into(), "counter".into()]
+            )
+        );
+        assert_eq!(
+            helper.candidates("pri", 3),
+            (0, vec![String::from("print"), "print_all".into()])
+        );
+    }
+
+    #[test]
+    fn completes_the_word_before_the_cursor() {
+        let helper = helper(&["total"]);
+        assert_eq!(
+            helper.candidates("print to + 1;", 8),
+            (6, vec![String::from("total")])
+        );
+    }
+
+    #[test]
+    fn nothing_to_complete() {
+        let helper = helper(&["a1"]);
+        assert_eq!(helper.candidates("print ", 6), (6, Vec::new()));
+        assert_eq!(helper.candidates("x = 1", 5), (5, Vec::new()));
+        assert_eq!(helper.candidates("a.a", 3), (3, Vec::new()));
+        assert_eq!(helper.candidates("zzz", 3), (0, Vec::new()));
+    }
+}
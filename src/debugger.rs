@@ -0,0 +1,174 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+
+// 程序停下来之后可以输入的命令
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Print(String), // print <expr>：在当前的环境里对表达式求值
+    Step,          // 执行到下一条语句再停下
+    Continue,      // 一直运行到下一个 breakpoint()
+    Backtrace,     // 列出调用栈
+    Help,
+    Unknown(String),
+}
+
+const HELP: &str = "Commands: print <expr> (p), step (s), continue (c), backtrace (bt), help.";
+
+// breakpoint() 停下时和用户交互的输入输出。终端里是标准输入输出，测试里换成内存里的缓冲区
+pub struct Debugger {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    pub(crate) stepping: bool, // 执行下一条语句之前停下
+}
+
+impl fmt::Debug for Debugger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Debugger")
+            .field("stepping", &self.stepping)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Debugger {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Debugger {
+        Debugger {
+            input,
+            output,
+            stepping: false,
+        }
+    }
+
+    // 每次读取时才锁住标准输入，REPL 自己也要从标准输入读
+    pub fn stdio() -> Debugger {
+        Debugger::new(
+            Box::new(BufReader::new(io::stdin())),
+            Box::new(io::stdout()),
+        )
+    }
+
+    // 输入结束时当作 continue，让程序运行完
+    pub(crate) fn read_command(&mut self) -> Command {
+        self.write("debug> ");
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) | Err(_) => {
+                self.say("");
+                Command::Continue
+            }
+            Ok(_) => parse_command(line.trim()),
+        }
+    }
+
+    pub(crate) fn say(&mut self, text: &str) {
+        self.write(&format!("{text}\n"));
+    }
+
+    pub(crate) fn help(&mut self) {
+        self.say(HELP);
+    }
+
+    // 调试器的输出写不出去也不影响程序本身，错误直接忽略
+    fn write(&mut self, text: &str) {
+        let _ = self.output.write_all(text.as_bytes());
+        let _ = self.output.flush();
+    }
+}
+
+fn parse_command(line: &str) -> Command {
+    let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+    match (name, rest.trim()) {
+        ("print" | "p", expr) if !expr.is_empty() => Command::Print(expr.to_string()),
+        ("step" | "s", "") => Command::Step,
+        ("continue" | "c", "") => Command::Continue,
+        ("backtrace" | "bt", "") => Command::Backtrace,
+        ("help" | "h", "") => Command::Help,
+        _ => Command::Unknown(line.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    // 测试结束后还要读出调试器写了什么，所以输出放在共享的缓冲区里
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn debug(source: &str, commands: &str) -> String {
+        let output = Output::default();
+        let mut interpreter = Interpreter::new();
+        let input = Cursor::new(commands.to_string());
+        interpreter.set_debugger(Debugger::new(Box::new(input), Box::new(output.clone())));
+
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        let (statements, errors) = Parser::new(tokens).parse();
+        assert!(errors.is_empty());
+        Resolver::new(&mut interpreter, true)
+            .resolve(&statements)
+            .unwrap();
+        interpreter.interpret(statements).unwrap();
+
+        let output = output.0.borrow();
+        String::from_utf8(output.clone()).unwrap()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse_command("p a + 1"),
+            Command::Print(String::from("a + 1"))
+        );
+        assert_eq!(parse_command("step"), Command::Step);
+        assert_eq!(parse_command("c"), Command::Continue);
+        assert_eq!(parse_command("bt"), Command::Backtrace);
+        assert_eq!(
+            parse_command("print"),
+            Command::Unknown(String::from("print"))
+        );
+        assert_eq!(parse_command("go"), Command::Unknown(String::from("go")));
+    }
+
+    #[test]
+    fn breakpoint_prints_locals_backtrace_and_steps() {
+        let source = "var base = 100;\nfun add(a, b) {\n  var sum = a + b;\n  breakpoint();\n  return sum;\n}\nvar total = add(1, 2);\n";
+        let commands = "print sum\np a * 10 + base\nprint total\nbacktrace\nstep\ncontinue\n";
+        let transcript = [
+            "Paused at breakpoint on line 4.",
+            "debug> 3",
+            "debug> 110",
+            "debug> Undefined variable 'total'.\n[line 1, col 1]",
+            "debug> in fn add (line 7)",
+            "at top level",
+            "debug> [line 5] (return sum)",
+            "debug> ",
+        ];
+        assert_eq!(debug(source, commands), transcript.join("\n"));
+    }
+
+    #[test]
+    fn end_of_input_continues() {
+        let source = "var a = 1;\nbreakpoint();\na = 2;\n";
+        assert_eq!(
+            debug(source, ""),
+            "Paused at breakpoint on line 2.\ndebug> \n"
+        );
+    }
+}
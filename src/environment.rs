@@ -1,79 +1,187 @@
+use super::callable::{Callable, Function};
 use super::error::{Error, Result};
 use super::object::Object;
 use super::token::Token;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub struct Environment {
     enclosing: Option<Rc<Environment>>, // 一个父环境可以有多个子环境 -> Rc
-    values: RefCell<HashMap<String, Object>>, // 父环境的变量键值对可以被子环境改变 -> RefCell
+    values: RefCell<Values>,            // 父环境的变量键值对可以被子环境改变 -> RefCell
+    // 局部变量按声明顺序存放，解析器事先算好了每个变量的下标，不用按名字查找。
+    // 只有全局环境用 values
+    slots: RefCell<Vec<Object>>,
+    // 局部变量的名字，只在调试时记录
+    slot_names: RefCell<Vec<Rc<str>>>,
+    frozen: bool, // 冻结之后不能再给其中的变量赋值
+    // 按名字存的变量每次被定义、或者原来是函数的变量被重新赋值时加一。
+    // 解释器在调用处缓存了按名字查到的函数，这个数变了缓存就作废
+    generation: Cell<usize>,
 }
 
 impl Environment {
     pub fn new(enclosing: Option<Rc<Environment>>) -> Environment {
-        let values = HashMap::new();
         Environment {
             enclosing,
-            values: RefCell::new(values),
+            values: RefCell::new(Values::default()),
+            slots: RefCell::new(Vec::new()),
+            slot_names: RefCell::new(Vec::new()),
+            frozen: false,
+            generation: Cell::new(0),
         }
     }
 
-    pub fn define(&self, name: String, value: &Object) {
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation.get()
+    }
+
+    pub fn enclosing(&self) -> Option<&Rc<Environment>> {
+        self.enclosing.as_ref()
+    }
+
+    // 这个环境里存着的函数值，给 gc 查找引用用
+    pub fn functions(&self) -> Vec<Rc<Function>> {
+        let values = self.values.borrow();
+        let slots = self.slots.borrow();
+        values
+            .values()
+            .chain(slots.iter())
+            .filter_map(|value| match value {
+                Object::Callable(Callable::Function(function)) => Some(function.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // 变量在借用结束之后才释放，释放时可能连带释放别的环境
+    pub fn clear(&self) {
+        let values = std::mem::take(&mut *self.values.borrow_mut());
+        let slots = std::mem::take(&mut *self.slots.borrow_mut());
+        self.generation.set(self.generation.get() + 1);
+        drop((values, slots));
+    }
+
+    pub fn define(&self, name: Rc<str>, value: &Object) {
         // 在当前环境下存储键值对
         self.values.borrow_mut().insert(name, value.clone());
+        self.generation.set(self.generation.get() + 1);
     }
 
-    pub fn get(&self, name: &Token) -> Result<Object> {
-        match self.values.borrow_mut().get(&name.lexeme) {
-            Some(r) => Ok(r.clone()), // 在当前环境下找到了对应的键值对
-            None => match self.enclosing.clone() {
-                // 到上一层环境中寻找
-                Some(enclosing) => enclosing.get(name),
-                None => Err(Error::RuntimeError(
-                    name.clone(),
-                    format!("Undefined variable '{}'.", &name.lexeme),
-                )),
-            },
+    // 按名字存的变量和它们的值，按名字排序。REPL 的 :env 用
+    pub fn bindings(&self) -> Vec<(Rc<str>, Object)> {
+        let mut bindings = self.values.borrow().pairs();
+        bindings.sort_by(|(a, _), (b, _)| a.cmp(b));
+        bindings
+    }
+
+    // 从这个环境能按名字找到的变量，包括外层环境里的，排好序并去掉重复。REPL 补全用
+    pub fn names(&self) -> Vec<Rc<str>> {
+        let mut names: Vec<Rc<str>> = self
+            .values
+            .borrow()
+            .pairs()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        if let Some(enclosing) = &self.enclosing {
+            names.extend(enclosing.names());
         }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    pub fn get(&self, name: &Token) -> Result<Object> {
+        self.lookup(&name.lexeme).ok_or_else(|| {
+            Error::RuntimeError(
+                name.clone(),
+                format!("Undefined variable '{}'.", &name.lexeme),
+            )
+        })
     }
 
-    pub fn get_at(&self, distance: &usize, name: &str) -> Option<Object> {
-        match self.ancestor(distance) {
-            Some(env) => { env.values.borrow().get(name).cloned() },
-            None => None
+    // 按名字找变量，当前环境里没有就到上一层环境中找
+    pub fn lookup(&self, name: &str) -> Option<Object> {
+        // 读只需要共享借用，而且借用在去父环境查找之前就结束了
+        if let Some(r) = self.values.borrow().get(name) {
+            return Some(r.clone());
         }
+
+        self.enclosing.as_ref()?.lookup(name)
     }
 
-    fn ancestor(&self, distance: &usize) -> Option<Rc<Environment>> {
-        if *distance == 0 {
-            return None
+    // 局部变量的下标就是它在作用域里的声明顺序，所以定义时依次追加即可
+    pub fn define_slot(&self, value: &Object) {
+        self.slots.borrow_mut().push(value.clone());
+    }
+
+    // 给刚定义的局部变量记下名字，调试器靠它按名字查找局部变量
+    pub fn name_slot(&self, name: Rc<str>) {
+        self.slot_names.borrow_mut().push(name);
+    }
+
+    // 按名字找局部变量所在环境的距离和下标，最近的同名变量优先
+    pub fn find_slot(&self, name: &str) -> Option<(usize, usize)> {
+        let mut env = Some(self);
+        let mut distance = 0;
+        while let Some(current) = env {
+            let names = current.slot_names.borrow();
+            if let Some(slot) = names.iter().rposition(|slot_name| **slot_name == *name) {
+                return Some((distance, slot));
+            }
+            env = current.enclosing.as_deref();
+            distance += 1;
         }
 
-        let mut env = self.clone().enclosing.unwrap();
-        for _ in 1..*distance {
-            env = env.enclosing.clone().unwrap();
+        None
+    }
+
+    pub fn get_at_slot(&self, distance: &usize, slot: usize) -> Option<Object> {
+        self.ancestor(distance)?.slots.borrow().get(slot).cloned()
+    }
+
+    // 沿着父环境往上走 distance 层，0 就是当前环境
+    fn ancestor(&self, distance: &usize) -> Option<&Environment> {
+        let mut env = self;
+        for _ in 0..*distance {
+            env = env.enclosing.as_deref()?;
         }
 
         Some(env)
     }
 
     pub fn assign(&self, name: &Token, value: &Object) -> Result<()> {
-        if self.values.borrow().contains_key(&name.lexeme) {
-            // 如果该变量是在当前环境下定义的
-            self.values
-                .borrow_mut()
-                .insert(name.lexeme.clone(), value.to_owned().clone()); // 那么就在当前环境下更新它的键值对
+        if self.frozen && self.values.borrow().contains_key(&name.lexeme) {
+            return Err(Error::RuntimeError(
+                name.clone(),
+                format!(
+                    "Can't assign to built-in '{}'. Declare a global with 'var' to shadow it.",
+                    &name.lexeme
+                ),
+            ));
+        }
+
+        if let Some(slot) = self.values.borrow_mut().get_mut(&name.lexeme) {
+            // 如果该变量是在当前环境下定义的，那么就在当前环境下更新它的键值对
+            // 缓存里只有函数，覆盖掉别的值不影响缓存
+            if let Object::Callable(_) = slot {
+                self.generation.set(self.generation.get() + 1);
+            }
+            *slot = value.clone();
             return Ok(());
         }
 
-        if self.enclosing.clone().is_some() {
+        if let Some(enclosing) = &self.enclosing {
             // 是否有父环境
             // 没在当前环境下定义，可能在父环境中有定义，如果父环境也没有，那就递归
-            self.enclosing.clone().unwrap().assign(name, value)?;
-            return Ok(());
+            return enclosing.assign(name, value);
         }
 
         // 递归到最后（全局环境）也没有发现定义，那就是一个未定义错误
@@ -83,15 +191,218 @@ impl Environment {
         ))
     }
 
-    pub fn assign_at(&self, distance: &usize, name: &Token, value: &Object) -> Option<()> {
-        match self.ancestor(distance) {
-            Some(env) => {
-                env.values
-                    .borrow_mut()
-                    .insert(name.lexeme.clone(), value.to_owned().clone());
-                Some(())
-            },
-            None => None
+    pub fn assign_at_slot(&self, distance: &usize, slot: usize, value: &Object) -> Option<()> {
+        *self.ancestor(distance)?.slots.borrow_mut().get_mut(slot)? = value.clone();
+        Some(())
+    }
+}
+
+// 默认的析构会沿着父环境和函数的闭包一层层递归，很长的链在最后一个引用消失时会撑爆栈。
+// 这里像链表一样把链拆开，在循环里逐个释放
+impl Drop for Environment {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.detach(&mut pending);
+
+        while let Some(env) = pending.pop() {
+            // 还有别人引用的环境不会在这里释放，不用再往下拆
+            if let Ok(mut env) = Rc::try_unwrap(env) {
+                env.detach(&mut pending);
+            }
         }
     }
 }
+
+impl Environment {
+    // 把这个环境引用的其他环境取出来，放进 pending 里
+    fn detach(&mut self, pending: &mut Vec<Rc<Environment>>) {
+        pending.extend(self.enclosing.take());
+        let values = std::mem::take(self.values.get_mut()).into_values();
+        for value in values.chain(self.slots.get_mut().drain(..)) {
+            if let Object::Callable(Callable::Function(function)) = value {
+                if let Ok(function) = Rc::try_unwrap(function) {
+                    pending.push(function.into_closure());
+                }
+            }
+        }
+    }
+}
+
+// 大多数按名字存的环境只有几个变量，线性查找比哈希快，也不用分配哈希表。
+// 超过 SPILL 个变量再换成 HashMap
+const SPILL: usize = 8;
+
+#[derive(Debug, Clone)]
+enum Values {
+    Inline(Vec<(Rc<str>, Object)>),
+    Spilled(HashMap<Rc<str>, Object>),
+}
+
+impl Default for Values {
+    fn default() -> Self {
+        Values::Inline(Vec::new())
+    }
+}
+
+impl Values {
+    // 同名变量再次定义时覆盖原来的值
+    fn insert(&mut self, name: Rc<str>, value: Object) {
+        match self {
+            Values::Inline(pairs) => {
+                if let Some(slot) = Self::find(pairs, &name) {
+                    *slot = value;
+                } else if pairs.len() < SPILL {
+                    pairs.push((name, value));
+                } else {
+                    let mut map: HashMap<_, _> = pairs.drain(..).collect();
+                    map.insert(name, value);
+                    *self = Values::Spilled(map);
+                }
+            }
+            Values::Spilled(map) => {
+                map.insert(name, value);
+            }
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&Object> {
+        match self {
+            Values::Inline(pairs) => pairs
+                .iter()
+                .find(|(key, _)| **key == *name)
+                .map(|(_, value)| value),
+            Values::Spilled(map) => map.get(name),
+        }
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Object> {
+        match self {
+            Values::Inline(pairs) => Self::find(pairs, name),
+            Values::Spilled(map) => map.get_mut(name),
+        }
+    }
+
+    fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    fn find<'a>(pairs: &'a mut [(Rc<str>, Object)], name: &str) -> Option<&'a mut Object> {
+        pairs
+            .iter_mut()
+            .find(|(key, _)| **key == *name)
+            .map(|(_, value)| value)
+    }
+
+    fn values(&self) -> Box<dyn Iterator<Item = &Object> + '_> {
+        match self {
+            Values::Inline(pairs) => Box::new(pairs.iter().map(|(_, value)| value)),
+            Values::Spilled(map) => Box::new(map.values()),
+        }
+    }
+
+    fn pairs(&self) -> Vec<(Rc<str>, Object)> {
+        match self {
+            Values::Inline(pairs) => pairs.clone(),
+            Values::Spilled(map) => map.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        }
+    }
+
+    fn into_values(self) -> Box<dyn Iterator<Item = Object>> {
+        match self {
+            Values::Inline(pairs) => Box::new(pairs.into_iter().map(|(_, value)| value)),
+            Values::Spilled(map) => Box::new(map.into_values()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::Literal;
+
+    fn number(i: usize) -> Object {
+        Object::Literal(Literal::Num(i as f64))
+    }
+
+    fn names(count: usize) -> Vec<Rc<str>> {
+        (0..count).map(|i| Rc::from(format!("v{i}"))).collect()
+    }
+
+    fn filled(count: usize) -> Values {
+        let mut values = Values::default();
+        for (i, name) in names(count).into_iter().enumerate() {
+            values.insert(name, number(i));
+        }
+        values
+    }
+
+    #[test]
+    fn stays_inline_up_to_threshold() {
+        let values = filled(SPILL);
+        assert!(matches!(values, Values::Inline(_)));
+        for (i, name) in names(SPILL).iter().enumerate() {
+            assert_eq!(values.get(name), Some(&number(i)));
+        }
+    }
+
+    #[test]
+    fn spills_past_threshold_and_keeps_values() {
+        let values = filled(SPILL + 1);
+        assert!(matches!(values, Values::Spilled(_)));
+        for (i, name) in names(SPILL + 1).iter().enumerate() {
+            assert_eq!(values.get(name), Some(&number(i)));
+        }
+        assert_eq!(values.get("missing"), None);
+    }
+
+    #[test]
+    fn redefining_replaces_without_spilling() {
+        let mut values = filled(SPILL);
+        values.insert(Rc::from("v0"), Object::Literal(Literal::Bool(true)));
+        assert!(matches!(values, Values::Inline(_)));
+        assert_eq!(
+            values.get("v0"),
+            Some(&Object::Literal(Literal::Bool(true)))
+        );
+        assert_eq!(values.values().count(), SPILL);
+    }
+
+    #[test]
+    fn redefining_after_spill_replaces() {
+        let mut values = filled(SPILL + 1);
+        values.insert(Rc::from("v0"), Object::Literal(Literal::Bool(true)));
+        *values.get_mut("v1").unwrap() = Object::Literal(Literal::Nil);
+        assert_eq!(
+            values.get("v0"),
+            Some(&Object::Literal(Literal::Bool(true)))
+        );
+        assert_eq!(values.get("v1"), Some(&Object::Literal(Literal::Nil)));
+        assert_eq!(values.into_values().count(), SPILL + 1);
+    }
+
+    #[test]
+    fn bindings_are_sorted_by_name() {
+        let env = Environment::new(None);
+        for (i, name) in names(SPILL + 1).into_iter().enumerate().rev() {
+            env.define(name, &number(i));
+        }
+        let bindings = env.bindings();
+        let mut sorted = names(SPILL + 1);
+        sorted.sort();
+        let got: Vec<Rc<str>> = bindings.iter().map(|(name, _)| name.clone()).collect();
+        assert_eq!(got, sorted);
+        assert_eq!(bindings[0].1, number(0));
+    }
+
+    #[test]
+    fn names_include_enclosing_environments() {
+        let outer = Rc::new(Environment::new(None));
+        outer.define(Rc::from("clock"), &number(0));
+        outer.define(Rc::from("x"), &number(1));
+        let env = Environment::new(Some(outer));
+        env.define(Rc::from("x"), &number(2));
+        env.define(Rc::from("a"), &number(3));
+        let names: Vec<Rc<str>> = ["a", "clock", "x"].map(Rc::from).into();
+        assert_eq!(env.names(), names);
+    }
+}
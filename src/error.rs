@@ -1,28 +1,443 @@
-use super::object::Object;
-use super::token::{Token, TokenType};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use super::token::{Span, Token, TokenType};
 
 #[derive(Debug, Clone)]
 pub enum Error {
-    ParseError(String),
+    ParseError(Token, String),
     RuntimeError(Token, String),
-    Return(Object),
-    ResolveError(Token, String),
+    ResolveError(Diagnostic), // 解析变量时发现的错误，警告不算
+    // 宿主程序定义的原生函数报告的错误，调用处把它换成带位置的 RuntimeError
+    NativeError(String),
+    // 输入在一个结构的中途就结束了，比如没闭合的块或字符串。包着实际报告的错误，
+    // REPL 据此接着读下一行，而不是报错
+    Incomplete(Box<Error>),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-fn report(line: usize, place: &str, message: &str) {
-    println!("[line {}] Error{}: {}", line, place, message);
+// 终端上报告错误时也用它，嵌入解释器的代码拿到错误后直接打印，和命令行上看到的一样
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ParseError(token, message) => write!(
+                f,
+                "[line {}, col {}] Error{}: {}",
+                token.line,
+                token.column,
+                place(token),
+                message
+            ),
+            Error::RuntimeError(token, message) => {
+                write!(
+                    f,
+                    "{}\n[line {}, col {}]",
+                    message, token.line, token.column
+                )
+            }
+            Error::ResolveError(diagnostic) => diagnostic.fmt(f),
+            Error::NativeError(message) => write!(f, "{message}"),
+            Error::Incomplete(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self, Error::Incomplete(_))
+    }
+
+    // 出错的位置在源码中的范围，宿主程序没有位置的错误是 None
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::ParseError(token, _) | Error::RuntimeError(token, _) => Some(Span::from(token)),
+            Error::ResolveError(diagnostic) => Some(diagnostic.span),
+            Error::NativeError(_) => None,
+            Error::Incomplete(error) => error.span(),
+        }
+    }
+
+    // 不带位置的错误信息
+    pub fn message(&self) -> &str {
+        match self {
+            Error::ParseError(_, message)
+            | Error::RuntimeError(_, message)
+            | Error::NativeError(message) => message,
+            Error::ResolveError(diagnostic) => &diagnostic.message,
+            Error::Incomplete(error) => error.message(),
+        }
+    }
+
+    // 错误的编号，目前只有变量解析发现的错误有
+    pub fn code(&self) -> Option<&'static str> {
+        match self.original() {
+            Error::ResolveError(diagnostic) => Some(diagnostic.code),
+            _ => None,
+        }
+    }
+
+    // Incomplete 包着的错误，其它错误就是它自己
+    pub fn original(&self) -> &Error {
+        match self {
+            Error::Incomplete(error) => error,
+            error => error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// 静态检查发现的问题。Error 会阻止程序运行，Warning 只报告
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // 终端上的格式和 jlox 保持一致，不显示 code，--json-diagnostics 才输出它
+    pub code: &'static str, // 比如 R001、W001
+    pub message: String,
+    pub lexeme: String,
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(
+        severity: Severity,
+        code: &'static str,
+        token: &Token,
+        message: String,
+    ) -> Diagnostic {
+        Diagnostic {
+            severity,
+            code,
+            message,
+            lexeme: token.lexeme.to_string(),
+            line: token.line,
+            column: token.column,
+            span: Span::from(token),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        write!(
+            f,
+            "[line {}, col {}] {label} at '{}': {}",
+            self.line, self.column, self.lexeme, self.message
+        )
+    }
+}
+
+thread_local! {
+    // 正在运行的文件。一次运行好几个文件时，静态错误前面带上文件名以便区分
+    static CURRENT_FILE: RefCell<Option<String>> = const { RefCell::new(None) };
+    // --json-diagnostics：错误和警告每个一行 JSON，给编辑器插件之类的程序读
+    static JSON_DIAGNOSTICS: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn set_current_file(name: Option<&str>) {
+    CURRENT_FILE.with(|file| *file.borrow_mut() = name.map(String::from));
+}
+
+pub fn set_json_diagnostics(enabled: bool) {
+    JSON_DIAGNOSTICS.with(|json| json.set(enabled));
+}
+
+pub fn json_diagnostics() -> bool {
+    JSON_DIAGNOSTICS.with(|json| json.get())
+}
+
+// 静态错误报告到 stderr，一次运行好几个文件时前面带上文件名
+fn report(message: &dyn fmt::Display) {
+    let file = CURRENT_FILE.with(|file| match &*file.borrow() {
+        Some(name) => format!("{name}: "),
+        None => String::new(),
+    });
+    eprintln!("{file}{message}");
+}
+
+// 词法错误和语法错误
+pub fn static_error(error: &Error) {
+    if json_diagnostics() {
+        eprintln!("{}", error_json(error));
+    } else {
+        report(error)
+    }
+}
+
+// 变量解析发现的错误和警告
+pub fn diagnostic(diagnostic: &Diagnostic) {
+    if json_diagnostics() {
+        eprintln!(
+            "{}",
+            json_line(
+                diagnostic.severity,
+                Some(diagnostic.code),
+                &diagnostic.message,
+                diagnostic.line,
+                diagnostic.column
+            )
+        );
+    } else {
+        report(diagnostic)
+    }
+}
+
+// 错误对应的一行 JSON。没有位置的错误行列都是 0，没有编号的 code 是 null
+pub fn error_json(error: &Error) -> String {
+    let (line, column) = error
+        .span()
+        .map_or((0, 0), |span| (span.start_line, span.start_col));
+    let severity = match error.original() {
+        Error::ResolveError(diagnostic) => diagnostic.severity,
+        _ => Severity::Error,
+    };
+    json_line(severity, error.code(), error.message(), line, column)
+}
+
+fn json_line(
+    severity: Severity,
+    code: Option<&str>,
+    message: &str,
+    line: usize,
+    column: usize,
+) -> String {
+    let severity = match severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+    };
+    let file = CURRENT_FILE.with(|file| file.borrow().clone());
+    json_object(severity, code, message, file.as_deref(), line, column)
 }
 
-pub fn parser_error(token: Token, message: &str) {
-    if token.token_type == TokenType::EOF {
-        report(token.line, " at end", message)
+// serde 是默认开启的特性，serde_json 本来就在，转义交给它
+#[cfg(feature = "serde")]
+fn json_object(
+    severity: &str,
+    code: Option<&str>,
+    message: &str,
+    file: Option<&str>,
+    line: usize,
+    column: usize,
+) -> String {
+    serde_json::json!({
+        "severity": severity,
+        "code": code,
+        "message": message,
+        "file": file,
+        "line": line,
+        "column": column,
+    })
+    .to_string()
+}
+
+// 不带 serde 编译时没有 serde_json，字段固定，手写转义
+#[cfg(not(feature = "serde"))]
+fn json_object(
+    severity: &str,
+    code: Option<&str>,
+    message: &str,
+    file: Option<&str>,
+    line: usize,
+    column: usize,
+) -> String {
+    let code = code.map_or(String::from("null"), json_string);
+    let file = file.map_or(String::from("null"), json_string);
+    format!(
+        "{{\"severity\":\"{severity}\",\"code\":{code},\"message\":{},\"file\":{file},\"line\":{line},\"column\":{column}}}",
+        json_string(message)
+    )
+}
+
+#[cfg(not(feature = "serde"))]
+fn json_string(string: &str) -> String {
+    let mut json = String::from('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn place(token: &Token) -> String {
+    match token.token_type {
+        TokenType::EOF => String::from(" at end"),
+        // 非法字符的报错信息里已经带上了这个字符
+        TokenType::ERROR => String::new(),
+        _ => format!(" at '{}'", token.lexeme),
+    }
+}
+
+// 运行时错误写到解释器的错误输出（默认是 stderr），和程序自己的输出分开
+pub fn runtime_error(output: &mut dyn Write, error: &Error) -> io::Result<()> {
+    if json_diagnostics() {
+        writeln!(output, "{}", error_json(error))
     } else {
-        report(token.line, "", message)
+        writeln!(output, "{error}")
     }
 }
 
-pub fn lexer_error(line: usize, message: &str) {
-    report(line, "", message)
+// 从出错的函数开始，逐层列出调用它的位置。递归产生的相同调用合并成一行
+pub fn stack_trace(output: &mut dyn Write, frames: &[(Rc<str>, usize)]) -> io::Result<()> {
+    if frames.is_empty() {
+        return Ok(());
+    }
+
+    let mut frames = frames.iter().rev().peekable();
+    while let Some((name, line)) = frames.next() {
+        writeln!(output, "in fn {name} (line {line})")?;
+
+        let mut repeated = 0;
+        while frames.next_if_eq(&&(name.clone(), *line)).is_some() {
+            repeated += 1;
+        }
+        if repeated > 0 {
+            writeln!(output, "[previous line repeated {repeated} more times]")?;
+        }
+    }
+    writeln!(output, "at top level")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::Literal;
+
+    fn token(token_type: TokenType, lexeme: &str, line: usize, column: usize) -> Token {
+        Token::new(token_type, lexeme.into(), Literal::Nil, line, column, 0..0)
+    }
+
+    #[test]
+    fn parse_error_names_the_token() {
+        let error = Error::ParseError(
+            token(TokenType::SEMICOLON, ";", 1, 7),
+            String::from("Expect expression."),
+        );
+        assert_eq!(
+            error.to_string(),
+            "[line 1, col 7] Error at ';': Expect expression."
+        );
+
+        let error = Error::ParseError(
+            token(TokenType::EOF, "", 3, 1),
+            String::from("Expect '}' after block."),
+        );
+        assert_eq!(
+            error.to_string(),
+            "[line 3, col 1] Error at end: Expect '}' after block."
+        );
+
+        let error = Error::ParseError(
+            token(TokenType::ERROR, "@", 1, 1),
+            String::from("Unexpected character '@'."),
+        );
+        assert_eq!(
+            error.to_string(),
+            "[line 1, col 1] Error: Unexpected character '@'."
+        );
+    }
+
+    #[test]
+    fn runtime_error_puts_the_location_on_its_own_line() {
+        let error = Error::RuntimeError(
+            token(TokenType::MINUS, "-", 2, 7),
+            String::from("Operand must be a number."),
+        );
+        assert_eq!(
+            error.to_string(),
+            "Operand must be a number.\n[line 2, col 7]"
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let name = token(TokenType::IDENTIFIER, "a", 4, 9);
+        let error = Diagnostic::new(
+            Severity::Error,
+            "R001",
+            &name,
+            String::from("Already a variable with this name in this scope."),
+        );
+        let warning = Diagnostic::new(
+            Severity::Warning,
+            "W004",
+            &name,
+            String::from("Local variable 'a' is never used."),
+        );
+        assert_eq!(
+            warning.to_string(),
+            "[line 4, col 9] Warning at 'a': Local variable 'a' is never used."
+        );
+        assert_eq!(
+            Error::ResolveError(error).to_string(),
+            "[line 4, col 9] Error at 'a': Already a variable with this name in this scope."
+        );
+    }
+
+    #[test]
+    fn native_and_incomplete_errors() {
+        assert_eq!(Error::NativeError(String::from("boom")).to_string(), "boom");
+
+        let inner = Error::ParseError(
+            token(TokenType::EOF, "", 1, 10),
+            String::from("Expect ';' after value."),
+        );
+        let error = Error::Incomplete(Box::new(inner.clone()));
+        assert_eq!(error.to_string(), inner.to_string());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_escapes_the_message() {
+        let error = Error::NativeError(String::from("say \"hi\"\n\tnow\\"));
+        let json: serde_json::Value = serde_json::from_str(&error_json(&error)).unwrap();
+        let expected = serde_json::json!({
+            "severity": "error",
+            "code": null,
+            "message": "say \"hi\"\n\tnow\\",
+            "file": null,
+            "line": 0,
+            "column": 0,
+        });
+        assert_eq!(json, expected);
+    }
+
+    #[cfg(not(feature = "serde"))]
+    #[test]
+    fn json_escapes_the_message() {
+        let error = Error::NativeError(String::from("say \"hi\"\n\tnow\\"));
+        assert_eq!(
+            error_json(&error),
+            r#"{"severity":"error","code":null,"message":"say \"hi\"\n\tnow\\","file":null,"line":0,"column":0}"#
+        );
+    }
+
+    #[test]
+    fn works_as_std_error() {
+        let error: Box<dyn std::error::Error> = Box::new(Error::NativeError(String::from("boom")));
+        assert_eq!(error.to_string(), "boom");
+        assert!(error.source().is_none());
+    }
 }
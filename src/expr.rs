@@ -1,54 +1,75 @@
 use super::token;
-use super::token::Token;
+use super::token::{Span, Token};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub trait Visitor<T> {
     fn visit_binary_expr(&mut self, left: &Expr, operator: &Token, right: &Expr) -> T;
     fn visit_grouping_expr(&mut self, expression: &Expr) -> T;
     fn visit_literal_expr(&mut self, expr: &token::Literal) -> T;
     fn visit_unary_expr(&mut self, operator: &Token, right: &Expr) -> T;
-    fn visit_var_expr(&mut self, name: &Token) -> T; // 变量表达式
-    fn visit_assign_expr(&mut self, name: &Token, value: &Expr) -> T;
+    fn visit_var_expr(&mut self, id: usize, name: &Token) -> T; // 变量表达式
+    fn visit_assign_expr(&mut self, id: usize, name: &Token, value: &Expr) -> T;
     fn visit_logic_expr(&mut self, left: &Expr, operator: &Token, right: &Expr) -> T;
     fn visit_call_expr(&mut self, callee: &Expr, paren: &Token, arguments: &Vec<Expr>) -> T;
+    fn visit_error_expr(&mut self, token: &Token) -> T;
 }
 
 pub trait Acceptor<T> {
     fn accept(&self, visitor: &mut dyn Visitor<T>) -> T;
 }
 
-#[derive(Eq, Hash, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Assign {
+        // id 只在一次运行里有意义，不写进 JSON；读回来时重新编号，不会和已有的 id 冲突
+        #[cfg_attr(feature = "serde", serde(skip, default = "Expr::next_id"))]
+        id: usize, // 解析器按 id 记录变量的位置，见 Expr::next_id
         name: Token,
         value: Box<Expr>,
+        span: Span,
     },
     Binary {
         left: Box<Expr>, // 注意自引用类型
         operator: Token,
         right: Box<Expr>,
+        span: Span,
     },
     Call {
         callee: Box<Expr>,
         paren: Token, // 右括号，用于运行时错误
         arguments: Vec<Expr>,
+        span: Span,
     },
     Grouping {
         expression: Box<Expr>,
+        span: Span,
     },
     Literal {
         value: token::Literal,
+        span: Span,
     },
     Unary {
         operator: Token,
         right: Box<Expr>,
+        span: Span,
     },
     Variable {
+        #[cfg_attr(feature = "serde", serde(skip, default = "Expr::next_id"))]
+        id: usize,
         name: Token,
+        span: Span,
     },
     Logic {
         left: Box<Expr>,
         operator: Token,
         right: Box<Expr>,
+        span: Span,
+    },
+    Error {
+        token: Token, // 语法错误处的占位节点，token 是出错的位置
+        span: Span,
     },
 }
 
@@ -59,22 +80,68 @@ impl<T> Acceptor<T> for Expr {
                 left,
                 operator,
                 right,
+                ..
             } => visitor.visit_binary_expr(left, operator, right),
-            Expr::Grouping { expression } => visitor.visit_grouping_expr(expression),
-            Expr::Literal { value } => visitor.visit_literal_expr(value),
-            Expr::Unary { operator, right } => visitor.visit_unary_expr(operator, right),
-            Expr::Variable { name } => visitor.visit_var_expr(name),
-            Expr::Assign { name, value } => visitor.visit_assign_expr(name, value),
+            Expr::Grouping { expression, .. } => visitor.visit_grouping_expr(expression),
+            Expr::Literal { value, .. } => visitor.visit_literal_expr(value),
+            Expr::Unary {
+                operator, right, ..
+            } => visitor.visit_unary_expr(operator, right),
+            Expr::Variable { id, name, .. } => visitor.visit_var_expr(*id, name),
+            Expr::Assign {
+                id, name, value, ..
+            } => visitor.visit_assign_expr(*id, name, value),
             Expr::Logic {
                 left,
                 operator,
                 right,
+                ..
             } => visitor.visit_logic_expr(left, operator, right),
             Expr::Call {
                 callee,
                 paren,
                 arguments,
+                ..
             } => visitor.visit_call_expr(callee, paren, arguments),
+            Expr::Error { token, .. } => visitor.visit_error_expr(token),
+        }
+    }
+}
+
+// REPL 每一行都用新的解析器，而之前定义的函数还会被调用，所以 id 在整个进程里递增
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl Expr {
+    // 变量和赋值表达式的唯一编号。按表达式的内容做 key 的话，
+    // 要哈希整棵树，而且内容相同的两个表达式会共用一个 key
+    pub fn next_id() -> usize {
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // 表达式中用于报告错误的 token，字面量没有对应的 token
+    pub fn token(&self) -> Option<&Token> {
+        match self {
+            Expr::Assign { name, .. } | Expr::Variable { name, .. } => Some(name),
+            Expr::Binary { operator, .. }
+            | Expr::Unary { operator, .. }
+            | Expr::Logic { operator, .. } => Some(operator),
+            Expr::Call { paren, .. } => Some(paren),
+            Expr::Grouping { expression, .. } => expression.token(),
+            Expr::Error { token, .. } => Some(token),
+            Expr::Literal { .. } => None,
+        }
+    }
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Assign { span, .. }
+            | Expr::Binary { span, .. }
+            | Expr::Call { span, .. }
+            | Expr::Grouping { span, .. }
+            | Expr::Literal { span, .. }
+            | Expr::Unary { span, .. }
+            | Expr::Variable { span, .. }
+            | Expr::Logic { span, .. }
+            | Expr::Error { span, .. } => *span,
         }
     }
 }
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use crate::callable::Function;
+use crate::environment::Environment;
+
+// 闭包引用定义它的环境，函数值又存回这个环境（或者它的外层环境）时就形成了 Rc 环，
+// 引用计数永远不会归零。这里用试探删除找出只被环内部引用的环境，清空其中的变量把环断开，
+// 剩下的交给 Rc 释放。
+//
+// 环里一定有一条“函数 -> 闭包环境”的边，而闭包环境就是执行函数声明时的环境，
+// 所以只要从所有定义过函数的环境出发，就能找到所有的环。
+
+enum Node {
+    Environment(Rc<Environment>),
+    Function(Rc<Function>),
+}
+
+impl Node {
+    fn key(&self) -> *const () {
+        match self {
+            Node::Environment(env) => Rc::as_ptr(env) as *const (),
+            Node::Function(function) => Rc::as_ptr(function) as *const (),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Environment(env) => Rc::strong_count(env),
+            Node::Function(function) => Rc::strong_count(function),
+        }
+    }
+
+    // 这个节点持有的 Rc，每一项对应被引用节点的一个引用计数
+    fn references(&self) -> Vec<Node> {
+        match self {
+            Node::Environment(env) => {
+                let mut references: Vec<Node> =
+                    env.functions().into_iter().map(Node::Function).collect();
+                if let Some(enclosing) = env.enclosing() {
+                    references.push(Node::Environment(enclosing.clone()));
+                }
+                references
+            }
+            Node::Function(function) => {
+                vec![Node::Environment(function.closure().clone())]
+            }
+        }
+    }
+}
+
+pub fn collect_cycles(closures: &[Weak<Environment>]) {
+    // 找出从候选环境出发能到达的所有节点，nodes 给每个节点多持有了一个引用
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut index: HashMap<*const (), usize> = HashMap::new();
+    let mut pending: Vec<Node> = closures
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(Node::Environment)
+        .collect();
+    while let Some(node) = pending.pop() {
+        if index.contains_key(&node.key()) {
+            continue;
+        }
+        index.insert(node.key(), nodes.len());
+        pending.extend(node.references());
+        nodes.push(node);
+    }
+
+    // 统计每个节点被这些节点内部引用了多少次
+    let mut internal = vec![0; nodes.len()];
+    for node in &nodes {
+        for reference in node.references() {
+            internal[index[&reference.key()]] += 1;
+        }
+    }
+
+    // 引用计数比内部引用多（除去 nodes 自己的那一个）的节点还被解释器的其他地方用着，
+    // 从它们能到达的节点都还活着
+    let mut live = vec![false; nodes.len()];
+    let mut pending: Vec<usize> = (0..nodes.len())
+        .filter(|&i| nodes[i].strong_count() - 1 > internal[i])
+        .collect();
+    while let Some(i) = pending.pop() {
+        if live[i] {
+            continue;
+        }
+        live[i] = true;
+        for reference in nodes[i].references() {
+            pending.push(index[&reference.key()]);
+        }
+    }
+
+    for (node, live) in nodes.iter().zip(live) {
+        if let (Node::Environment(env), false) = (node, live) {
+            env.clear();
+        }
+    }
+}
@@ -1,28 +1,121 @@
+use super::ast_printer::AstPrinter;
+use super::debugger::{Command, Debugger};
 use super::error::Result;
 use super::expr;
 use super::expr::{Acceptor as ExprAcceptor, Expr};
+use super::profiler::Profiler;
 use super::stmt;
 use super::stmt::{Acceptor as StmtAcceptor, Stmt};
-use crate::callable::Function;
+use crate::callable;
 use crate::callable::{Callable, LoxCallable};
+use crate::callable::{Function, NativeFn};
 use crate::environment::Environment;
 use crate::error::Error;
+use crate::gc;
 use crate::object::Object;
-use crate::token::{Literal, Token, TokenType};
+use crate::parser::{Parser, DEFAULT_MAX_EXPRESSION_DEPTH};
+use crate::scanner::Scanner;
+use crate::sources::{RandomSource, SeededRandom, SystemTime, TimeSource};
+use crate::token::{Literal, Span, Token, TokenType};
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+// 函数调用的默认最大嵌套层数。每层 Lox 调用都要占用好几层 Rust 的栈，
+// 不加限制的话无限递归会直接让进程栈溢出
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+// 定义过函数的环境攒到这么多个之后才第一次检查 Rc 环
+const MIN_GC_THRESHOLD: usize = 1024;
+
+// 设了截止时间时，每走这么多步才看一次时钟
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+// 执行语句时从当前位置一路退出去的原因。return 和错误一样要穿过嵌套的语句，
+// 但它只在函数调用处被接住，不会出现在公开的 Error 里
+// 尾调用（`return f(...);`）也通过它带出被调用的函数和实参，由外层的函数调用
+// 在循环里接着执行，这样尾递归不会加深 Rust 的栈和调用栈
+#[derive(Debug)]
+pub enum Unwind {
+    Return(Object),
+    TailCall(Rc<Function>, Token, Vec<Object>),
+    Error(Error),
+}
+
+impl From<Error> for Unwind {
+    fn from(error: Error) -> Unwind {
+        Unwind::Error(error)
+    }
+}
+
+pub type Exec = std::result::Result<(), Unwind>;
 
 #[derive(Debug)]
 pub struct Interpreter {
     env: Rc<Environment>,
     pub globals: Rc<Environment>,
-    locals: HashMap<Expr, usize>,
+    locals: HashMap<usize, (usize, usize)>, // 局部变量所在环境的距离和下标，按表达式 id 查
+    depth: usize,                           // 当前表达式求值的嵌套层数
+    frames: Vec<(Rc<str>, usize)>,          // 调用栈：函数名和调用处的行号
+    trace: Option<Vec<(Rc<str>, usize)>>,   // 运行时错误发生时的调用栈
+    max_call_depth: usize,
+    max_expression_depth: usize, // 和解析器的上限一致，见 evaluate_nested
+    trace_execution: bool,       // --trace：执行的每条语句和函数调用都打印到 stderr
+    debugger: Option<Debugger>,  // 没有调试器时 breakpoint() 什么也不做
+    breakpoint_warned: bool,     // 没有调试器时只提醒一次 breakpoint() 被忽略了
+    profiler: Option<Profiler>,  // --profile：关掉时每次调用只多一次判断
+    interrupt: Arc<AtomicBool>,  // 按下 Ctrl-C 时由信号处理函数设置，循环和函数调用处检查
+    steps: u64,                  // 执行过的语句和求值过的表达式的个数，见 set_step_limit
+    step_limit: u64,
+    deadline: Option<Instant>,
+    next_check: u64, // steps 超过它时才去检查步数上限和截止时间，平时每一步只多一次比较
+    closures: Vec<Weak<Environment>>, // 定义过函数的环境，闭包形成的 Rc 环一定经过其中的某一个
+    gc_threshold: usize,
+    // 按名字调用的全局函数，按被调用的变量表达式的 id 查，
+    // 和查到它时全局环境的 generation 一起存，见 lookup_callee
+    call_sites: HashMap<usize, (usize, Object)>,
+    writer: Sink,       // print 的输出，默认是标准输出
+    error_writer: Sink, // 运行时错误和 --trace 的输出，默认是标准错误
+    sources: Sources,
+}
+
+// 输出的去处。嵌入解释器的程序和测试可以换成内存里的缓冲区
+struct Sink(Box<dyn Write>);
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Sink")
+    }
+}
+
+// clock()、now() 和 random() 读到的时间和随机数，见 sources.rs
+struct Sources {
+    time: Box<dyn TimeSource>,
+    random: Box<dyn RandomSource>,
+}
+
+impl fmt::Debug for Sources {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Sources")
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
 }
 
 impl Interpreter {
-    pub fn new(env: Environment) -> Interpreter {
-        let globals = Rc::new(env.clone());
-        globals.define("clock".to_string(), &Object::Callable(Callable::Clock));
+    pub fn new() -> Interpreter {
+        // 原生函数放在全局环境外面一层冻结的环境里。脚本里的同名全局变量只是遮蔽它们，
+        // 嵌入解释器的代码还能通过 globals.enclosing() 拿到原来的函数
+        let globals = Rc::new(Environment::new(Some(callable::prelude())));
 
         let env = globals.clone();
 
@@ -30,274 +123,772 @@ impl Interpreter {
             env,
             globals,
             locals: HashMap::new(),
+            depth: 0,
+            frames: Vec::new(),
+            trace: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_expression_depth: DEFAULT_MAX_EXPRESSION_DEPTH,
+            trace_execution: false,
+            debugger: None,
+            breakpoint_warned: false,
+            profiler: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            steps: 0,
+            step_limit: u64::MAX,
+            deadline: None,
+            next_check: u64::MAX,
+            closures: Vec::new(),
+            gc_threshold: MIN_GC_THRESHOLD,
+            call_sites: HashMap::new(),
+            writer: Sink(Box::new(io::stdout())),
+            error_writer: Sink(Box::new(io::stderr())),
+            sources: Sources {
+                time: Box::new(SystemTime),
+                random: Box::new(SeededRandom::from_entropy()),
+            },
         }
     }
 
-    pub fn interpret(&mut self, stmts: Vec<Stmt>) -> Result<()> {
-        for stmt in stmts {
-            match self.execute(&stmt) {
-                Ok(_) => {}
-                Err(r) => println!("{:?}", r),
-            }
+    pub fn set_writer(&mut self, writer: Box<dyn Write>) {
+        self.writer = Sink(writer);
+    }
+
+    pub fn set_error_writer(&mut self, error_writer: Box<dyn Write>) {
+        self.error_writer = Sink(error_writer);
+    }
+
+    // print 写到这里，原生函数要输出时也用它
+    pub fn writer(&mut self) -> &mut dyn Write {
+        &mut *self.writer.0
+    }
+
+    // 报告运行时错误的地方，和程序的输出分开
+    pub fn error_writer(&mut self) -> &mut dyn Write {
+        &mut *self.error_writer.0
+    }
+
+    // 记下定义函数的环境，攒够了就回收只被闭包互相引用着的环境。
+    // 每次回收之后把阈值设成存活数量的两倍，回收的开销平摊到每次函数定义上是常数
+    fn track_closure(&mut self) {
+        self.closures.push(Rc::downgrade(&self.env));
+        if self.closures.len() < self.gc_threshold {
+            return;
         }
-        Ok(())
+
+        gc::collect_cycles(&self.closures);
+        self.closures.retain(|env| env.strong_count() > 0);
+        self.closures.sort_by_key(|env| env.as_ptr());
+        self.closures.dedup_by(|a, b| a.ptr_eq(b));
+        self.gc_threshold = MIN_GC_THRESHOLD.max(self.closures.len() * 2);
     }
 
-    fn execute(&mut self, stmt: &Stmt) -> Result<()> {
-        stmt.accept(self)
+    // 在全局环境里定义一个用 Rust 写的函数。调用时和别的函数一样检查参数个数，
+    // 函数体返回 Error::NativeError 时报告成调用处的运行时错误
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        f: impl Fn(&mut Interpreter, Vec<Object>) -> Result<Object> + 'static,
+    ) {
+        let native = NativeFn::new(name, arity, f);
+        let value = Object::Callable(Callable::Native(Rc::new(native)));
+        self.globals.define(name.into(), &value);
     }
 
-    pub fn resolve(&mut self, expr: Expr, depth: usize) {
-        self.locals.insert(expr, depth);
+    pub fn set_time_source(&mut self, time: Box<dyn TimeSource>) {
+        self.sources.time = time;
     }
 
-    pub fn execute_block(&mut self, stmts: &Vec<Stmt>, env: Environment) -> Result<()> {
-        let previous_env = self.env.clone();
-        self.env = Rc::new(env);
+    pub fn set_random_source(&mut self, random: Box<dyn RandomSource>) {
+        self.sources.random = random;
+    }
 
-        for stmt in stmts {
-            if let Err(e) = self.execute(stmt) {
-                self.env = previous_env;
-                return Err(e);
-            }
+    pub(crate) fn time(&mut self) -> &mut dyn TimeSource {
+        &mut *self.sources.time
+    }
+
+    pub(crate) fn random(&mut self) -> &mut dyn RandomSource {
+        &mut *self.sources.random
+    }
+
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+
+    pub fn set_max_expression_depth(&mut self, max_expression_depth: usize) {
+        self.max_expression_depth = max_expression_depth;
+    }
+
+    pub fn set_trace_execution(&mut self, trace_execution: bool) {
+        self.trace_execution = trace_execution;
+    }
+
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profiler = profiling.then(Profiler::new);
+    }
+
+    // 程序结束后取出统计结果打印
+    pub fn take_profile(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub(crate) fn profile_enter(&mut self, name: &Rc<str>, line: usize) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.enter(name.clone(), line);
+        }
+    }
+
+    pub(crate) fn profile_exit(&mut self) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.exit();
+        }
+    }
+
+    // 和信号处理函数共用的标志。REPL 里 :reset 换了解释器之后还是同一个
+    pub fn set_interrupt_flag(&mut self, interrupt: Arc<AtomicBool>) {
+        self.interrupt = interrupt;
+    }
+
+    pub fn interrupted(&self) -> bool {
+        self.interrupt.load(Ordering::Relaxed)
+    }
+
+    // REPL 每条输入开始之前清除，运行文件时留着，出错后据此以 130 退出
+    pub fn clear_interrupt(&self) {
+        self.interrupt.store(false, Ordering::Relaxed);
+    }
+
+    // 从现在起最多再走 steps 步，每执行一条语句、求值一个表达式算一步，超过就是运行时错误。
+    // 运行不受信任的代码时用它保证程序一定会结束
+    pub fn set_step_limit(&mut self, steps: u64) {
+        self.steps = 0;
+        self.step_limit = steps;
+        self.next_check = self.next_check();
+    }
+
+    // 过了这个时刻还没运行完就报错。和步数在同样的地方检查，不过隔一段才看一次时钟
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        self.next_check = self.next_check();
+    }
+
+    fn next_check(&self) -> u64 {
+        match self.deadline {
+            Some(_) => (self.steps + DEADLINE_CHECK_INTERVAL).min(self.step_limit),
+            None => self.step_limit,
         }
+    }
+
+    // steps 超过 next_check 时才会走到这里
+    #[cold]
+    fn check_budget(&mut self, span: Span) -> Result<()> {
+        let message = if self.steps > self.step_limit {
+            "Execution budget exceeded."
+        } else if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            "Execution deadline exceeded."
+        } else {
+            self.next_check = self.next_check();
+            return Ok(());
+        };
 
-        self.env = previous_env;
+        // 字面量和空块没有自己的 token，错误的位置取节点开头
+        let token = Token::new(
+            TokenType::EOF,
+            Rc::from(""),
+            Literal::Nil,
+            span.start_line,
+            span.start_col,
+            0..0,
+        );
+        Err(Error::RuntimeError(token, String::from(message)))
+    }
+
+    // 按了 Ctrl-C 就在这里报错，错误一路传回 REPL 的提示符，或者结束脚本
+    pub(crate) fn check_interrupt(&self, token: &Token) -> Result<()> {
+        if self.interrupted() {
+            return Err(Error::RuntimeError(
+                token.clone(),
+                String::from("Interrupted."),
+            ));
+        }
         Ok(())
     }
 
-    fn evaluate(&mut self, expr: &Expr) -> Result<Object> {
-        expr.accept(self)
+    // 执行跟踪的一行：行号，再按调用层数缩进
+    fn trace_line(&mut self, line: Option<usize>, depth: usize, text: &str) {
+        let indent = "  ".repeat(depth);
+        let _ = writeln!(self.error_writer(), "{} {indent}{text}", line_label(line));
     }
 
-    fn is_truthy(&self, object: Object) -> bool {
-        match object {
-            Object::Literal(literal) => match literal {
-                Literal::Nil => false,
-                Literal::Bool(b) => b,
-                _ => true,
-            },
-            _ => true,
+    // 每条语句执行之前：--trace 时打印它，调试器单步时在这里停下
+    fn before_stmt(&mut self, stmt: &Stmt) {
+        if self.trace_execution {
+            let line = stmt.token().map(|token| token.line);
+            let text = AstPrinter::default().print_compact(stmt);
+            self.trace_line(line, self.frames.len(), &text);
+        }
+        if self
+            .debugger
+            .as_ref()
+            .is_some_and(|debugger| debugger.stepping)
+        {
+            let line = line_label(stmt.token().map(|token| token.line));
+            self.pause(&format!(
+                "{line} {}",
+                AstPrinter::default().print_compact(stmt)
+            ));
         }
     }
 
-    fn lookup_variable(&mut self, name: Token, expr: &Expr) -> Result<Object> {
-        let distance = self.locals.get(expr);
-        match distance {
-            Some(distance) => match self.env.get_at(distance, &name.lexeme) {
-                Some(x) => Ok(x),
-                None => self.env.get(&name),
-            },
-            None => self.globals.get(&name),
+    pub fn set_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(debugger);
+    }
+
+    // 有调试器时局部变量要记下名字，这样停下来时才能按名字找到它们
+    pub(crate) fn debugging(&self) -> bool {
+        self.debugger.is_some()
+    }
+
+    // breakpoint() 的调用处。没有调试器时（不在终端里运行，或者是字节码虚拟机）
+    // 什么也不做，第一次调用时往错误输出里提醒一句
+    pub(crate) fn breakpoint(&mut self, paren: &Token) {
+        if self.debugger.is_none() {
+            if !self.breakpoint_warned {
+                self.breakpoint_warned = true;
+                let _ = writeln!(
+                    self.error_writer(),
+                    "[line {}, col {}] Warning: breakpoint() is ignored when not running in a terminal.",
+                    paren.line,
+                    paren.column
+                );
+            }
+            return;
         }
+        self.pause(&format!("Paused at breakpoint on line {}.", paren.line));
     }
-}
 
-impl expr::Visitor<Result<Object>> for Interpreter {
-    fn visit_binary_expr(&mut self, left: &Expr, operator: &Token, right: &Expr) -> Result<Object> {
-        let left = self.evaluate(left)?;
-        let right = self.evaluate(right)?;
+    // 停下来读命令，直到 step 或 continue 才继续执行
+    fn pause(&mut self, message: &str) {
+        let Some(debugger) = self.debugger.as_mut() else {
+            return;
+        };
+        debugger.stepping = false;
+        debugger.say(message);
 
-        match (left, right) {
-            (
-                Object::Literal(Literal::Num(left_value)),
-                Object::Literal(Literal::Num(right_value)),
-            ) => match operator.token_type {
-                TokenType::PLUS => {
-                    let res = left_value + right_value;
-                    Ok(Object::Literal(Literal::Num(res)))
-                }
-                TokenType::MINUS => {
-                    let res = left_value - right_value;
-                    Ok(Object::Literal(Literal::Num(res)))
-                }
-                TokenType::SLASH => {
-                    let res = left_value / right_value;
-                    Ok(Object::Literal(Literal::Num(res)))
-                }
-                TokenType::STAR => {
-                    let res = left_value * right_value;
-                    Ok(Object::Literal(Literal::Num(res)))
+        loop {
+            let Some(debugger) = self.debugger.as_mut() else {
+                return;
+            };
+            match debugger.read_command() {
+                Command::Print(source) => {
+                    let text = self.debug_evaluate(&source);
+                    if let Some(debugger) = self.debugger.as_mut() {
+                        debugger.say(&text);
+                    }
                 }
-                TokenType::GREATER => {
-                    let res = left_value > right_value;
-                    Ok(Object::Literal(Literal::Bool(res)))
+                Command::Step => {
+                    debugger.stepping = true;
+                    return;
                 }
-                TokenType::GreaterEqual => {
-                    let res = left_value >= right_value;
-                    Ok(Object::Literal(Literal::Bool(res)))
+                Command::Continue => return,
+                Command::Backtrace => {
+                    for (name, line) in self.frames.iter().rev() {
+                        debugger.say(&format!("in fn {name} (line {line})"));
+                    }
+                    debugger.say("at top level");
                 }
-                TokenType::LESS => {
-                    let res = left_value < right_value;
-                    Ok(Object::Literal(Literal::Bool(res)))
+                Command::Help => debugger.help(),
+                Command::Unknown(command) => {
+                    debugger.say(&format!("Unknown command '{command}'."));
+                    debugger.help();
                 }
-                TokenType::LessEqual => {
-                    let res = left_value <= right_value;
-                    Ok(Object::Literal(Literal::Bool(res)))
-                }
-                TokenType::EqualEqual => {
-                    let res = left_value == right_value;
-                    Ok(Object::Literal(Literal::Bool(res)))
-                }
-                TokenType::BangEqual => {
-                    let res = left_value != right_value;
-                    Ok(Object::Literal(Literal::Bool(res)))
-                }
-                _ => Ok(Object::Literal(Literal::Nil)),
-            },
-            (
-                Object::Literal(Literal::Str(left_value)),
-                Object::Literal(Literal::Str(right_value)),
-            ) => match operator.token_type {
-                TokenType::PLUS => {
-                    let mut res = left_value;
-                    res.push_str(&right_value);
-                    Ok(Object::Literal(Literal::Str(res)))
+            }
+        }
+    }
+
+    // 在停下来的地方对表达式求值。新解析出来的变量没有经过解析器，
+    // 按名字在当前环境链上找局部变量，找不到的就是全局变量
+    fn debug_evaluate(&mut self, source: &str) -> String {
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        let mut parser = Parser::new(tokens);
+        parser.set_max_expression_depth(self.max_expression_depth);
+        let expr = match parser.parse_expression() {
+            Ok(expr) => expr,
+            Err(error) => return error.to_string(),
+        };
+        self.resolve_by_name(&expr);
+
+        match self.evaluate(&expr) {
+            Ok(value) => value.to_string(),
+            Err(error) => {
+                self.trace = None; // 这个错误不属于程序本身，不要留给之后的错误报告
+                error.to_string()
+            }
+        }
+    }
+
+    fn resolve_by_name(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Variable { id, name, .. } => {
+                if let Some((depth, slot)) = self.env.find_slot(&name.lexeme) {
+                    self.resolve(*id, depth, slot);
                 }
-                TokenType::EqualEqual => {
-                    let res = left_value == right_value;
-                    Ok(Object::Literal(Literal::Bool(res)))
+            }
+            Expr::Assign {
+                id, name, value, ..
+            } => {
+                if let Some((depth, slot)) = self.env.find_slot(&name.lexeme) {
+                    self.resolve(*id, depth, slot);
                 }
-                TokenType::BangEqual => {
-                    let res = left_value != right_value;
-                    Ok(Object::Literal(Literal::Bool(res)))
+                self.resolve_by_name(value);
+            }
+            Expr::Binary { left, right, .. } | Expr::Logic { left, right, .. } => {
+                self.resolve_by_name(left);
+                self.resolve_by_name(right);
+            }
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                self.resolve_by_name(callee);
+                for argument in arguments {
+                    self.resolve_by_name(argument);
                 }
-                _ => Ok(Object::Literal(Literal::Nil)),
-            },
-            (_, _) => match operator.token_type {
-                TokenType::PLUS => Err(Error::RuntimeError(
-                    operator.clone(),
-                    String::from("Operands must be two numbers or two strings."),
-                )),
-                _ => Err(Error::RuntimeError(
-                    operator.clone(),
-                    String::from("Operands must be numbers."),
-                )),
-            },
+            }
+            Expr::Grouping { expression, .. } => self.resolve_by_name(expression),
+            Expr::Unary { right, .. } => self.resolve_by_name(right),
+            Expr::Literal { .. } | Expr::Error { .. } => {}
         }
     }
 
-    fn visit_grouping_expr(&mut self, expression: &Expr) -> Result<Object> {
-        self.evaluate(expression)
+    // 进入和离开函数时在调用处的行号下打印，缩进和调用者的语句对齐
+    pub(crate) fn trace_call(&mut self, name: &str, arguments: &[Object]) {
+        if let (true, Some((_, line))) = (self.trace_execution, self.frames.last()) {
+            let arguments: Vec<String> = arguments.iter().map(trace_value).collect();
+            let text = format!("call {name}({})", arguments.join(", "));
+            self.trace_line(Some(*line), self.frames.len() - 1, &text);
+        }
     }
 
-    fn visit_literal_expr(&mut self, expr: &Literal) -> Result<Object> {
-        Ok(Object::Literal(expr.clone()))
+    pub(crate) fn trace_return(&mut self, name: &str, value: &Object) {
+        if let (true, Some((_, line))) = (self.trace_execution, self.frames.last()) {
+            let text = format!("return from {name}: {}", trace_value(value));
+            self.trace_line(Some(*line), self.frames.len() - 1, &text);
+        }
     }
 
-    fn visit_unary_expr(&mut self, operator: &Token, right: &Expr) -> Result<Object> {
-        let right = self.evaluate(right)?;
+    // 超过调用层数限制时在调用处报错，而不是等 Rust 的栈溢出
+    pub fn push_frame(&mut self, name: Rc<str>, paren: &Token) -> Result<()> {
+        if self.frames.len() >= self.max_call_depth {
+            return Err(stack_overflow(paren, self.max_call_depth));
+        }
+
+        self.frames.push((name, paren.line));
+        Ok(())
+    }
 
-        match operator.token_type {
-            TokenType::MINUS => match right {
-                Object::Literal(Literal::Num(x)) => {
-                    let neg = -x;
-                    Ok(Object::Literal(Literal::Num(neg)))
+    // 尾调用复用当前这一层
+    pub fn replace_frame(&mut self, name: Rc<str>, paren: &Token) {
+        if let Some(frame) = self.frames.last_mut() {
+            *frame = (name, paren.line);
+        }
+    }
+
+    // 错误从最内层的调用传出来时记下当时的调用栈，外层再传出时已经记过了
+    pub fn pop_frame<T>(&mut self, result: &Result<T>) {
+        if let Err(Error::RuntimeError(..)) = result {
+            if self.trace.is_none() {
+                self.trace = Some(self.frames.clone());
+            }
+        }
+        self.frames.pop();
+    }
+
+    // 取走上一个运行时错误的调用栈，错误发生在顶层时为空
+    pub fn take_trace(&mut self) -> Vec<(Rc<str>, usize)> {
+        self.trace.take().unwrap_or_default()
+    }
+
+    // 返回最后一条语句的值：最后一条是表达式语句时就是表达式的值，否则是 nil
+    pub fn interpret(&mut self, stmts: Vec<Stmt>) -> Result<Object> {
+        let mut last_value = Object::Literal(Literal::Nil);
+        // 表达式的 id 只增不减，REPL 和 --watch 里不清空的话缓存会越积越多，还让旧的函数对象一直活着。
+        // 清掉之后最多是重新查一次
+        self.call_sites.clear();
+
+        // 遇到第一个运行时错误就停下，后面的语句依赖的状态可能没有建立起来
+        for stmt in stmts {
+            let result = match &stmt {
+                Stmt::Expression { expression, .. } => {
+                    self.before_stmt(&stmt);
+                    self.evaluate(expression).map(Some)
                 }
-                _ => Err(Error::RuntimeError(
-                    operator.clone(),
-                    String::from("Operand must be a number."),
-                )),
-            },
-            TokenType::BANG => Ok(Object::Literal(Literal::Bool(!self.is_truthy(right)))),
-            _ => Err(Error::RuntimeError(
-                operator.clone(),
-                String::from("Operand must be a number."),
-            )),
+                _ => match self.execute(&stmt) {
+                    Ok(()) => Ok(None),
+                    Err(Unwind::Error(e)) => Err(e),
+                    // 解析器不允许在顶层 return，真遇到了就当作程序结束
+                    Err(Unwind::Return(_)) | Err(Unwind::TailCall(..)) => break,
+                },
+            };
+            last_value = result?.unwrap_or(Object::Literal(Literal::Nil));
         }
+
+        Ok(last_value)
     }
 
-    fn visit_var_expr(&mut self, name: &Token) -> Result<Object> {
-        // 变量表达式
-        let expr = Expr::Variable { name: name.clone() };
-        self.lookup_variable(name.to_owned(), &expr)
+    pub fn interpret_expression(&mut self, expr: &Expr) -> Result<Object> {
+        self.evaluate(expr)
     }
 
-    fn visit_assign_expr(&mut self, name: &Token, value: &Expr) -> Result<Object> {
-        let value_object = self.evaluate(value)?;
-        let distance = self.locals.get(value);
-        match distance {
-            Some(dis) => match self.env.assign_at(dis, name, &value_object) {
-                None => self.env.assign(name, &value_object)?,
-                _ => (),
-            },
-            None => self.globals.assign(name, &value_object)?,
+    // 按名字读全局变量，原生函数也能读到。没有定义或者声明了还没赋值时返回 None
+    pub fn get_global(&self, name: &str) -> Option<Object> {
+        match self.globals.lookup(name)? {
+            Object::Uninitialized => None,
+            value => Some(value),
         }
+    }
 
-        Ok(value_object)
+    // 从 Rust 调用一个函数值，和脚本里的调用一样检查参数个数、接住函数里的 return。
+    // 函数体里的运行时错误带着原来的行号返回；这次调用本身没有调用处，
+    // 参数个数不对、不是函数这类错误算在第 0 行
+    pub fn call_function(&mut self, callee: &Object, arguments: Vec<Object>) -> Result<Object> {
+        let paren = Token::new(
+            TokenType::RightParen,
+            Rc::from(")"),
+            Literal::Nil,
+            0,
+            0,
+            0..0,
+        );
+        match callee {
+            Object::Callable(callable) => {
+                callable.check_arity(&paren, arguments.len())?;
+                callable.call(self, &paren, arguments)
+            }
+            value => Err(not_callable(&paren, None, value)),
+        }
     }
 
-    fn visit_logic_expr(&mut self, left: &Expr, operator: &Token, right: &Expr) -> Result<Object> {
-        let evaluated_left = self.evaluate(left);
-        let is_left_truthy = self.is_truthy(evaluated_left.clone()?);
+    fn execute(&mut self, stmt: &Stmt) -> Exec {
+        self.steps += 1;
+        if self.steps > self.next_check {
+            self.check_budget(stmt.span())?;
+        }
+        self.before_stmt(stmt);
+        stmt.accept(self)
+    }
+
+    pub fn resolve(&mut self, id: usize, depth: usize, slot: usize) {
+        self.locals.insert(id, (depth, slot));
+    }
+
+    // 全局变量按名字存，局部变量按声明顺序存
+    fn define(&self, name: &Token, value: &Object) {
+        if Rc::ptr_eq(&self.env, &self.globals) {
+            self.env.define(name.lexeme.clone(), value);
+        } else {
+            self.env.define_slot(value);
+            if self.debugging() {
+                self.env.name_slot(name.lexeme.clone());
+            }
+        }
+    }
+
+    pub fn execute_block(&mut self, stmts: &[Stmt], env: Environment) -> Exec {
+        let mut scope = Scope::enter(self, env);
+        for stmt in stmts {
+            scope.execute(stmt)?;
+        }
+
+        Ok(())
+    }
+
+    fn evaluate(&mut self, expr: &Expr) -> Result<Object> {
+        self.count_step(expr)?;
+        match expr {
+            Expr::Binary { .. } | Expr::Logic { .. } => self.evaluate_chain(expr),
+            _ => expr.accept(self),
+        }
+    }
+
+    fn count_step(&mut self, expr: &Expr) -> Result<()> {
+        self.steps += 1;
+        if self.steps > self.next_check {
+            self.check_budget(expr.span())?;
+        }
+        Ok(())
+    }
+
+    // 解析器已经限制了语法树的高度（链上的每一节也算一层），这里再防一手不是由解析器构造出来的语法树。
+    // 链由 evaluate_chain 循环求值，用不着在这里计数，只在括号、一元运算符、赋值的右边和实参处计数
+    fn evaluate_nested(&mut self, expr: &Expr) -> Result<Object> {
+        if self.depth >= self.max_expression_depth {
+            if let Some(token) = expr.token() {
+                return Err(Error::RuntimeError(
+                    token.clone(),
+                    String::from("Expression too deeply nested."),
+                ));
+            }
+        }
+
+        self.depth += 1;
+        let result = self.evaluate(expr);
+        self.depth -= 1;
+
+        result
+    }
 
+    // `1 + 2 + … + n` 是一条往左下延伸的链，不用递归求值：先沿着左边找到链的起点，再从下往上逐个运算
+    fn evaluate_chain(&mut self, expr: &Expr) -> Result<Object> {
+        let mut chain = vec![expr];
+        let mut first = expr;
+        while let Expr::Binary { left, .. } | Expr::Logic { left, .. } = first {
+            first = left;
+            if matches!(first, Expr::Binary { .. } | Expr::Logic { .. }) {
+                self.count_step(first)?;
+                chain.push(first);
+            }
+        }
+
+        let mut value = self.evaluate(first)?;
+        for expr in chain.into_iter().rev() {
+            value = match expr {
+                Expr::Binary {
+                    operator, right, ..
+                } => self.binary_operation(value, operator, right)?,
+                Expr::Logic {
+                    operator, right, ..
+                } => self.logic_operation(value, operator, right)?,
+                _ => unreachable!(),
+            };
+        }
+
+        Ok(value)
+    }
+
+    fn binary_operation(&mut self, left: Object, operator: &Token, right: &Expr) -> Result<Object> {
+        let right = self.evaluate(right)?;
+        binary(operator, &left, &right)
+    }
+
+    // 短路求值：左边已经能决定结果时不求值右边
+    fn logic_operation(&mut self, left: Object, operator: &Token, right: &Expr) -> Result<Object> {
+        let is_left_truthy = left.is_truthy();
         if operator.token_type == TokenType::OR {
             if is_left_truthy {
-                return evaluated_left;
+                return Ok(left);
             }
         } else if !is_left_truthy {
-            return evaluated_left;
+            return Ok(left);
         }
 
         self.evaluate(right)
     }
 
-    fn visit_call_expr(
+    // 求值被调用者和实参并检查参数个数，普通调用和尾调用共用
+    fn prepare_call(
         &mut self,
         callee: &Expr,
         paren: &Token,
         arguments: &Vec<Expr>,
-    ) -> Result<Object> {
-        let callee = self.evaluate(callee)?;
+    ) -> Result<(Callable, Vec<Object>)> {
+        let callee_expr = callee;
+        let callee = self.lookup_callee(callee_expr)?;
 
         let mut args = Vec::new();
         for argument in arguments {
-            args.push(self.evaluate(argument)?);
+            args.push(self.evaluate_nested(argument)?);
         }
 
         match callee {
             Object::Callable(callable) => {
-                if args.len() != callable.arity() {
-                    let message = format!(
-                        "Expected {} arguments but got {}.",
-                        callable.arity(),
-                        args.len()
-                    );
-                    return Err(Error::RuntimeError(paren.to_owned(), message));
-                }
+                callable.check_arity(paren, args.len())?;
+                Ok((callable, args))
+            }
+            _ => {
+                let name = match callee_expr {
+                    Expr::Variable { name, .. } => Some(&*name.lexeme),
+                    _ => None,
+                };
+                Err(not_callable(paren, name, &callee))
+            }
+        }
+    }
 
-                callable.call(self, args)
+    // 循环里反复调用同一个全局函数（包括原生函数）时，每次都按名字查要算哈希、
+    // 还可能要再去外层的原生函数环境里找一遍。第一次查到的函数记在调用处，
+    // 之后全局环境的 generation 没变就直接用
+    fn lookup_callee(&mut self, callee: &Expr) -> Result<Object> {
+        let id = match callee {
+            Expr::Variable { id, .. } => *id,
+            _ => return self.evaluate(callee),
+        };
+
+        let generation = self.globals.generation();
+        if let Some((cached, value)) = self.call_sites.get(&id) {
+            if *cached == generation {
+                return Ok(value.clone());
             }
-            _ => Err(Error::RuntimeError(
-                paren.to_owned(),
-                String::from("Can only call functions and classes."),
-            )),
+        }
+
+        let value = self.evaluate(callee)?;
+        if let Object::Callable(_) = value {
+            if !self.locals.contains_key(&id) {
+                self.call_sites.insert(id, (generation, value.clone()));
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn lookup_variable(&mut self, id: usize, name: &Token) -> Result<Object> {
+        match self.locals.get(&id) {
+            Some((distance, slot)) => self
+                .env
+                .get_at_slot(distance, *slot)
+                .ok_or_else(|| undefined_variable(name)),
+            None => self.globals.get(name),
         }
     }
 }
 
-impl stmt::Visitor<Result<()>> for Interpreter {
-    fn visit_expression_stmt(&mut self, expression: &Expr) -> Result<()> {
+// 进入块时换上新的环境，Scope 被丢弃时（不管是正常结束、出错还是 return）换回原来的环境
+struct Scope<'a> {
+    interpreter: &'a mut Interpreter,
+    previous_env: Rc<Environment>,
+    previous_depth: usize,
+}
+
+impl<'a> Scope<'a> {
+    fn enter(interpreter: &'a mut Interpreter, env: Environment) -> Scope<'a> {
+        let previous_env = std::mem::replace(&mut interpreter.env, Rc::new(env));
+        // 语句只会通过函数调用出现在表达式内部，函数体里的表达式嵌套层数重新计算
+        let previous_depth = std::mem::take(&mut interpreter.depth);
+
+        Scope {
+            interpreter,
+            previous_env,
+            previous_depth,
+        }
+    }
+}
+
+impl Drop for Scope<'_> {
+    fn drop(&mut self) {
+        std::mem::swap(&mut self.interpreter.env, &mut self.previous_env);
+        self.interpreter.depth = self.previous_depth;
+    }
+}
+
+impl Deref for Scope<'_> {
+    type Target = Interpreter;
+
+    fn deref(&self) -> &Interpreter {
+        self.interpreter
+    }
+}
+
+impl DerefMut for Scope<'_> {
+    fn deref_mut(&mut self) -> &mut Interpreter {
+        self.interpreter
+    }
+}
+
+impl expr::Visitor<Result<Object>> for Interpreter {
+    fn visit_binary_expr(&mut self, left: &Expr, operator: &Token, right: &Expr) -> Result<Object> {
+        let left = self.evaluate(left)?;
+        self.binary_operation(left, operator, right)
+    }
+
+    fn visit_grouping_expr(&mut self, expression: &Expr) -> Result<Object> {
+        self.evaluate_nested(expression)
+    }
+
+    fn visit_literal_expr(&mut self, expr: &Literal) -> Result<Object> {
+        Ok(Object::Literal(expr.clone()))
+    }
+
+    fn visit_unary_expr(&mut self, operator: &Token, right: &Expr) -> Result<Object> {
+        let right = self.evaluate_nested(right)?;
+
+        unary(operator, &right)
+    }
+
+    fn visit_var_expr(&mut self, id: usize, name: &Token) -> Result<Object> {
+        // 变量表达式
+        match self.lookup_variable(id, name)? {
+            Object::Uninitialized => Err(uninitialized_variable(name)),
+            value => Ok(value),
+        }
+    }
+
+    fn visit_assign_expr(&mut self, id: usize, name: &Token, value: &Expr) -> Result<Object> {
+        let value_object = self.evaluate_nested(value)?;
+        // 解析器算出了距离的是局部变量，直接写到那一层环境；没有距离的一定是全局变量
+        match self.locals.get(&id) {
+            Some((distance, slot)) => {
+                if self
+                    .env
+                    .assign_at_slot(distance, *slot, &value_object)
+                    .is_none()
+                {
+                    return Err(undefined_variable(name));
+                }
+            }
+            None => self.globals.assign(name, &value_object)?,
+        }
+
+        Ok(value_object)
+    }
+
+    fn visit_logic_expr(&mut self, left: &Expr, operator: &Token, right: &Expr) -> Result<Object> {
+        let left = self.evaluate(left)?;
+        self.logic_operation(left, operator, right)
+    }
+
+    fn visit_call_expr(
+        &mut self,
+        callee: &Expr,
+        paren: &Token,
+        arguments: &Vec<Expr>,
+    ) -> Result<Object> {
+        let (callable, args) = self.prepare_call(callee, paren, arguments)?;
+        callable.call(self, paren, args)
+    }
+
+    fn visit_error_expr(&mut self, token: &Token) -> Result<Object> {
+        Err(syntax_error(token))
+    }
+}
+
+impl stmt::Visitor<Exec> for Interpreter {
+    fn visit_expression_stmt(&mut self, expression: &Expr) -> Exec {
         self.evaluate(expression)?;
 
         Ok(())
     }
 
-    fn visit_print_stmt(&mut self, expression: &Expr) -> Result<()> {
+    fn visit_print_stmt(&mut self, expression: &Expr) -> Exec {
         let value = self.evaluate(expression)?;
-        println!("{value}");
+        // 写不出去（比如管道被关掉了）时程序照常运行，和 jlox 的 System.out 一样不报错
+        let _ = writeln!(self.writer(), "{value}");
 
         Ok(())
     }
 
-    fn visit_var_stmt(&mut self, name: &Token, expression: &Expr) -> Result<()> {
-        let value = self.evaluate(expression)?;
-        self.env.define(name.lexeme.clone(), &value);
+    fn visit_var_stmt(&mut self, name: &Token, expression: &Option<Expr>) -> Exec {
+        let value = match expression {
+            Some(expression) => self.evaluate(expression)?,
+            None => Object::Uninitialized,
+        };
+        self.define(name, &value);
 
         Ok(())
     }
 
-    fn visit_block_stmt(&mut self, stmts: &Vec<Stmt>) -> Result<()> {
+    fn visit_block_stmt(&mut self, stmts: &Vec<Stmt>) -> Exec {
         let env = self.env.clone();
         self.execute_block(stmts, Environment::new(Some(env)))?;
 
@@ -309,24 +900,22 @@ impl stmt::Visitor<Result<()>> for Interpreter {
         condition: &Expr,
         then_branch: &Stmt,
         else_branch: &Option<Box<Stmt>>,
-    ) -> Result<()> {
+    ) -> Exec {
         let evaluated = self.evaluate(condition)?;
-        if self.is_truthy(evaluated) {
+        if evaluated.is_truthy() {
             self.execute(then_branch)?
-        }
-
-        match else_branch {
-            Some(else_branch) => self.execute(else_branch)?,
-            None => (),
+        } else if let Some(else_branch) = else_branch {
+            self.execute(else_branch)?
         }
 
         Ok(())
     }
 
-    fn visit_while_stmt(&mut self, condition: &Expr, body: &Stmt) -> Result<()> {
+    fn visit_while_stmt(&mut self, keyword: &Token, condition: &Expr, body: &Stmt) -> Exec {
         loop {
+            self.check_interrupt(keyword)?;
             let evaluated_condition = self.evaluate(condition)?;
-            if self.is_truthy(evaluated_condition) {
+            if evaluated_condition.is_truthy() {
                 self.execute(body)?
             } else {
                 break;
@@ -336,30 +925,410 @@ impl stmt::Visitor<Result<()>> for Interpreter {
         Ok(())
     }
 
-    fn visit_fun_stmt(
-        &mut self,
-        name: &Token,
-        params: &Vec<Token>,
-        body: &Vec<Stmt>,
-    ) -> Result<()> {
+    fn visit_fun_stmt(&mut self, name: &Token, params: &Rc<[Token]>, body: &Rc<[Stmt]>) -> Exec {
         let closure = self.env.clone(); // 这是在声明函数时而不是在调用函数时处于活动状态的环境（声明时）
-        let fun = Function::new(name.clone(), params.to_owned(), body.to_owned(), closure);
-        let function = Object::Callable(Callable::Function(fun));
+        let fun = Function::new(name.clone(), params.clone(), body.clone(), closure);
+        let function = Object::Callable(Callable::Function(Rc::new(fun)));
 
-        self.env.define(name.clone().lexeme, &function);
+        self.define(name, &function);
+        self.track_closure();
 
         Ok(())
     }
 
-    fn visit_return_stmt(&mut self, _keyword: &Token, value: &Expr) -> Result<()> {
+    fn visit_return_stmt(&mut self, _keyword: &Token, value: &Expr) -> Exec {
         let evaluated_value = match value {
             Expr::Literal {
                 value: Literal::Nil,
+                ..
             } => Object::Literal(Literal::Nil),
+            // 尾调用 Lox 函数时不在这里调用，交给外层的函数调用接着执行
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+                ..
+            } => match self.prepare_call(callee, paren, arguments)? {
+                (Callable::Function(function), args) => {
+                    return Err(Unwind::TailCall(function, paren.clone(), args))
+                }
+                (callable, args) => callable.call(self, paren, args)?,
+            },
             _ => self.evaluate(&value)?,
         };
 
         // 通过 Err 把要 Return 的值带出来（;前面没有表达式的话就是 nil）
-        Err(Error::Return(evaluated_value))
+        Err(Unwind::Return(evaluated_value))
+    }
+
+    fn visit_error_stmt(&mut self, token: &Token) -> Exec {
+        Err(syntax_error(token).into())
+    }
+}
+
+// 二元和一元运算，字节码虚拟机也用它们，两个后端的结果和报错保持一致
+pub(crate) fn binary(operator: &Token, left: &Object, right: &Object) -> Result<Object> {
+    // 任意两个值之间都可以比较是否相等，不同类型的值总是不相等
+    match operator.token_type {
+        TokenType::EqualEqual => return Ok(Object::Literal(Literal::Bool(left == right))),
+        TokenType::BangEqual => return Ok(Object::Literal(Literal::Bool(left != right))),
+        _ => (),
+    }
+
+    match (left, right) {
+        (Object::Literal(Literal::Num(left_value)), Object::Literal(Literal::Num(right_value))) => {
+            match operator.token_type {
+                TokenType::PLUS => {
+                    let res = left_value + right_value;
+                    Ok(Object::Literal(Literal::Num(res)))
+                }
+                TokenType::MINUS => {
+                    let res = left_value - right_value;
+                    Ok(Object::Literal(Literal::Num(res)))
+                }
+                TokenType::SLASH => {
+                    let res = left_value / right_value;
+                    Ok(Object::Literal(Literal::Num(res)))
+                }
+                TokenType::STAR => {
+                    let res = left_value * right_value;
+                    Ok(Object::Literal(Literal::Num(res)))
+                }
+                TokenType::GREATER => {
+                    let res = left_value > right_value;
+                    Ok(Object::Literal(Literal::Bool(res)))
+                }
+                TokenType::GreaterEqual => {
+                    let res = left_value >= right_value;
+                    Ok(Object::Literal(Literal::Bool(res)))
+                }
+                TokenType::LESS => {
+                    let res = left_value < right_value;
+                    Ok(Object::Literal(Literal::Bool(res)))
+                }
+                TokenType::LessEqual => {
+                    let res = left_value <= right_value;
+                    Ok(Object::Literal(Literal::Bool(res)))
+                }
+                _ => Ok(Object::Literal(Literal::Nil)),
+            }
+        }
+        // 字符串之间只能拼接
+        (Object::Literal(Literal::Str(left_value)), Object::Literal(Literal::Str(right_value)))
+            if operator.token_type == TokenType::PLUS =>
+        {
+            Ok(Object::Literal(Literal::Str(
+                format!("{left_value}{right_value}").into(),
+            )))
+        }
+        _ => Err(operands_error(operator, left, right)),
+    }
+}
+
+pub(crate) fn unary(operator: &Token, right: &Object) -> Result<Object> {
+    match operator.token_type {
+        TokenType::MINUS => match right {
+            Object::Literal(Literal::Num(x)) => {
+                let neg = -x;
+                Ok(Object::Literal(Literal::Num(neg)))
+            }
+            _ => Err(operand_error(operator, right)),
+        },
+        TokenType::BANG => Ok(Object::Literal(Literal::Bool(!right.is_truthy()))),
+        _ => Err(operand_error(operator, right)),
+    }
+}
+
+// 类型错误带上运算符和操作数的类型、值，方便在循环之类的地方找到出错的那一次
+fn operands_error(operator: &Token, left: &Object, right: &Object) -> Error {
+    let expected = match operator.token_type {
+        TokenType::PLUS => "two numbers or two strings",
+        _ => "numbers",
+    };
+    Error::RuntimeError(
+        operator.clone(),
+        format!(
+            "Operands of '{}' must be {}, got {} and {}.",
+            operator.lexeme,
+            expected,
+            left.describe(),
+            right.describe()
+        ),
+    )
+}
+
+fn operand_error(operator: &Token, right: &Object) -> Error {
+    Error::RuntimeError(
+        operator.clone(),
+        format!(
+            "Operand of '{}' must be a number, got {}.",
+            operator.lexeme,
+            right.describe()
+        ),
+    )
+}
+
+// 被调用的是变量时说出变量名，拼错函数名的时候一眼就能看出来
+pub(crate) fn not_callable(paren: &Token, name: Option<&str>, callee: &Object) -> Error {
+    let message = match name {
+        Some(name) => format!("'{}' is not callable (it is {}).", name, callee.type_name()),
+        None => format!(
+            "Can only call functions and classes, got {}.",
+            callee.describe()
+        ),
+    };
+    Error::RuntimeError(paren.clone(), message)
+}
+
+// `print "a";` 这样没有 token 的语句不知道行号，显示为 `?`
+fn line_label(line: Option<usize>) -> String {
+    match line {
+        Some(line) => format!("[line {line}]"),
+        None => String::from("[line ?]"),
+    }
+}
+
+// 跟踪里的字符串加上引号，和数字、nil 等区分开
+fn trace_value(value: &Object) -> String {
+    match value {
+        Object::Literal(Literal::Str(string)) => format!("{string:?}"),
+        _ => value.to_string(),
+    }
+}
+
+pub(crate) fn stack_overflow(paren: &Token, max_call_depth: usize) -> Error {
+    Error::RuntimeError(
+        paren.clone(),
+        format!("Stack overflow (max call depth {max_call_depth} exceeded)."),
+    )
+}
+
+pub(crate) fn uninitialized_variable(name: &Token) -> Error {
+    Error::RuntimeError(
+        name.clone(),
+        format!("Variable '{}' used before being initialized.", name.lexeme),
+    )
+}
+
+// 只有解析器算出的距离和运行时的环境对不上时才会走到这里
+fn undefined_variable(name: &Token) -> Error {
+    Error::RuntimeError(
+        name.clone(),
+        format!("Undefined variable '{}'.", name.lexeme),
+    )
+}
+
+// 带错误节点的树只能给工具用，不能执行
+pub(crate) fn syntax_error(token: &Token) -> Error {
+    Error::RuntimeError(
+        token.clone(),
+        String::from("Can't run code that failed to parse."),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::Resolver;
+    use std::cell::RefCell;
+    use std::thread;
+    use std::time::Duration;
+
+    // 运行程序，中途由另一个线程像信号处理函数一样设置标志，after 为零时在运行之前就设置好。
+    // 返回运行时错误和它所在的行
+    fn interrupt(source: &str, after: Duration) -> (String, usize) {
+        let mut interpreter = Interpreter::new();
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        let (statements, errors) = Parser::new(tokens).parse();
+        assert!(errors.is_empty());
+        Resolver::new(&mut interpreter, true)
+            .resolve(&statements)
+            .unwrap();
+
+        let flag = Arc::new(AtomicBool::new(after.is_zero()));
+        interpreter.set_interrupt_flag(flag.clone());
+        let ctrl_c = thread::spawn(move || {
+            thread::sleep(after);
+            flag.store(true, Ordering::Relaxed);
+        });
+        let result = interpreter.interpret(statements);
+        ctrl_c.join().unwrap();
+        assert!(interpreter.interrupted());
+        match result {
+            Err(Error::RuntimeError(token, message)) => (message, token.line),
+            result => panic!("expected an interrupt, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn interrupt_stops_loops() {
+        let after = Duration::from_millis(10);
+        let expected = (String::from("Interrupted."), 2);
+        assert_eq!(interrupt("var i = 0;\nwhile (true) {}", after), expected);
+        assert_eq!(
+            interrupt("var i = 0;\nfor (;;) i = i + 1;", after),
+            expected
+        );
+    }
+
+    #[test]
+    fn interrupt_stops_calls() {
+        let source = "fun f() {\n  f();\n}\nf();";
+        let expected = (String::from("Interrupted."), 4);
+        assert_eq!(interrupt(source, Duration::ZERO), expected);
+    }
+
+    #[test]
+    fn interrupt_stops_tail_calls() {
+        let source = "fun g(n) {\n  return g(n + 1);\n}\ng(0);";
+        let expected = (String::from("Interrupted."), 2);
+        assert_eq!(interrupt(source, Duration::from_millis(10)), expected);
+    }
+
+    // 共享的内存缓冲区，解释器拿走一份，测试留一份读结果
+    #[derive(Clone, Default)]
+    struct Captured(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn print_writes_to_the_configured_writer() {
+        let (output, errors) = (Captured::default(), Captured::default());
+        let mut interpreter = Interpreter::new();
+        interpreter.set_writer(Box::new(output.clone()));
+        interpreter.set_error_writer(Box::new(errors.clone()));
+        interpreter.set_trace_execution(true);
+
+        crate::run_source("print 1 + 2;\nprint \"hello\";", &mut interpreter).unwrap();
+        assert_eq!(*output.0.borrow(), b"3\nhello\n");
+        let trace = String::from_utf8(errors.0.borrow().clone()).unwrap();
+        assert_eq!(trace.lines().count(), 2, "{trace}");
+    }
+
+    #[test]
+    fn ignored_breakpoint_warns_on_the_error_writer() {
+        let errors = Captured::default();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_writer(Box::new(Captured::default()));
+        interpreter.set_error_writer(Box::new(errors.clone()));
+
+        crate::run_source("breakpoint();\nbreakpoint();", &mut interpreter).unwrap();
+        let warnings = String::from_utf8(errors.0.borrow().clone()).unwrap();
+        assert_eq!(
+            warnings,
+            "[line 1, col 12] Warning: breakpoint() is ignored when not running in a terminal.\n"
+        );
+    }
+
+    // 返回运行时错误的信息和行号
+    fn over_budget(interpreter: &mut Interpreter, source: &str) -> (String, usize) {
+        match crate::run_source(source, interpreter) {
+            Err(errors) => match &errors[..] {
+                [Error::RuntimeError(token, message)] => (message.clone(), token.line),
+                errors => panic!("expected one runtime error, got {errors:?}"),
+            },
+            Ok(value) => panic!("expected the budget to run out, got {value}"),
+        }
+    }
+
+    #[test]
+    fn step_limit_stops_infinite_loops() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_step_limit(10_000);
+        let expected = (String::from("Execution budget exceeded."), 2);
+        assert_eq!(
+            over_budget(
+                &mut interpreter,
+                "var i = 0;
+while (true) {}"
+            ),
+            expected
+        );
+
+        interpreter.set_step_limit(10_000);
+        let source = "fun f(n) {
+  return f(n + 1);
+}
+f(0);";
+        assert_eq!(over_budget(&mut interpreter, source).0, expected.0);
+    }
+
+    #[test]
+    fn programs_within_the_step_limit_are_unaffected() {
+        let output = Captured::default();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_writer(Box::new(output.clone()));
+
+        // 一条 print 语句和它的字面量，正好两步
+        interpreter.set_step_limit(2);
+        crate::run_source("print 1;", &mut interpreter).unwrap();
+        interpreter.set_step_limit(1);
+        assert_eq!(
+            over_budget(&mut interpreter, "print 2;"),
+            (String::from("Execution budget exceeded."), 1)
+        );
+
+        interpreter.set_step_limit(100_000);
+        let source =
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\nprint fib(10);";
+        crate::run_source(source, &mut interpreter).unwrap();
+        assert_eq!(*output.0.borrow(), b"1\n55\n");
+    }
+
+    #[test]
+    fn deadline_stops_infinite_loops() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_deadline(Some(Instant::now() + Duration::from_millis(20)));
+        let expected = (String::from("Execution deadline exceeded."), 1);
+        assert_eq!(over_budget(&mut interpreter, "for (;;) {}"), expected);
+    }
+
+    #[test]
+    fn chains_the_parser_accepts_evaluate() {
+        let output = Captured::default();
+        let mut interpreter = Interpreter::new();
+        interpreter.set_writer(Box::new(output.clone()));
+
+        // 解析器接受的链，求值时也不能因为嵌套层数出错
+        let sum = vec!["1"; 250].join(" + ");
+        let any = vec!["false"; 250].join(" or ");
+        let source = format!("print {sum};\nprint {any};");
+        crate::run_source(&source, &mut interpreter).unwrap();
+        assert_eq!(*output.0.borrow(), b"250\nfalse\n");
+    }
+
+    // 解析器放过的树，求值时按解释器自己的上限检查
+    #[test]
+    fn expression_depth_limit_is_configurable() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_writer(Box::new(Captured::default()));
+        interpreter.set_max_expression_depth(3);
+        match crate::run_source("print - - - - - - 1;", &mut interpreter) {
+            Err(errors) => assert!(matches!(
+                &errors[..],
+                [Error::RuntimeError(_, message)] if message == "Expression too deeply nested."
+            )),
+            Ok(_) => panic!("expected the depth limit to stop evaluation"),
+        }
+    }
+
+    // REPL 里每一行都是新解析的表达式，缓存不能跟着运行的次数一直变大
+    #[test]
+    fn call_site_cache_does_not_grow_across_runs() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_writer(Box::new(Captured::default()));
+        crate::run_source("fun f() { return 1; }", &mut interpreter).unwrap();
+        for _ in 0..10 {
+            crate::run_source("f(); f();", &mut interpreter).unwrap();
+            assert_eq!(interpreter.call_sites.len(), 2);
+        }
     }
 }
@@ -0,0 +1,70 @@
+//! A tree-walking interpreter (and an experimental bytecode VM) for Lox.
+//!
+//! Run a program against an interpreter that keeps its globals between calls:
+//!
+//! ```
+//! use rjlox::{run_source, Interpreter};
+//!
+//! let mut interpreter = Interpreter::new();
+//! run_source("fun square(x) { return x * x; }", &mut interpreter).unwrap();
+//! let value = run_source("square(7);", &mut interpreter).unwrap();
+//! assert_eq!(value.to_string(), "49");
+//! ```
+
+pub mod ast_printer;
+mod callable;
+mod chunk;
+mod compiler;
+pub mod debugger;
+pub mod environment;
+pub mod error;
+pub mod expr;
+mod gc;
+pub mod interpreter;
+pub mod object;
+pub mod parser;
+pub mod profiler;
+pub mod resolver;
+pub mod scanner;
+pub mod sources;
+pub mod stmt;
+pub mod token;
+pub mod vm;
+#[cfg(feature = "wasm-bindgen")]
+pub mod wasm;
+
+pub use crate::error::Error;
+pub use crate::interpreter::Interpreter;
+pub use crate::object::Object;
+pub use crate::parser::Parser;
+pub use crate::resolver::Resolver;
+pub use crate::scanner::Scanner;
+
+use crate::error::Severity;
+
+// 解析（扫描出的非法字符和没闭合的字符串也在这一步报告）、变量解析、执行，
+// 一步出错就停下，返回这一步的所有错误；警告不算错误。
+// 结果是最后一条表达式语句的值，没有的话是 nil。什么都不往终端上打印，报告错误由调用者决定
+pub fn run_source(source: &str, interp: &mut Interpreter) -> Result<Object, Vec<Error>> {
+    let tokens = Scanner::new(source.to_string()).scan_tokens();
+
+    let (statements, errors) = Parser::new(tokens).parse();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut resolver = Resolver::new(interp, true);
+    resolver.resolve(&statements).map_err(|e| vec![e])?;
+    let errors: Vec<Error> = resolver
+        .diagnostics()
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .cloned()
+        .map(Error::ResolveError)
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    interp.interpret(statements).map_err(|e| vec![e])
+}
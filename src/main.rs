@@ -1,100 +1,1036 @@
-mod callable;
-mod environment;
-mod error;
-mod expr;
-mod interpreter;
-mod object;
-mod parser;
-mod resolver;
-mod scanner;
-mod stmt;
-mod token;
-
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
+mod completer;
+mod watch;
+
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::completer::LoxHelper;
+use clap::{Parser, ValueEnum};
+use rjlox::ast_printer::AstPrinter;
+use rjlox::debugger::Debugger;
+use rjlox::environment::Environment;
+use rjlox::error::{self, Error, Result, Severity};
+use rjlox::expr::Expr;
+use rjlox::interpreter::{Interpreter, DEFAULT_MAX_CALL_DEPTH};
+use rjlox::object::Object;
+use rjlox::resolver::Resolver;
+use rjlox::sources::{FixedTime, SeededRandom, FIXED_SEED};
+use rjlox::stmt::Stmt;
+use rjlox::token::Token;
+use rjlox::vm::Vm;
+use rjlox::{parser, scanner};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
 
-use crate::environment::Environment;
-use crate::error::Error;
-use crate::error::Result;
-use crate::interpreter::Interpreter;
-use crate::resolver::Resolver;
-use clap::Parser;
+// 解释器线程的栈大小：固定部分留给解析和表达式求值，
+// 另外每层 Lox 函数调用按 64 KiB 算（调试构建下一层调用大约要用掉二三十 KiB）
+const BASE_STACK_SIZE: usize = 8 * 1024 * 1024;
+const STACK_SIZE_PER_CALL: usize = 64 * 1024;
 
 /// rjlox interpreter
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Name of the lox file to interpreter
-    #[arg(short, long)]
+    /// Lox files to run in order against one interpreter (`-` for stdin), followed by
+    /// arguments for the script. Arguments after the first one that don't end in `.lox`
+    /// belong to the script
+    // 脚本路径之后的参数都属于脚本，即使以 `-` 开头也不当作 rjlox 的选项；
+    // 脚本路径之前的未知选项仍然是用法错误，不会被当成文件名。
+    // 紧跟在第一个脚本后面、以 .lox 结尾的参数是接着运行的文件
+    #[arg(value_name = "SCRIPT", trailing_var_arg = true)]
+    script: Vec<String>,
+
+    /// Lox file to run (same as giving SCRIPT)
+    // 旧的写法，保留下来兼容已有的用法
+    #[arg(short, long, hide = true)]
     run: Option<String>,
+
+    /// Run SOURCE instead of a file; repeat to run several snippets in order
+    #[arg(short, long, value_name = "SOURCE", allow_hyphen_values = true)]
+    eval: Vec<String>,
+
+    /// Maximum depth of nested Lox function calls
+    #[arg(long, default_value_t = DEFAULT_MAX_CALL_DEPTH)]
+    max_call_depth: usize,
+
+    /// How to execute the program
+    #[arg(long, value_enum, default_value_t = Backend::Tree)]
+    backend: Backend,
+
+    /// Print how long scanning, parsing, resolving and interpreting took to stderr
+    #[arg(long)]
+    time: bool,
+
+    /// Log each statement and function call to stderr as it runs (tree-walking backend only)
+    #[arg(long)]
+    trace: bool,
+
+    /// Print calls and time spent in each Lox function to stderr when the program ends (tree-walking backend only)
+    #[arg(long)]
+    profile: bool,
+
+    /// Run PATH before the program; the REPL also loads ~/.config/rjlox/prelude.lox when it exists
+    #[arg(long, value_name = "PATH")]
+    prelude: Option<String>,
+
+    /// Run the script again with a fresh interpreter whenever it changes
+    #[arg(long)]
+    watch: bool,
+
+    /// Make clock(), now() and random() return the same values on every run
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Report errors and warnings to stderr as one JSON object per line
+    #[arg(long)]
+    json_diagnostics: bool,
+
+    /// Print the scanned tokens instead of running; reads stdin when no program is given
+    #[arg(long, group = "mode")]
+    dump_tokens: bool,
+
+    /// Print the syntax tree instead of running; reads stdin when no program is given
+    #[arg(long, group = "mode")]
+    dump_ast: bool,
+
+    /// With --dump-ast, follow each node with its source range as line:col-line:col
+    #[arg(long, requires = "dump_ast")]
+    spans: bool,
+
+    /// Print the syntax tree as JSON instead of running; reads stdin when no program is given
+    #[arg(long, group = "mode")]
+    dump_ast_json: bool,
 }
 
-fn main() -> io::Result<()> {
-    let args = Args::parse();
+// 命令行选择的工作方式。查看中间结果的选项互相排斥，各是一种模式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Run, // 运行脚本，没有脚本时运行标准输入或者进入 REPL
+    DumpTokens,
+    DumpAst,
+    DumpAstJson,
+}
+
+impl Args {
+    fn mode(&self) -> Mode {
+        if self.dump_tokens {
+            Mode::DumpTokens
+        } else if self.dump_ast {
+            Mode::DumpAst
+        } else if self.dump_ast_json {
+            Mode::DumpAstJson
+        } else {
+            Mode::Run
+        }
+    }
+
+    // 程序从哪里来，以及传给脚本的参数。用了 --run 或 --eval 时，位置参数全都是脚本的参数；
+    // 什么都没给时程序是空的。等有了 import，连着给出多个文件的写法就用不着了
+    fn input(&self) -> (Vec<Input<'_>>, &[String]) {
+        if let Some(path) = &self.run {
+            return (vec![Input::File(path)], &self.script);
+        }
+        if !self.eval.is_empty() {
+            return (vec![Input::Eval(&self.eval)], &self.script);
+        }
+        let Some((first, rest)) = self.script.split_first() else {
+            return (Vec::new(), &[]);
+        };
+        let files = rest.iter().take_while(|arg| arg.ends_with(".lox")).count();
+        let program = std::iter::once(first)
+            .chain(&rest[..files])
+            .map(|path| match path.as_str() {
+                "-" => Input::Stdin,
+                path => Input::File(path),
+            })
+            .collect();
+        (program, &rest[files..])
+    }
+}
 
-    match args.run {
-        None => run_prompt(),
-        Some(program_name) => run_file(&program_name),
+#[derive(Clone, Copy)]
+enum Input<'a> {
+    File(&'a str),
+    Stdin,              // `-`，或者没给脚本而标准输入不是终端
+    Eval(&'a [String]), // 多个 --eval 按顺序拼成一个程序，共享同一个全局环境
+}
+
+impl Input<'_> {
+    // 报错时用的名字
+    fn name(&self) -> &str {
+        match self {
+            Input::File(path) => path,
+            Input::Stdin => "<stdin>",
+            Input::Eval(_) => "<eval>",
+        }
     }
 }
 
-fn run_file(path: &str) -> io::Result<()> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let mut source = String::from("");
-    let env = Environment::new(None);
-    let mut interpreter = Interpreter::new(env);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    /// Tree-walking interpreter
+    Tree,
+    /// Bytecode compiler and virtual machine (experimental)
+    Vm,
+}
+
+// 两个后端都用解释器做变量解析；选了虚拟机时程序交给它执行
+struct Runtime {
+    interpreter: Interpreter,
+    vm: Option<Vm>,
+    time: bool, // 每次运行之后打印各阶段的耗时
+}
+
+impl Runtime {
+    fn new(backend: Backend, max_call_depth: usize) -> Runtime {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_max_call_depth(max_call_depth);
+        let vm = match backend {
+            Backend::Tree => None,
+            Backend::Vm => {
+                let mut vm = Vm::new();
+                vm.set_max_call_depth(max_call_depth);
+                Some(vm)
+            }
+        };
+
+        Runtime {
+            interpreter,
+            vm,
+            time: false,
+        }
+    }
+
+    // 固定的时钟和随机种子，两个后端都换上
+    fn make_deterministic(&mut self) {
+        self.interpreter
+            .set_time_source(Box::new(FixedTime::default()));
+        self.interpreter
+            .set_random_source(Box::new(SeededRandom::new(FIXED_SEED)));
+        if let Some(vm) = &mut self.vm {
+            vm.set_time_source(Box::new(FixedTime::default()));
+            vm.set_random_source(Box::new(SeededRandom::new(FIXED_SEED)));
+        }
+    }
+
+    fn interpret(&mut self, statements: Vec<Stmt>) -> Result<Object> {
+        match &mut self.vm {
+            Some(vm) => vm.interpret(&statements),
+            None => self.interpreter.interpret(statements),
+        }
+    }
+
+    fn interpret_expression(&mut self, expr: &Expr) -> Result<Object> {
+        match &mut self.vm {
+            Some(vm) => vm.interpret_expression(expr),
+            None => self.interpreter.interpret_expression(expr),
+        }
+    }
+
+    // 程序结束时打印 --profile 的统计结果，出错退出之前也要打印
+    fn report_profile(&mut self) {
+        if let Some(profiler) = self.interpreter.take_profile() {
+            eprint!("{profiler}");
+        }
+    }
+
+    // 全局变量所在的环境，两个后端各有一个
+    fn globals(&self) -> &Rc<Environment> {
+        match &self.vm {
+            Some(vm) => vm.globals(),
+            None => &self.interpreter.globals,
+        }
+    }
+
+    fn take_trace(&mut self) -> Vec<(Rc<str>, usize)> {
+        match &mut self.vm {
+            Some(vm) => vm.take_trace(),
+            None => self.interpreter.take_trace(),
+        }
+    }
 
-    for line in reader.lines() {
-        source.push_str(&line.unwrap());
-        source.push('\n');
+    // 运行时错误和调用栈写到解释器的错误输出。输出 JSON 时每个错误只占一行，不带调用栈
+    fn report_error(&mut self, error: &Error) {
+        let trace = self.take_trace();
+        let output = self.interpreter.error_writer();
+        let _ = error::runtime_error(output, error);
+        if !error::json_diagnostics() {
+            let _ = error::stack_trace(output, &trace);
+        }
     }
+}
+
+fn main() -> io::Result<()> {
+    // 参数用错时按 sysexits 的约定以 64 退出；--help 和 --version 也走这里，照常以 0 退出
+    let args = Args::try_parse().unwrap_or_else(|e| {
+        if !e.use_stderr() {
+            e.exit();
+        }
+        let _ = e.print();
+        exit(64);
+    });
+
+    // 主线程的栈放不下调用层数上限那么深的递归，在按上限分配了栈的线程里运行
+    let stack_size = args
+        .max_call_depth
+        .saturating_mul(STACK_SIZE_PER_CALL)
+        .saturating_add(BASE_STACK_SIZE);
+    let interpreter = thread::Builder::new()
+        .stack_size(stack_size)
+        .spawn(move || {
+            // 报告错误的设置是线程局部的，要在解释器线程里设置
+            error::set_json_diagnostics(args.json_diagnostics);
+            // Lox 程序还读不到传给脚本的参数，这里只是把它们和 rjlox 自己的选项分开
+            let (program, _script_args) = args.input();
+            // 监视模式下 Ctrl-C 照常结束整个进程
+            let interrupt = Arc::new(AtomicBool::new(false));
+            if args.mode() == Mode::Run && !args.watch {
+                install_interrupt_handler(interrupt.clone());
+            }
+            let runtime = || {
+                let mut runtime = Runtime::new(args.backend, args.max_call_depth);
+                runtime.time = args.time;
+                runtime.interpreter.set_trace_execution(args.trace);
+                runtime.interpreter.set_profiling(args.profile);
+                runtime.interpreter.set_interrupt_flag(interrupt.clone());
+                if args.deterministic {
+                    runtime.make_deterministic();
+                }
+                // breakpoint() 停下来时要和用户交互，只有在终端里才有意义
+                if io::stdin().is_terminal() {
+                    runtime.interpreter.set_debugger(Debugger::stdio());
+                }
+                runtime
+            };
+            if args.watch {
+                let ([Input::File(path)], Mode::Run) = (&program[..], args.mode()) else {
+                    eprintln!("--watch needs a script file to run.");
+                    exit(64);
+                };
+                return watch_file(path, args.prelude.as_deref(), runtime);
+            }
 
-    if run(&source, &mut interpreter).is_err() {
-        exit(70);
+            // 没有给脚本时，终端里进入 REPL，输入是管道时把它当作脚本整个运行
+            if program.is_empty() && args.mode() == Mode::Run && io::stdin().is_terminal() {
+                let prelude = args.prelude.as_ref().map(PathBuf::from);
+                return run_prompt(runtime, prelude.or_else(default_prelude));
+            }
+            let program = if program.is_empty() {
+                vec![Input::Stdin]
+            } else {
+                program
+            };
+
+            // 查看中间结果时一次只看一个程序
+            let source = || {
+                let [input] = program[..] else {
+                    eprintln!("--dump-tokens and --dump-ast[-json] take a single program.");
+                    exit(64);
+                };
+                read_source(input)
+            };
+            match args.mode() {
+                Mode::Run => run_file(args.prelude.as_deref(), &program, runtime()),
+                Mode::DumpTokens => dump_tokens(&source()),
+                Mode::DumpAst => dump_ast(&source(), args.spans),
+                Mode::DumpAstJson => dump_ast_json(&source()),
+            }
+        })?;
+
+    interpreter.join().unwrap()
+}
+
+// Ctrl-C 只设置标志，解释器在循环和函数调用处看到它就停下来报错。
+// 标志还没被清除时又按了一次，说明程序卡在了不检查标志的地方，直接以 130 退出
+fn install_interrupt_handler(interrupt: Arc<AtomicBool>) {
+    let _ = ctrlc::set_handler(move || {
+        if interrupt.swap(true, Ordering::Relaxed) {
+            exit(130);
+        }
+    });
+}
+
+// 一次性读入整个文件，保留原始的换行符。读不出来时退出
+fn read_source(input: Input) -> String {
+    load_source(input).unwrap_or_else(|code| exit(code))
+}
+
+// 读不出来时报告原因，返回按 sysexits 约定的退出码
+fn load_source(input: Input) -> std::result::Result<String, i32> {
+    let name = input.name();
+    let bytes = match input {
+        Input::File(path) => fs::read(path),
+        Input::Stdin => {
+            let mut bytes = Vec::new();
+            let result = io::stdin().lock().read_to_end(&mut bytes);
+            result.map(|_| bytes)
+        }
+        Input::Eval(snippets) => return Ok(snippets.join("\n")),
+    };
+    let bytes = match bytes {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Could not read '{name}': {e}.");
+            return Err(66);
+        }
     };
+    String::from_utf8(bytes).map_err(|e| {
+        let offset = e.utf8_error().valid_up_to();
+        eprintln!("'{name}' is not valid UTF-8 (invalid byte at offset {offset}).");
+        65
+    })
+}
+
+fn run_file(prelude: Option<&str>, program: &[Input], runtime: Runtime) -> io::Result<()> {
+    match run_program(prelude, program, runtime) {
+        0 => Ok(()),
+        code => exit(code),
+    }
+}
+
+// 把整个程序一次运行完，返回退出码：出错时按错误的阶段选择，被 Ctrl-C 打断时按惯例是 130。
+// 只有明确用 --prelude 给出的 prelude 才会先运行，脚本的行为不受用户配置的影响。
+// 程序由几个文件组成时，它们按顺序在同一个运行时里运行，前面的文件出错时后面的不再运行，
+// 静态错误前面带上出错的文件名
+fn run_program(prelude: Option<&str>, program: &[Input], mut runtime: Runtime) -> i32 {
+    let files: Vec<Input> = prelude
+        .map(Input::File)
+        .into_iter()
+        .chain(program.iter().copied())
+        .collect();
+    let mut result = Ok(());
+    for &input in &files {
+        let source = match load_source(input) {
+            Ok(source) => source,
+            Err(code) => return code,
+        };
+        // JSON 里总是带上文件名，编辑器要靠它找到出错的文件
+        if files.len() > 1 || error::json_diagnostics() {
+            error::set_current_file(Some(input.name()));
+        }
+        result = run(&source, &mut runtime, false);
+        if result.is_err() {
+            break;
+        }
+    }
+    error::set_current_file(None);
+
+    runtime.report_profile();
+    match result {
+        Ok(_) => 0,
+        Err(_) if runtime.interpreter.interrupted() => 130,
+        Err(Error::ParseError(..)) | Err(Error::ResolveError(_)) | Err(Error::Incomplete(_)) => 65,
+        Err(_) => 70,
+    }
+}
+
+// --watch：每次改动之后重新读入脚本，用新的运行时运行
+fn watch_file(
+    path: &str,
+    prelude: Option<&str>,
+    new_runtime: impl Fn() -> Runtime,
+) -> io::Result<()> {
+    let mut changes = watch::FileChanges::new(Path::new(path)).unwrap_or_else(|e| {
+        eprintln!("Could not watch '{path}': {e}.");
+        exit(66);
+    });
+    let run = || run_program(prelude, &[Input::File(path)], new_runtime());
+    let clear_screen = io::stdout().is_terminal();
+    watch::watch(path, &mut changes, clear_screen, &mut io::stdout(), run)
+}
+
+fn run_prompt(new_runtime: impl Fn() -> Runtime, prelude: Option<PathBuf>) -> io::Result<()> {
+    let mut editor = LineEditor::new().map_err(io::Error::other)?;
+    editor.set_helper(Some(LoxHelper::default()));
+    let history = history_path();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path); // 第一次运行时还没有历史文件
+    }
+
+    // rustyline 只在读一行的时候占用终端，调试器停下来时也能从标准输入读命令
+    let mut session = Session::new(new_runtime, prelude);
+    loop {
+        // 补全用的全局变量名：这时上一条输入定义的变量已经在里面了
+        if let Some(helper) = editor.helper_mut() {
+            helper.set_names(session.runtime.globals().names());
+        }
+        let Some(source) = read_entry(|prompt| read_line(&mut editor, prompt))? else {
+            break;
+        };
+        if !source.trim().is_empty() {
+            let _ = editor.add_history_entry(source.as_str());
+        }
+        if !session.run_entry(&source, &mut io::stdout())? {
+            break;
+        }
+    }
 
+    // 历史记录存不下来不影响这次的会话，错误直接忽略
+    if let Some(path) = &history {
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let _ = editor.save_history(path);
+    }
+    session.runtime.report_profile();
     Ok(())
 }
 
-fn run_prompt() -> io::Result<()> {
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
-    let env = Environment::new(None);
-    let mut interpreter = Interpreter::new(env);
+const REPL_HELP: &str = "Commands: :load <path>, :reset, :env, :time, :ast [source], :quit.";
+
+// REPL 的一次会话，每条输入都在同一个运行时里执行。
+// :reset 时用 new_runtime 换一个新的运行时，命令行上的选项照样生效，prelude 也重新运行
+struct Session<F> {
+    runtime: Runtime,
+    new_runtime: F,
+    prelude: Option<PathBuf>,
+    timing: bool,               // :time 打开时每条输入执行完都打印各阶段的耗时
+    last_entry: Option<String>, // 上一条执行过的输入，不带参数的 :ast 重新解析它
+}
+
+impl<F: Fn() -> Runtime> Session<F> {
+    fn new(new_runtime: F, prelude: Option<PathBuf>) -> Session<F> {
+        let mut session = Session {
+            runtime: new_runtime(),
+            new_runtime,
+            prelude,
+            timing: false,
+            last_entry: None,
+        };
+        session.run_prelude();
+        session
+    }
+
+    fn reset(&mut self) {
+        self.runtime = (self.new_runtime)();
+        self.run_prelude();
+    }
+
+    // prelude 出错时照常报告，会话还是照样开始
+    fn run_prelude(&mut self) {
+        if let Some(path) = self.prelude.clone() {
+            self.load(&path);
+        }
+    }
 
-    print!("> ");
-    stdout.flush().unwrap();
+    // 执行一条输入。冒号开头的是 REPL 自己的命令，在扫描之前处理。
+    // 命令的输出写到 output，返回 false 表示要退出
+    fn run_entry(&mut self, source: &str, output: &mut impl Write) -> io::Result<bool> {
+        // 错误在 run 里已经报告过了，REPL 接着读下一条
+        self.runtime.interpreter.clear_interrupt();
+        let Some(command) = source.trim().strip_prefix(':') else {
+            let mut timer = PhaseTimer::new(self.runtime.time);
+            let _ = run_timed(source, &mut self.runtime, true, &mut timer);
+            if self.timing {
+                writeln!(output, "{}", timer.summary())?;
+            }
+            if !source.trim().is_empty() {
+                self.last_entry = Some(source.to_string());
+            }
+            return Ok(true);
+        };
 
-    let mut source = String::from("");
-    for line in stdin.lock().lines() {
-        source.push_str(&line?);
+        let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+        match (name, argument.trim()) {
+            ("load", path) if !path.is_empty() => self.load(Path::new(path)),
+            ("reset", "") => self.reset(),
+            ("env", "") => {
+                for (name, value) in self.runtime.globals().bindings() {
+                    writeln!(output, "{name} = {value}")?;
+                }
+            }
+            ("time", "") => {
+                self.timing = !self.timing;
+                let state = if self.timing { "on" } else { "off" };
+                writeln!(output, "Timing {state}.")?;
+            }
+            ("ast", "") => match &self.last_entry {
+                Some(source) => show_ast(source, output)?,
+                None => writeln!(output, "No previous entry.")?,
+            },
+            ("ast", source) => show_ast(source, output)?,
+            ("quit", "") => return Ok(false),
+            _ => {
+                writeln!(output, "Unknown command ':{command}'.")?;
+                writeln!(output, "{REPL_HELP}")?;
+            }
+        }
+        Ok(true)
+    }
 
-        if run(&source, &mut interpreter).is_err() {}
+    // 像运行脚本一样运行文件，定义的全局变量留在这次会话里
+    fn load(&mut self, path: &Path) {
+        match fs::read_to_string(path) {
+            Ok(source) => {
+                let _ = run(&source, &mut self.runtime, false);
+            }
+            Err(e) => eprintln!("Could not read '{}': {e}.", path.display()),
+        }
+    }
+}
 
-        source.clear();
-        print!("> ");
-        stdout.flush().unwrap();
+// :ast 只解析不执行，树的写法和 --dump-ast 一样。不带分号的表达式和 REPL 里一样按表达式解析，
+// 语法错误照常报告，出错的地方在树里是 error 节点
+fn show_ast(source: &str, output: &mut impl Write) -> io::Result<()> {
+    let tokens = scan(source);
+    if let Ok(expr) = parser::Parser::new(tokens.clone()).parse_expression() {
+        return writeln!(output, "{}", AstPrinter::default().print_expr(&expr));
+    }
+
+    let (statements, _) = parse(tokens);
+    write!(output, "{}", AstPrinter::default().print(&statements))
+}
+
+// 用户的数据或配置目录：先看 xdg 指定的环境变量，再看 Windows 上的 %APPDATA%，
+// 都没有时是 home 下的 fallback
+fn user_dir(xdg: &str, fallback: &str) -> Option<PathBuf> {
+    let var = |name| env::var_os(name).filter(|value| !value.is_empty());
+    var(xdg)
+        .or_else(|| var("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| var("HOME").map(|home| Path::new(&home).join(fallback)))
+}
+
+// 历史记录放在用户的数据目录下，通常是 ~/.local/share
+fn history_path() -> Option<PathBuf> {
+    Some(user_dir("XDG_DATA_HOME", ".local/share")?.join(".rjlox_history"))
+}
+
+// 没有用 --prelude 时，REPL 启动前运行配置目录里的 rjlox/prelude.lox（如果有的话）
+fn default_prelude() -> Option<PathBuf> {
+    let path = user_dir("XDG_CONFIG_HOME", ".config")?.join("rjlox/prelude.lox");
+    path.is_file().then_some(path)
+}
+
+type LineEditor = Editor<LoxHelper, DefaultHistory>;
+
+// REPL 读到的一行
+enum Line {
+    Text(String),
+    Interrupted, // Ctrl-C：放弃正在输入的这一条
+    Eof,         // Ctrl-D：退出
+}
+
+fn read_line(editor: &mut LineEditor, prompt: &str) -> io::Result<Line> {
+    match editor.readline(prompt) {
+        Ok(line) => Ok(Line::Text(line)),
+        Err(ReadlineError::Interrupted) => Ok(Line::Interrupted),
+        Err(ReadlineError::Eof) => Ok(Line::Eof),
+        Err(ReadlineError::Io(e)) => Err(e),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+// 读入一条完整的输入：块、字符串或者表达式没写完时，用 `..` 提示接着读下一行，
+// 这时输入空行或者 Ctrl-C 就放弃这一条。输入结束时返回 None
+fn read_entry(mut read_line: impl FnMut(&str) -> io::Result<Line>) -> io::Result<Option<String>> {
+    let mut source = String::new();
+    let mut prompt = "> ";
+    loop {
+        let line = match read_line(prompt)? {
+            Line::Text(line) => line,
+            Line::Interrupted => return Ok(Some(String::new())),
+            // 在中途结束时把已有的部分照常运行，让用户看到报错
+            Line::Eof => return Ok((!source.is_empty()).then_some(source)),
+        };
+
+        if !source.is_empty() {
+            if line.trim().is_empty() {
+                return Ok(Some(String::new()));
+            }
+            source.push('\n');
+        }
+        source.push_str(&line);
+        // REPL 的命令总是只占一行
+        if source.trim_start().starts_with(':') || !incomplete(&source) {
+            return Ok(Some(source));
+        }
+        prompt = ".. ";
+    }
+}
+
+// 输入是不是停在了某个结构的中途。这里只检查不报告，真正的错误留给 run 去报告
+fn incomplete(source: &str) -> bool {
+    let tokens = scan(source);
+    // 没闭合的字符串一直延续到输入末尾，后面几行的 token 其实都在字符串里，不用再看
+    if tokens.iter().any(Token::is_unterminated_string) {
+        return true;
+    }
+    if parser::Parser::new(tokens.clone())
+        .parse_expression()
+        .is_ok()
+    {
+        return false;
+    }
+
+    let (_, errors) = parser::Parser::new(tokens).parse();
+    !errors.is_empty() && errors.iter().all(Error::is_incomplete)
+}
+
+// 只扫描，每行打印一个 token，不解析也不运行
+fn dump_tokens(source: &str) -> io::Result<()> {
+    let tokens = scan(source);
+
+    let mut stdout = io::stdout().lock();
+    for token in &tokens {
+        writeln!(stdout, "{}", token.dump())?;
     }
 
     Ok(())
 }
 
-fn run(source: &str, interpreter: &mut Interpreter) -> Result<()> {
-    let mut scanner = scanner::Scanner::new(source.to_string());
-    let tokens = scanner.scan_tokens();
-    let mut parser = parser::Parser::new(tokens);
-    let statements = match parser.parse() {
-        Ok(result) => result,
-        _ => return Err(Error::ParseError(String::from("parse error"))),
+// 打印语法树，有语法错误时出错的地方显示为 error 节点，打印完以 65 退出
+fn dump_ast(source: &str, spans: bool) -> io::Result<()> {
+    let (statements, failed) = parse_for_dump(source);
+
+    let mut stdout = io::stdout().lock();
+    let mut printer = if spans {
+        AstPrinter::with_spans()
+    } else {
+        AstPrinter::default()
     };
+    write!(stdout, "{}", printer.print(&statements))?;
+    if failed {
+        stdout.flush()?;
+        exit(65);
+    }
+
+    Ok(())
+}
+
+// 和 --dump-ast 一样，有语法错误时照样输出，出错的地方是 Error 节点
+#[cfg(feature = "serde")]
+fn dump_ast_json(source: &str) -> io::Result<()> {
+    let (statements, failed) = parse_for_dump(source);
+
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, &statements)?;
+    writeln!(stdout)?;
+    if failed {
+        stdout.flush()?;
+        exit(65);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn dump_ast_json(_source: &str) -> io::Result<()> {
+    eprintln!("--dump-ast-json needs rjlox to be built with the `serde` feature.");
+    exit(64);
+}
+
+// 查看语法树时用：词法错误报告之后直接退出，语法错误报告之后还能得到树，是否出过错一起返回
+fn parse_for_dump(source: &str) -> (Vec<Stmt>, bool) {
+    let (statements, errors) = parse(scan(source));
+    (statements, !errors.is_empty())
+}
+
+// 扫描源码。扫描不会失败，非法字符和没闭合的字符串留给解析器报告
+fn scan(source: &str) -> Vec<Token> {
+    scanner::Scanner::new(source.to_string()).scan_tokens()
+}
+
+// 解析 token，语法错误在这里报告。解析失败的语句以 Stmt::Error 的形式留在结果里
+fn parse(tokens: Vec<Token>) -> (Vec<Stmt>, Vec<Error>) {
+    let mut parser = parser::Parser::new(tokens);
+    let (statements, errors) = parser.parse();
+    for error in &errors {
+        error::static_error(error);
+    }
+    (statements, errors)
+}
 
-    let mut resolver = Resolver::new(interpreter);
-    match resolver.resolve_statements(&statements) {
-        Err(e) => print!("{:?}", e),
-        _ => (),
+fn run(source: &str, runtime: &mut Runtime, interactive: bool) -> Result<()> {
+    let mut timer = PhaseTimer::new(runtime.time);
+    run_timed(source, runtime, interactive, &mut timer)
+}
+
+// 各阶段的耗时记在 timer 里，REPL 的 :time 要在执行完之后读出来
+fn run_timed(
+    source: &str,
+    runtime: &mut Runtime,
+    interactive: bool,
+    timer: &mut PhaseTimer,
+) -> Result<()> {
+    let tokens = timer.measure("scan", || scan(source));
+
+    // REPL 中单独输入的表达式不需要分号，直接求值并打印结果
+    if interactive {
+        let expr = timer.measure("parse", || {
+            parser::Parser::new(tokens.clone()).parse_expression()
+        });
+        if let Ok(expr) = expr {
+            match timer.measure("interpret", || runtime.interpret_expression(&expr)) {
+                Ok(value) => println!("{value}"),
+                Err(e) => runtime.report_error(&e),
+            }
+            return Ok(());
+        }
+    }
+
+    let (statements, errors) = timer.measure("parse", || parse(tokens));
+    if let Some(error) = errors.into_iter().next() {
+        return Err(error);
+    }
+
+    // 和语法错误一样，解析变量时发现的错误也会阻止程序运行，警告则不会
+    let mut diagnostics = timer.measure("resolve", || {
+        let mut resolver = Resolver::new(&mut runtime.interpreter, !interactive);
+        resolver
+            .resolve(&statements)
+            .map(|_| resolver.diagnostics().to_vec())
+    })?;
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    for diagnostic in &diagnostics {
+        error::diagnostic(diagnostic);
+    }
+    if let Some(error) = diagnostics.iter().find(|d| d.severity == Severity::Error) {
+        return Err(Error::ResolveError(error.clone()));
+    }
+
+    // 报告之后把错误交给调用者：运行文件时以 70 退出，REPL 则继续读下一行
+    // 脚本最后一个表达式的值只对嵌入解释器的代码有用，这里不打印
+    if let Err(e) = timer.measure("interpret", || runtime.interpret(statements)) {
+        runtime.report_error(&e);
+        return Err(e);
     }
 
-    interpreter.interpret(statements)
+    Ok(())
+}
+
+// --time 用的计时器：记录每个阶段的耗时，enabled 时离开作用域时一起打印到 stderr。
+// 中途出错返回时只打印已经走过的阶段
+struct PhaseTimer {
+    enabled: bool,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl PhaseTimer {
+    fn new(enabled: bool) -> PhaseTimer {
+        PhaseTimer {
+            enabled,
+            phases: Vec::new(),
+        }
+    }
+
+    // 同一个阶段走了两次时（REPL 先试着当作表达式解析）耗时加在一起
+    fn measure<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, duration)) => *duration += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+        result
+    }
+
+    // 格式是 `scan=0.120ms parse=0.340ms ... total=1.020ms`，方便脚本解析
+    fn summary(&self) -> String {
+        let total: Duration = self.phases.iter().map(|(_, duration)| *duration).sum();
+        let fields: Vec<String> = self
+            .phases
+            .iter()
+            .copied()
+            .chain([("total", total)])
+            .map(|(phase, duration)| format!("{phase}={:.3}ms", duration.as_secs_f64() * 1000.0))
+            .collect();
+        fields.join(" ")
+    }
+}
+
+impl Drop for PhaseTimer {
+    fn drop(&mut self) {
+        if self.enabled && !self.phases.is_empty() {
+            eprintln!("{}", self.summary());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 像 REPL 一样一条一条地读完所有输入，同时返回打印出来的提示符。
+    // 输入里的 `^C` 当作在这一行按了 Ctrl-C
+    fn entries(input: &str) -> (Vec<String>, String) {
+        let mut lines = input.lines();
+        let mut prompts = String::new();
+        let mut read_line = |prompt: &str| {
+            prompts.push_str(prompt);
+            Ok(match lines.next() {
+                Some("^C") => Line::Interrupted,
+                Some(line) => Line::Text(line.to_string()),
+                None => Line::Eof,
+            })
+        };
+        let mut entries = Vec::new();
+        while let Some(entry) = read_entry(&mut read_line).unwrap() {
+            entries.push(entry);
+        }
+        (entries, prompts)
+    }
+
+    #[test]
+    fn unfinished_function_continues_on_next_line() {
+        let (entries, prompts) = entries("fun add(a, b) {\n  return a + b;\n}\nadd(1, 2)\n");
+        assert_eq!(
+            entries,
+            ["fun add(a, b) {\n  return a + b;\n}", "add(1, 2)"]
+        );
+        assert_eq!(prompts, "> .. .. > > ");
+
+        let mut runtime = Runtime::new(Backend::Tree, DEFAULT_MAX_CALL_DEPTH);
+        run(&entries[0], &mut runtime, true).unwrap();
+        let tokens = scan(&entries[1]);
+        let expr = parser::Parser::new(tokens).parse_expression().unwrap();
+        let value = runtime.interpret_expression(&expr).unwrap();
+        assert_eq!(value.to_string(), "3");
+    }
+
+    #[test]
+    fn unterminated_string_and_trailing_operator_continue() {
+        let (entries, _) = entries("print \"a\nb\";\n1 +\n2\n");
+        assert_eq!(entries, ["print \"a\nb\";", "1 +\n2"]);
+    }
+
+    #[test]
+    fn blank_line_cancels_unfinished_input() {
+        let (entries, prompts) = entries("if (true) {\n\nprint 1;\n");
+        assert_eq!(entries, ["", "print 1;"]);
+        assert_eq!(prompts, "> .. > > ");
+    }
+
+    #[test]
+    fn interrupt_cancels_unfinished_input() {
+        let (entries, prompts) = entries("fun f() {\n^C\n^C\nprint 1;\n");
+        assert_eq!(entries, ["", "", "print 1;"]);
+        assert_eq!(prompts, "> .. > > > ");
+    }
+
+    #[test]
+    fn commands_take_one_line() {
+        let (entries, _) = entries(":load \"a.lox\nprint 1;\n");
+        assert_eq!(entries, [":load \"a.lox", "print 1;"]);
+    }
+
+    // 按顺序执行每条输入，返回 REPL 命令的输出
+    fn session(entries: &[&str]) -> String {
+        session_with_prelude(entries, None)
+    }
+
+    fn session_with_prelude(entries: &[&str], prelude: Option<PathBuf>) -> String {
+        let new_runtime = || Runtime::new(Backend::Tree, DEFAULT_MAX_CALL_DEPTH);
+        let mut session = Session::new(new_runtime, prelude);
+        let mut output = Vec::new();
+        for entry in entries {
+            if !session.run_entry(entry, &mut output).unwrap() {
+                output.extend_from_slice(b"(quit)\n");
+                break;
+            }
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn env_lists_globals() {
+        let output = session(&["var b = \"two\";", "var a = 1;", "fun f() {}", ":env"]);
+        assert_eq!(output, "a = 1\nb = two\nf = <fn f>\n");
+    }
+
+    #[test]
+    fn load_runs_a_file_into_the_session() {
+        let path = env::temp_dir().join("rjlox_repl_load.lox");
+        fs::write(
+            &path,
+            "var loaded = 1 + 2;\nfun twice(x) { return x * 2; }\n",
+        )
+        .unwrap();
+        let load = format!(":load {}", path.display());
+        let output = session(&[&load, "var doubled = twice(loaded);", ":env"]);
+        assert_eq!(output, "doubled = 6\nloaded = 3\ntwice = <fn twice>\n");
+    }
+
+    #[test]
+    fn reset_discards_globals_but_keeps_natives() {
+        let output = session(&["var a = 1;", ":reset", "var t = clock() > 0;", ":env"]);
+        assert_eq!(output, "t = true\n");
+    }
+
+    #[test]
+    fn prelude_runs_at_start_and_after_reset() {
+        let path = env::temp_dir().join("rjlox_repl_prelude.lox");
+        fs::write(&path, "fun double(x) { return x * 2; }\n").unwrap();
+        let entries = [
+            "var a = double(21);",
+            ":reset",
+            "var b = double(4);",
+            ":env",
+        ];
+        let output = session_with_prelude(&entries, Some(path));
+        assert_eq!(output, "b = 8\ndouble = <fn double>\n");
+    }
+
+    #[test]
+    fn time_prints_phases_only_while_on() {
+        let output = session(&["1 + 2", ":time", "var a = 1;", "a", ":time", "a"]);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4, "{output}");
+        assert_eq!(lines[0], "Timing on.");
+        assert_eq!(lines[3], "Timing off.");
+
+        let phases = |line: &str| -> Vec<String> {
+            let fields = line.split(' ').map(|field| field.split_once('=').unwrap());
+            fields.map(|(phase, _)| phase.to_string()).collect()
+        };
+        let statement = ["scan", "parse", "resolve", "interpret", "total"];
+        assert_eq!(phases(lines[1]), statement);
+        assert_eq!(phases(lines[2]), ["scan", "parse", "interpret", "total"]);
+        assert!(lines[1].ends_with("ms"));
+    }
+
+    #[test]
+    fn ast_prints_the_tree_without_running() {
+        let entries = [
+            ":ast",
+            "1 + 2 * 3 == 7",
+            ":ast",
+            ":ast var x = -a or b; print x;",
+            ":ast",
+            ":ast if (x) {",
+            ":env",
+        ];
+        let expected = [
+            "No previous entry.",
+            "(== (+ 1 (* 2 3)) 7)",
+            "(var x (or (- a) b))",
+            "(print x)",
+            "(== (+ 1 (* 2 3)) 7)",
+            "(error at end)",
+        ];
+        assert_eq!(session(&entries), expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn quit_and_unknown_commands() {
+        let output = session(&[":help", ":quit", ":env"]);
+        let help = format!("Unknown command ':help'.\n{REPL_HELP}\n");
+        assert_eq!(output, help + "(quit)\n");
+    }
+
+    #[test]
+    fn errors_before_the_end_do_not_continue() {
+        let (entries, _) = entries("print 1\nvar = {\n");
+        assert_eq!(entries, ["print 1", "var = {"]);
+    }
 }
@@ -6,6 +6,57 @@ use std::fmt;
 pub enum Object {
     Literal(Literal),
     Callable(Callable),
+    Uninitialized, // `var a;` 声明之后、第一次赋值之前的值，读取它是运行时错误
+}
+
+// 错误信息里的值预览最多显示这么多个字符
+const PREVIEW_LENGTH: usize = 20;
+
+impl Object {
+    // nil 和 false 是假，其他的值都是真
+    pub fn is_truthy(&self) -> bool {
+        !matches!(
+            self,
+            Object::Literal(Literal::Nil) | Object::Literal(Literal::Bool(false))
+        )
+    }
+
+    // 运行时错误信息里用的类型名
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Object::Literal(Literal::Nil) => "nil",
+            Object::Literal(Literal::Bool(_)) => "boolean",
+            Object::Literal(Literal::Num(_)) => "number",
+            Object::Literal(Literal::Str(_)) => "string",
+            Object::Callable(_) => "function",
+            Object::Uninitialized => "uninitialized",
+        }
+    }
+
+    // 类型名加上值的简短预览，比如 `number (3)`、`string ("abc")`；nil 只有一个值，不用预览
+    pub fn describe(&self) -> String {
+        match self {
+            Object::Literal(Literal::Nil) | Object::Uninitialized => self.type_name().to_string(),
+            Object::Literal(Literal::Str(string)) => {
+                let mut preview: String = string.chars().take(PREVIEW_LENGTH).collect();
+                if string.chars().count() > PREVIEW_LENGTH {
+                    preview.push_str("...");
+                }
+                format!("string (\"{preview}\")")
+            }
+            _ => format!("{} ({})", self.type_name(), self),
+        }
+    }
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Object) -> bool {
+        match (self, other) {
+            (Object::Literal(a), Object::Literal(b)) => a == b,
+            (Object::Callable(a), Object::Callable(b)) => a == b,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Object {
@@ -13,6 +64,7 @@ impl fmt::Display for Object {
         match self {
             Object::Literal(l) => write!(f, "{l}"),
             Object::Callable(c) => write!(f, "{c}"),
+            Object::Uninitialized => write!(f, "<uninitialized>"),
         }
     }
 }
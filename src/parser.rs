@@ -1,34 +1,72 @@
-use crate::error;
 use crate::error::Error;
 use crate::expr::Expr;
 use crate::stmt::Stmt;
-use crate::token::{Literal, Token, TokenType};
+use crate::token::{Literal, Span, Token, TokenType};
 
 type ParseResult<T> = Result<T, Error>;
 
+// 表达式默认的最大嵌套层数，避免 `((((...` 这样的输入让递归下降把 Rust 的栈撑爆
+pub const DEFAULT_MAX_EXPRESSION_DEPTH: usize = 256;
+
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    depth: usize,       // 当前表达式的嵌套层数
+    peak: usize,        // 正在解析的操作数最深到了第几层，见 operand
+    max_depth: usize,   // 表达式最多能嵌套几层
+    blocks: usize,      // 当前所在的块的层数，synchronize 据此决定能不能停在 `}` 上
+    errors: Vec<Error>, // 不影响继续解析的错误（比如参数太多），报告后照常往下走
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Parser {
-        Parser { tokens, current: 0 }
+        Parser {
+            tokens,
+            current: 0,
+            depth: 0,
+            peak: 0,
+            max_depth: DEFAULT_MAX_EXPRESSION_DEPTH,
+            blocks: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn set_max_expression_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
     }
 
     // program → declaration* EOF ;
-    pub fn parse(&mut self) -> ParseResult<Vec<Stmt>> {
+    // 出错后 declaration 会 synchronize 到下一条语句继续解析，所以一次能收集到所有的语法错误。
+    // 有错误时也总是返回一棵完整的树，出错的地方是 Stmt::Error / Expr::Error 占位节点，
+    // 编辑器之类的工具可以照常遍历；这样的树不能交给解释器执行
+    pub fn parse(&mut self) -> (Vec<Stmt>, Vec<Error>) {
         let mut statements = Vec::new();
 
         while !self.is_at_end() {
-            statements.push(self.declaration()?);
+            statements.push(self.declaration());
         }
 
-        Ok(statements)
+        (statements, std::mem::take(&mut self.errors))
+    }
+
+    // REPL 中不带分号的单个表达式，必须用完所有 token
+    pub fn parse_expression(&mut self) -> ParseResult<Expr> {
+        let expr = self.expression()?;
+
+        if !self.is_at_end() {
+            return Err(Self::error(self.peek(), "Expect end of expression."));
+        }
+
+        if !self.errors.is_empty() {
+            return Err(self.errors.remove(0));
+        }
+
+        Ok(expr)
     }
 
     // declaration → funDecl | varDecl | statement ; // 这样设计是因为不允许在块里声明语句
-    fn declaration(&mut self) -> ParseResult<Stmt> {
+    fn declaration(&mut self) -> Stmt {
+        let start = self.current;
         let result = if self.match_one_token(&TokenType::VAR) {
             self.var_declaration()
         } else if self.match_one_token(&TokenType::FUN) {
@@ -38,73 +76,112 @@ impl Parser {
         };
 
         match result {
-            Ok(r) => Ok(r),
-            Err(e) => {
+            Ok(stmt) => stmt,
+            Err(error) => {
+                let token = Self::error_token(&error);
+                self.report(error);
                 self.synchronize();
-                Err(e)
+                let span = self.skipped_span(start, &token);
+                Stmt::Error { token, span }
             }
         }
     }
 
     // funDecl → "fun" function ;
     // 辅助规则 function → IDENTIFIER "(" parameters? ")" block ;
-    //         parameters → IDENTIFIER ( "," IDENTIFIER )* ;
+    //         parameters → IDENTIFIER ( "," IDENTIFIER )* ","? ;
     fn function(&mut self, fun: &str) -> ParseResult<Stmt> {
+        let start = self.current - 1; // `fun`
         let mut message = format!("Expect {fun} name.");
-        let name = self.consume(TokenType::IDENTIFIER, &message)?;
+        let name = self.consume(TokenType::IDENTIFIER, &message)?.clone();
 
         message = format!("Expect '(' after {fun} name.");
         self.consume(TokenType::LeftParen, &message)?;
-        let mut params = Vec::new();
-        if !self.check(&TokenType::RightParen) {
-            if params.len() >= 255 {
-                Self::error(self.peek(), "Can't have more than 255 arguments.");
-            }
-
-            params.push(self.consume(TokenType::IDENTIFIER, "Expect parameter name.")?);
-
-            while self.match_one_token(&TokenType::COMMA) {
-                if params.len() >= 255 {
-                    Self::error(self.peek(), "Can't have more than 255 arguments.");
-                }
-
-                params.push(self.consume(TokenType::IDENTIFIER, "Expect parameter name.")?);
-            }
-        }
+        let params = self.parameters()?;
         self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
 
         message = format!("Expect '{{' before {fun} body.");
         self.consume(TokenType::LeftBrace, &message)?;
         let body = self.block()?;
 
-        Ok(Stmt::Function { name, params, body })
+        Ok(Stmt::Function {
+            name,
+            params: params.into(),
+            body: body.into(),
+            span: self.span_from(start),
+        })
+    }
+
+    // parameters → IDENTIFIER ( "," IDENTIFIER )* ","? ;
+    fn parameters(&mut self) -> ParseResult<Vec<Token>> {
+        let mut params: Vec<Token> = Vec::new();
+        if self.check(&TokenType::RightParen) {
+            return Ok(params);
+        }
+
+        loop {
+            // 只在第 256 个参数上报一次错，报告之后继续解析
+            if params.len() == 255 {
+                self.report(Self::error(
+                    self.peek(),
+                    "Can't have more than 255 parameters.",
+                ));
+            }
+
+            let param = self
+                .consume(TokenType::IDENTIFIER, "Expect parameter name.")?
+                .clone();
+            // 同名参数会让后一个悄悄覆盖前一个，错误指向重复的那个
+            if params.iter().any(|p| p.lexeme == param.lexeme) {
+                self.report(Self::error(
+                    &param,
+                    "Already a variable with this name in this scope.",
+                ));
+            }
+            params.push(param);
+
+            // 允许在 `)` 前多写一个逗号
+            if !self.match_one_token(&TokenType::COMMA) || self.check(&TokenType::RightParen) {
+                break;
+            }
+        }
+
+        Ok(params)
     }
 
     // varDecl → "var" IDENTIFIER ( "=" expression )? ";" ;
     fn var_declaration(&mut self) -> ParseResult<Stmt> {
-        let name = self.consume(TokenType::IDENTIFIER, "Expect variable name.")?;
+        let start = self.current - 1; // `var`
+        let line = self.previous().line;
+        let name = self
+            .consume(TokenType::IDENTIFIER, "Expect variable name.")?
+            .clone();
 
         let initializer = if self.match_one_token(&TokenType::EQUAL) {
-            self.expression()?
+            Some(self.expression()?)
         } else {
-            Expr::Literal {
-                value: Literal::Nil,
-            }
+            None
         };
 
-        self.consume(
-            TokenType::SEMICOLON,
-            "Expect ';' after variable declaration.",
-        )?;
+        self.consume_semicolon("Expect ';' after variable declaration", line)?;
 
         Ok(Stmt::Var {
             name,
             expression: initializer,
+            span: self.span_from(start),
         })
     }
 
-    // statement → exprStmt | forStmt | ifStmt | printStmt | returnStmt | whileStmt | block ;
+    // statement → exprStmt | forStmt | ifStmt | printStmt | returnStmt | whileStmt | block | ";" ;
     fn statement(&mut self) -> ParseResult<Stmt> {
+        // 单独的分号是空语句，当作一个空块，什么也不做
+        if self.match_one_token(&TokenType::SEMICOLON) {
+            return Ok(Stmt::Block {
+                stmts: Vec::new(),
+                span: Span::from(self.previous()),
+            });
+        }
+
         if self.match_one_token(&TokenType::FOR) {
             return self.for_statement();
         }
@@ -126,8 +203,10 @@ impl Parser {
         }
 
         if self.match_one_token(&TokenType::LeftBrace) {
+            let start = self.current - 1;
             return Ok(Stmt::Block {
                 stmts: self.block()?,
+                span: self.span_from(start),
             });
         }
 
@@ -136,40 +215,58 @@ impl Parser {
 
     // returnStmt → "return" expression? ";" ;
     fn return_statement(&mut self) -> ParseResult<Stmt> {
-        let keyword = self.previous();
+        let start = self.current - 1;
+        let keyword = self.previous().clone();
+        // 没写返回值时是一个隐含的 nil，位置记在 `return` 上
         let mut value = Expr::Literal {
             value: Literal::Nil,
+            span: Span::from(&keyword),
         };
 
         if !self.check(&TokenType::SEMICOLON) {
             value = self.expression()?;
         }
 
-        self.consume(TokenType::SEMICOLON, "Expect ';' after return value.")?;
+        self.consume_semicolon("Expect ';' after return value", keyword.line)?;
 
-        Ok(Stmt::Return { keyword, value })
+        Ok(Stmt::Return {
+            keyword,
+            value,
+            span: self.span_from(start),
+        })
     }
 
     // printStmt → "print" expression ";" ;
     fn print_statement(&mut self) -> ParseResult<Stmt> {
+        let start = self.current - 1;
+        let line = self.previous().line;
         let value = self.expression()?;
-        self.consume(TokenType::SEMICOLON, "Expect ';' after value.")?;
+        self.consume_semicolon("Expect ';' after value", line)?;
 
-        Ok(Stmt::Print { expression: value })
+        Ok(Stmt::Print {
+            expression: value,
+            span: self.span_from(start),
+        })
     }
 
     // exprStmt → expression ";" ;
     fn expression_statement(&mut self) -> ParseResult<Stmt> {
+        let start = self.current;
+        let line = self.peek().line;
         let expr = self.expression()?;
-        self.consume(TokenType::SEMICOLON, "Expect ';' after expression.")?;
+        self.consume_semicolon("Expect ';' after expression", line)?;
 
-        Ok(Stmt::Expression { expression: expr })
+        Ok(Stmt::Expression {
+            expression: expr,
+            span: self.span_from(start),
+        })
     }
 
     // ifStmt → "if" "(" expression ")" statement ( "else" statement )? ;
     fn if_statement(&mut self) -> ParseResult<Stmt> {
+        let start = self.current - 1;
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
-        let condition = self.expression()?;
+        let condition = self.condition()?;
         self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
 
         let then_branch = self.statement()?;
@@ -183,24 +280,68 @@ impl Parser {
             condition,
             then_branch: Box::new(then_branch),
             else_branch,
+            span: self.span_from(start),
         })
     }
 
     // whileStmt → "while" "(" expression ")" statement ;
     fn while_statement(&mut self) -> ParseResult<Stmt> {
+        let start = self.current - 1;
+        let keyword = self.previous().clone();
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
-        let condition = self.expression()?;
+        let condition = self.condition()?;
         self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
         let body = self.statement()?;
 
         Ok(Stmt::While {
+            keyword,
             condition,
             body: Box::new(body),
+            span: self.span_from(start),
         })
     }
 
+    // if/while 的条件出错时跳到对应的 `)`，用 Expr::Error 占位，语句的其余部分照常解析；
+    // 找不到 `)` 就只能让整条语句失败
+    fn condition(&mut self) -> ParseResult<Expr> {
+        let start = self.current;
+        let error = match self.expression() {
+            Ok(expr) => return Ok(expr),
+            Err(error) => error,
+        };
+
+        // 出错前可能已经进了几层括号，要跳过它们对应的 `)`
+        let mut depth = 0;
+        for token in &self.tokens[start..self.current] {
+            match token.token_type {
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen => depth -= 1,
+                _ => (),
+            }
+        }
+        while !self.is_at_end() {
+            match self.peek().token_type {
+                TokenType::RightParen if depth == 0 => {
+                    let token = Self::error_token(&error);
+                    self.report(error);
+                    let span = self.skipped_span(start, &token);
+                    return Ok(Expr::Error { token, span });
+                }
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen => depth -= 1,
+                TokenType::SEMICOLON | TokenType::LeftBrace | TokenType::RightBrace => break,
+                _ => (),
+            }
+            self.advance();
+        }
+
+        Err(error)
+    }
+
     // forStmt → "for" "(" ( varDecl | exprStmt | ";" ) expression? ";" expression? ")" statement ;
     fn for_statement(&mut self) -> ParseResult<Stmt> {
+        let start = self.current - 1;
+        let keyword = self.previous().clone();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
 
         let initializer = if self.match_one_token(&TokenType::SEMICOLON) {
@@ -216,6 +357,7 @@ impl Parser {
         } else {
             Expr::Literal {
                 value: Literal::Bool(true), // 如果没条件，意味着 for 循环的条件判断句永远返回 true -> 死循环
+                span: Span::from(self.peek()), // 隐含的条件记在本该写条件的 `;` 上
             }
         };
         self.consume(TokenType::SEMICOLON, "Expect ';' after loop condition.")?;
@@ -228,27 +370,32 @@ impl Parser {
         self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
 
         let mut body = self.statement()?;
-        if increment.is_some() {
+        // 脱糖产生的块和 while 都覆盖整个 for 语句
+        let span = self.span_from(start);
+        if let Some(increment) = increment {
             body = Stmt::Block {
                 stmts: vec![
                     body,
                     Stmt::Expression {
-                        expression: increment.unwrap(),
-                        // 这里不能直接用 ? 的原因是：函数返回 Result，而这里（如果写 ? 的话）是对 Option 进行操作
-                        // 遇到错误的话 return 的类型不匹配（会返回 None 而不是 Err）
+                        span: increment.span(),
+                        expression: increment,
                     },
                 ],
+                span,
             }
         }
         body = Stmt::While {
             // while 部分
+            keyword,
             condition,
             body: Box::new(body),
+            span,
         };
-        if initializer.is_some() {
+        if let Some(initializer) = initializer {
             // 初始化部分
             body = Stmt::Block {
-                stmts: vec![initializer.unwrap(), body],
+                stmts: vec![initializer, body],
+                span,
             }
         }
 
@@ -258,9 +405,12 @@ impl Parser {
     // block → "{" declaration* "}" ;
     fn block(&mut self) -> ParseResult<Vec<Stmt>> {
         let mut statements = Vec::new();
+        self.blocks += 1;
+        // 块内某条语句出错时 declaration 会记下错误继续往下解析，直到遇到 `}`，一个笔误只报一个错
         while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
-            statements.push(self.declaration()?);
+            statements.push(self.declaration());
         }
+        self.blocks -= 1;
 
         self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
         Ok(statements)
@@ -268,23 +418,80 @@ impl Parser {
 
     // expression → assignment ;
     fn expression(&mut self) -> ParseResult<Expr> {
-        self.assignment()
+        self.nested(Self::assignment)
+    }
+
+    // 所有递归进入子表达式的地方都经过这里计数
+    fn nested(&mut self, parse: fn(&mut Self) -> ParseResult<Expr>) -> ParseResult<Expr> {
+        if self.depth >= self.max_depth {
+            return Err(Self::error(self.peek(), "Expression too deeply nested."));
+        }
+
+        self.depth += 1;
+        self.peak = self.peak.max(self.depth);
+        let result = parse(self);
+        self.depth -= 1;
+
+        result
+    }
+
+    // `a + b + c` 和 `f()()` 是循环构造出来的往左下延伸的链，不经过 nested，
+    // 但链上每多一个运算符，前面的操作数就在树里深一层，后面的遍历照样要递归这么多层。
+    // 所以解析每个操作数时记下它比当前层数深了多少，链每长一节就用 link 把整条链的高度计入层数
+    fn operand<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> ParseResult<T>,
+    ) -> ParseResult<(T, usize)> {
+        let outer = std::mem::replace(&mut self.peak, self.depth);
+        let result = parse(self);
+        let height = self.peak - self.depth;
+        self.peak = self.peak.max(outer);
+
+        Ok((result?, height))
+    }
+
+    // 链上已有的部分高 height，接上高 right 的一节之后整条链的高度
+    fn link(&mut self, operator: &Token, height: usize, right: usize) -> ParseResult<usize> {
+        let height = height.max(right) + 1;
+        if self.depth + height > self.max_depth {
+            return Err(Self::error(operator, "Expression too deeply nested."));
+        }
+
+        self.peak = self.peak.max(self.depth + height);
+        Ok(height)
     }
 
     // assignment → IDENTIFIER "=" assignment | logic_or ; // 赋值是表达式而不是语句
     fn assignment(&mut self) -> ParseResult<Expr> {
+        let start = self.current;
         let expr = self.or()?;
 
         if self.match_one_token(&TokenType::EQUAL) {
-            let equals = self.previous();
-            let value = self.assignment()?;
+            let equals = self.previous().clone();
+            let value = self.nested(Self::assignment)?;
 
             return match expr {
-                Expr::Variable { name } => Ok(Expr::Assign {
+                Expr::Variable { id, name, .. } => Ok(Expr::Assign {
+                    id,
                     name,
                     value: Box::new(value),
+                    span: self.span_from(start),
                 }),
-                _ => Err(Self::error(equals, "Invalid assignment target.")),
+                // 和 jlox 一样不接受 `(a) = 1`，但要说清楚是哪种表达式不能赋值
+                _ => {
+                    let target = match expr {
+                        Expr::Grouping { .. } => "a parenthesized expression",
+                        Expr::Binary { .. } => "a binary expression",
+                        Expr::Logic { .. } => "a logical expression",
+                        Expr::Unary { .. } => "a unary expression",
+                        Expr::Literal { .. } => "a literal",
+                        Expr::Call { .. } => "a function call",
+                        Expr::Assign { .. } => "an assignment",
+                        Expr::Variable { .. } | Expr::Error { .. } => unreachable!(),
+                    };
+                    let message = format!("Cannot assign to {target}.");
+                    Err(Self::error(&equals, &message))
+                }
             };
         }
 
@@ -293,15 +500,18 @@ impl Parser {
 
     // logic_or → logic_and ( "or" logic_and )* ;
     fn or(&mut self) -> ParseResult<Expr> {
-        let mut expr = self.and()?;
+        let start = self.current;
+        let (mut expr, mut height) = self.operand(Self::and)?;
 
         while self.match_one_token(&TokenType::OR) {
-            let operator = self.previous();
-            let right = self.and()?;
+            let operator = self.previous().clone();
+            let (right, right_height) = self.operand(Self::and)?;
+            height = self.link(&operator, height, right_height)?;
             expr = Expr::Logic {
                 left: Box::new(expr),
                 operator,
                 right: Box::new(right),
+                span: self.span_from(start),
             }
         }
 
@@ -310,14 +520,17 @@ impl Parser {
 
     // logic_and → equality ( "and" equality )* ;
     fn and(&mut self) -> ParseResult<Expr> {
-        let mut expr = self.equality()?;
+        let start = self.current;
+        let (mut expr, mut height) = self.operand(Self::equality)?;
         while self.match_one_token(&TokenType::AND) {
-            let operator = self.previous();
-            let right = self.equality()?;
+            let operator = self.previous().clone();
+            let (right, right_height) = self.operand(Self::equality)?;
+            height = self.link(&operator, height, right_height)?;
             expr = Expr::Logic {
                 left: Box::new(expr),
                 operator,
                 right: Box::new(right),
+                span: self.span_from(start),
             };
         }
 
@@ -326,20 +539,22 @@ impl Parser {
 
     // equality → comparison ( ( "!=" | "==" ) comparison )* ;
     fn equality(&mut self) -> ParseResult<Expr> {
-        let mut left = self.comparison()?;
+        let start = self.current;
+        let (mut left, mut height) = self.operand(Self::comparison)?;
 
-        // Rust 中没有可变参数列表，不得已使用 vec，可能用切片是更好的选择
-        // todo 其实是有的，尴尬
-        let types = vec![TokenType::BangEqual, TokenType::EqualEqual];
+        // Rust 中没有可变参数列表，用切片代替
+        let types = [TokenType::BangEqual, TokenType::EqualEqual];
 
         while self.match_token(&types) {
-            let operator = self.previous();
-            let right = self.comparison()?;
+            let operator = self.previous().clone();
+            let (right, right_height) = self.operand(Self::comparison)?;
+            height = self.link(&operator, height, right_height)?;
 
             left = Expr::Binary {
                 left: Box::new(left),
                 operator,
                 right: Box::new(right),
+                span: self.span_from(start),
             };
         }
 
@@ -348,9 +563,10 @@ impl Parser {
 
     // comparison → term ( ( ">" | ">=" | "<" | "<=" ) term )* ;
     fn comparison(&mut self) -> ParseResult<Expr> {
-        let mut left = self.term()?;
+        let start = self.current;
+        let (mut left, mut height) = self.operand(Self::term)?;
 
-        let types = vec![
+        let types = [
             TokenType::GREATER,
             TokenType::GreaterEqual,
             TokenType::LESS,
@@ -358,13 +574,15 @@ impl Parser {
         ];
 
         while self.match_token(&types) {
-            let operator = self.previous();
-            let right = self.term()?;
+            let operator = self.previous().clone();
+            let (right, right_height) = self.operand(Self::term)?;
+            height = self.link(&operator, height, right_height)?;
 
             left = Expr::Binary {
                 left: Box::new(left),
                 operator,
                 right: Box::new(right),
+                span: self.span_from(start),
             }
         }
 
@@ -373,17 +591,20 @@ impl Parser {
 
     // term → factor ( ( "-" | "+" ) factor )* ;
     fn term(&mut self) -> ParseResult<Expr> {
-        let mut expr = self.factor()?;
+        let start = self.current;
+        let (mut expr, mut height) = self.operand(Self::factor)?;
 
-        let types = vec![TokenType::MINUS, TokenType::PLUS];
+        let types = [TokenType::MINUS, TokenType::PLUS];
 
         while self.match_token(&types) {
-            let operator = self.previous();
-            let right = self.factor()?;
+            let operator = self.previous().clone();
+            let (right, right_height) = self.operand(Self::factor)?;
+            height = self.link(&operator, height, right_height)?;
             expr = Expr::Binary {
                 left: Box::new(expr),
                 operator,
                 right: Box::new(right),
+                span: self.span_from(start),
             };
         }
 
@@ -392,16 +613,19 @@ impl Parser {
 
     // factor → unary ( ( "/" | "*" ) unary )* ;
     fn factor(&mut self) -> ParseResult<Expr> {
-        let mut expr = self.unary()?;
-        let types = vec![TokenType::SLASH, TokenType::STAR];
+        let start = self.current;
+        let (mut expr, mut height) = self.operand(Self::unary)?;
+        let types = [TokenType::SLASH, TokenType::STAR];
 
         while self.match_token(&types) {
-            let operator = self.previous();
-            let right = self.unary()?;
+            let operator = self.previous().clone();
+            let (right, right_height) = self.operand(Self::unary)?;
+            height = self.link(&operator, height, right_height)?;
             expr = Expr::Binary {
                 left: Box::new(expr),
                 operator,
                 right: Box::new(right),
+                span: self.span_from(start),
             };
         }
 
@@ -410,15 +634,17 @@ impl Parser {
 
     // unary → ( "!" | "-" ) unary | call ; // 把函数调用看成一种运算符
     fn unary(&mut self) -> ParseResult<Expr> {
-        let types = vec![TokenType::BANG, TokenType::MINUS];
+        let types = [TokenType::BANG, TokenType::MINUS];
 
         if self.match_token(&types) {
-            let operator = self.previous();
-            let right = self.unary()?;
+            let start = self.current - 1;
+            let operator = self.previous().clone();
+            let right = self.nested(Self::unary)?;
 
             return Ok(Expr::Unary {
                 operator,
                 right: Box::new(right),
+                span: self.span_from(start),
             });
         }
 
@@ -427,35 +653,43 @@ impl Parser {
 
     // call → primary ( "(" arguments? ")" )* ;
     fn call(&mut self) -> ParseResult<Expr> {
-        let mut expr = self.primary()?;
-        loop {
-            if self.match_one_token(&TokenType::LeftParen) {
-                expr = self.finish_call(expr.clone())?;
-            } else {
-                break;
-            }
+        let (mut expr, mut height) = self.operand(Self::primary)?;
+        while self.match_one_token(&TokenType::LeftParen) {
+            let paren = self.previous().clone();
+            let (call, arguments_height) = self.operand(|parser| parser.finish_call(expr))?;
+            height = self.link(&paren, height, arguments_height)?;
+            expr = call;
         }
 
         Ok(expr)
     }
 
-    // arguments → expression ( "," expression )* ;
+    // arguments → expression ( "," expression )* ","? ;
     fn finish_call(&mut self, callee: Expr) -> ParseResult<Expr> {
         let mut arguments = Vec::new();
         if !self.check(&TokenType::RightParen) {
-            arguments.push(self.expression()?);
-            while self.match_one_token(&TokenType::COMMA) {
-                if arguments.len() >= 255 {
-                    Self::error(self.peek(), "Can't have more than 255 arguments.");
+            loop {
+                if arguments.len() == 255 {
+                    self.report(Self::error(
+                        self.peek(),
+                        "Can't have more than 255 arguments.",
+                    ));
                 }
 
                 arguments.push(self.expression()?);
+
+                if !self.match_one_token(&TokenType::COMMA) || self.check(&TokenType::RightParen) {
+                    break;
+                }
             }
         }
 
-        let paren = self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
+        let paren = self
+            .consume(TokenType::RightParen, "Expect ')' after arguments.")?
+            .clone();
 
         Ok(Expr::Call {
+            span: callee.span().to(Span::from(&paren)),
             callee: Box::new(callee),
             paren,
             arguments,
@@ -467,50 +701,61 @@ impl Parser {
         if self.match_one_token(&TokenType::FALSE) {
             return Ok(Expr::Literal {
                 value: Literal::Bool(false),
+                span: Span::from(self.previous()),
             });
         }
 
         if self.match_one_token(&TokenType::TRUE) {
             return Ok(Expr::Literal {
                 value: Literal::Bool(true),
+                span: Span::from(self.previous()),
             });
         }
 
         if self.match_one_token(&TokenType::NIL) {
             return Ok(Expr::Literal {
                 value: Literal::Nil,
+                span: Span::from(self.previous()),
             });
         }
 
         if self.match_one_token(&TokenType::STRING) {
-            let value = self.previous().literal;
-            return Ok(Expr::Literal { value });
+            let value = self.previous().literal.clone();
+            let span = Span::from(self.previous());
+            return Ok(Expr::Literal { value, span });
         }
 
         if self.match_one_token(&TokenType::NUMBER) {
-            let value = self.previous().literal;
-            return Ok(Expr::Literal { value });
+            let value = self.previous().literal.clone();
+            let span = Span::from(self.previous());
+            return Ok(Expr::Literal { value, span });
         }
 
         if self.match_one_token(&TokenType::IDENTIFIER) {
-            let value = self.previous();
-            return Ok(Expr::Variable { name: value });
+            let value = self.previous().clone();
+            return Ok(Expr::Variable {
+                id: Expr::next_id(),
+                span: Span::from(&value),
+                name: value,
+            });
         }
 
         if self.match_one_token(&TokenType::LeftParen) {
+            let start = self.current - 1;
             let expr = self.expression()?;
 
             self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
 
             return Ok(Expr::Grouping {
                 expression: Box::new(expr),
+                span: self.span_from(start),
             });
         }
 
         Err(Self::error(self.peek(), "Expect expression."))
     }
 
-    fn match_token(&mut self, types: &Vec<TokenType>) -> bool {
+    fn match_token(&mut self, types: &[TokenType]) -> bool {
         for token_type in types {
             if self.match_one_token(token_type) {
                 return true;
@@ -529,7 +774,7 @@ impl Parser {
         }
     }
 
-    fn consume(&mut self, token_type: TokenType, message: &str) -> ParseResult<Token> {
+    fn consume(&mut self, token_type: TokenType, message: &str) -> ParseResult<&Token> {
         if self.check(&token_type) {
             return Ok(self.advance());
         }
@@ -537,12 +782,75 @@ impl Parser {
         Err(Self::error(self.peek(), message))
     }
 
-    fn error(token: Token, message: &str) -> Error {
-        error::parser_error(token, message);
-        Error::ParseError(String::from(message))
+    // 语句末尾缺分号时，下一个 token 往往已经在下一行了，所以错误指向语句的最后一个 token，
+    // 并带上语句开始的行号
+    fn consume_semicolon(&mut self, message: &str, line: usize) -> ParseResult<()> {
+        if self.match_one_token(&TokenType::SEMICOLON) {
+            return Ok(());
+        }
+
+        // 非法字符还是报在它自己身上
+        if self.check(&TokenType::ERROR) {
+            return Err(Self::error(self.peek(), message));
+        }
+
+        let message = format!("{message} (statement starting on line {line}).");
+        Err(Self::error(self.previous(), &message))
+    }
+
+    fn error(token: &Token, message: &str) -> Error {
+        // 扫描器把非法字符和没闭合的字符串留给解析器，不管此处期望的是什么，都直接指出它们
+        let message = if token.is_unterminated_string() {
+            String::from("Unterminated string.")
+        } else if token.token_type == TokenType::ERROR {
+            format!("Unexpected character '{}'.", token.lexeme)
+        } else {
+            String::from(message)
+        };
+
+        // 在末尾出错说明输入还没写完；没闭合的字符串一直延续到了输入末尾，也是没写完
+        let error = Error::ParseError(token.clone(), message);
+        if token.token_type == TokenType::EOF || token.is_unterminated_string() {
+            return Error::Incomplete(Box::new(error));
+        }
+        error
+    }
+
+    // 错误节点记录的出错位置
+    fn error_token(error: &Error) -> Token {
+        match error {
+            Error::ParseError(token, _) => token.clone(),
+            Error::Incomplete(error) => Self::error_token(error),
+            _ => unreachable!(),
+        }
+    }
+
+    // 从第 start 个 token 到刚消费的 token
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(&self.tokens[start], self.previous())
+    }
+
+    // 出错后被跳过的 token；一个也没跳过时就是出错的 token
+    fn skipped_span(&self, start: usize, token: &Token) -> Span {
+        if self.current > start {
+            self.span_from(start)
+        } else {
+            Span::from(token)
+        }
+    }
+
+    // 记下一个错误但不中断解析，parse() 结束时和其它错误一起返回
+    fn report(&mut self, error: Error) {
+        self.errors.push(error);
     }
 
     fn synchronize(&mut self) {
+        // 出错的地方就是块的 `}` 时不能把它吃掉，留给 block() 去 consume；
+        // 顶层没有块会消费它，必须跳过，否则会在同一个 `}` 上反复报错
+        if self.blocks > 0 && self.check(&TokenType::RightBrace) {
+            return;
+        }
+
         self.advance();
 
         while !self.is_at_end() {
@@ -559,6 +867,7 @@ impl Parser {
                 | TokenType::WHILE
                 | TokenType::PRINT
                 | TokenType::RETURN => return,
+                TokenType::RightBrace if self.blocks > 0 => return,
                 _ => (),
             }
 
@@ -571,10 +880,10 @@ impl Parser {
             return false;
         }
 
-        self.peek().token_type == token_type.clone()
+        self.peek().token_type == *token_type
     }
 
-    fn advance(&mut self) -> Token {
+    fn advance(&mut self) -> &Token {
         if !self.is_at_end() {
             self.current += 1;
         }
@@ -586,11 +895,100 @@ impl Parser {
         self.peek().token_type == TokenType::EOF
     }
 
-    fn peek(&self) -> Token {
-        self.tokens.get(self.current).unwrap().to_owned()
+    // 只借出 token，需要保存到语法树里的时候再 clone
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
     }
 
-    fn previous(&self) -> Token {
-        self.tokens.get(self.current - 1).unwrap().to_owned()
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current - 1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast_printer::AstPrinter;
+    use crate::scanner::Scanner;
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let tokens = Scanner::new(source.to_string()).scan_tokens();
+        Parser::new(tokens).parse().0
+    }
+
+    fn spans(source: &str) -> String {
+        AstPrinter::with_spans().print(&parse(source))
+    }
+
+    fn span(start_line: usize, start_col: usize, end_line: usize, end_col: usize) -> Span {
+        Span {
+            start_line,
+            start_col,
+            end_line,
+            end_col,
+        }
+    }
+
+    #[test]
+    fn nested_expressions_cover_their_operands() {
+        assert_eq!(
+            spans("x = -(a + b) * f(1, 2);"),
+            "(; (= x (* (- (group (+ a@1:7-1:8 b@1:11-1:12)@1:7-1:12)@1:6-1:13)@1:5-1:13 \
+             (call f@1:16-1:17 1@1:18-1:19 2@1:21-1:22)@1:16-1:23)@1:5-1:23)@1:1-1:23)@1:1-1:24\n"
+        );
+    }
+
+    #[test]
+    fn statements_span_lines() {
+        let statements = parse("fun f(a) {\n  return a;\n}\nif (f(1))\n  print \"a\nb\";\n");
+        assert_eq!(statements[0].span(), span(1, 1, 3, 2));
+        let Stmt::Function { body, .. } = &statements[0] else {
+            panic!("expected a function");
+        };
+        assert_eq!(body[0].span(), span(2, 3, 2, 12));
+
+        assert_eq!(statements[1].span(), span(4, 1, 6, 4));
+        let Stmt::If { then_branch, .. } = &statements[1] else {
+            panic!("expected an if statement");
+        };
+        let Stmt::Print { expression, .. } = &**then_branch else {
+            panic!("expected a print statement");
+        };
+        assert_eq!(expression.span(), span(5, 9, 6, 3));
+    }
+
+    // for 脱糖出来的块和 while 覆盖整个 for 语句，增量表达式保留自己的位置
+    #[test]
+    fn desugared_for_keeps_source_positions() {
+        assert_eq!(
+            spans("for (;; i = i + 1) {}"),
+            "(while true@1:7-1:8\n  (block\n    (block)@1:20-1:22\n    \
+             (; (= i (+ i@1:13-1:14 1@1:17-1:18)@1:13-1:18)@1:9-1:18)@1:9-1:18)@1:1-1:22)@1:1-1:22\n"
+        );
+    }
+
+    #[test]
+    fn error_nodes_cover_skipped_tokens() {
+        let statements = parse("var = 1;\nprint 2;");
+        assert!(matches!(statements[0], Stmt::Error { .. }));
+        assert_eq!(statements[0].span(), span(1, 1, 1, 9));
+        assert_eq!(statements[1].span(), span(2, 1, 2, 9));
+    }
+
+    #[test]
+    fn expression_depth_limit_is_configurable() {
+        let source = "print ((((1))));";
+        assert!(Parser::new(Scanner::new(source.to_string()).scan_tokens())
+            .parse()
+            .1
+            .is_empty());
+
+        let mut parser = Parser::new(Scanner::new(source.to_string()).scan_tokens());
+        parser.set_max_expression_depth(4);
+        let (_, errors) = parser.parse();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0]
+            .to_string()
+            .contains("Expression too deeply nested."));
     }
 }
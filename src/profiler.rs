@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// 一个函数累计的调用次数和耗时。inclusive 包括它调用的其他函数，exclusive 只算函数自己
+#[derive(Debug, Default, Clone, Copy)]
+struct Entry {
+    calls: usize,
+    inclusive: Duration,
+    exclusive: Duration,
+}
+
+// 正在执行的一次调用
+#[derive(Debug)]
+struct Active {
+    key: (Rc<str>, usize),
+    start: Instant,
+    children: Duration, // 它调用的函数一共花的时间，算 exclusive 时要减掉
+}
+
+// --profile：按函数名和声明所在的行统计每个 Lox 函数的调用次数和耗时
+#[derive(Debug, Default)]
+pub struct Profiler {
+    entries: HashMap<(Rc<str>, usize), Entry>,
+    stack: Vec<Active>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    pub fn enter(&mut self, name: Rc<str>, line: usize) {
+        let key = (name, line);
+        self.entries.entry(key.clone()).or_default().calls += 1;
+        self.stack.push(Active {
+            key,
+            start: Instant::now(),
+            children: Duration::ZERO,
+        });
+    }
+
+    pub fn exit(&mut self) {
+        let Some(active) = self.stack.pop() else {
+            return;
+        };
+        let elapsed = active.start.elapsed();
+        if let Some(caller) = self.stack.last_mut() {
+            caller.children += elapsed;
+        }
+
+        // 递归调用时外层已经包含了内层的时间，inclusive 只在最外层那次累加
+        let recursive = self.stack.iter().any(|caller| caller.key == active.key);
+        let entry = self.entries.entry(active.key).or_default();
+        if !recursive {
+            entry.inclusive += elapsed;
+        }
+        entry.exclusive += elapsed.saturating_sub(active.children);
+    }
+}
+
+// 按 exclusive 时间从多到少排列，最耗时的函数在最上面
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by(|(a_key, a), (b_key, b)| {
+            b.exclusive.cmp(&a.exclusive).then_with(|| a_key.cmp(b_key))
+        });
+
+        let width = entries
+            .iter()
+            .map(|((name, _), _)| name.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max("function".len());
+        writeln!(
+            f,
+            "{:<width$} {:>6} {:>8} {:>12} {:>12}",
+            "function", "line", "calls", "inclusive", "exclusive"
+        )?;
+        for ((name, line), entry) in entries {
+            writeln!(
+                f,
+                "{:<width$} {:>6} {:>8} {:>12} {:>12}",
+                name,
+                line,
+                entry.calls,
+                milliseconds(entry.inclusive),
+                milliseconds(entry.exclusive)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// 和 --time 的格式一致
+fn milliseconds(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recursion_counts_inclusive_time_once() {
+        let mut profiler = Profiler::new();
+        let name: Rc<str> = Rc::from("fib");
+        profiler.enter(name.clone(), 1);
+        profiler.enter(name.clone(), 1);
+        std::thread::sleep(Duration::from_millis(2));
+        profiler.exit();
+        profiler.exit();
+
+        let entry = profiler.entries[&(name, 1)];
+        assert_eq!(entry.calls, 2);
+        assert!(entry.inclusive >= Duration::from_millis(2));
+        assert!(entry.exclusive <= entry.inclusive);
+    }
+}
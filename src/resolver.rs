@@ -1,4 +1,4 @@
-use super::error::{Error, Result};
+use super::error::{Diagnostic, Result, Severity};
 use super::expr::Expr;
 use super::expr::{Acceptor as ExprAcceptor, Visitor as ExprVisitor};
 use super::interpreter::Interpreter;
@@ -7,6 +7,7 @@ use super::token::Literal;
 use super::token::Token;
 
 use std::collections::HashMap;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 enum FunctionType {
@@ -14,24 +15,127 @@ enum FunctionType {
     FUNCTION,
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum VariableState {
+    Declared, // 存在，但"尚未准备好"──"未初始化"
+    Defined,  // 已初始化可供使用
+    Read,     // 被读取过
+}
+
+// 名字是怎么被绑定的，重复声明时用来说清楚和谁冲突
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum BindingKind {
+    Variable,
+    Function(usize), // 参数个数
+    Parameter,
+}
+
+impl BindingKind {
+    fn describe(&self) -> &'static str {
+        match self {
+            BindingKind::Variable => "variable",
+            BindingKind::Function(_) => "function",
+            BindingKind::Parameter => "parameter",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Local {
+    name: Token, // 声明处的 token，用于报告警告
+    kind: BindingKind,
+    state: VariableState,
+    reassigned: bool,           // 声明之后是否被赋过值（全局函数被重新定义也算）
+    calls: Vec<(Token, usize)>, // 直接通过这个名字的调用：右括号和实参个数
+    last_write: Option<Token>,  // 最后一次赋值之后还没被读过的话，就是那次赋值的 token
+    captured: bool,             // 被内层函数读过，之后的赋值可能在闭包里被读到
+    functions: usize,           // 声明时所在的函数嵌套层数
+    loops: usize,               // 声明时所在的循环嵌套层数
+    slot: usize,                // 在作用域里的声明顺序，也就是运行时在环境里的下标
+}
+
 #[derive(Debug)]
 pub struct Resolver<'res> {
     interpreter: &'res mut Interpreter,
-    pub scopes: Vec<HashMap<String, bool>>, // 所有局部作用域，不包括全局
+    scopes: Vec<HashMap<Rc<str>, Local>>, // 所有局部作用域，不包括全局
+    globals: HashMap<Rc<str>, Local>,     // 已经声明过的全局变量，只用于检查遮蔽和重复定义
     current_function: FunctionType,
+    functions: usize, // 当前的函数嵌套层数
+    loops: usize,     // 当前的循环嵌套层数
+    // 运行脚本文件时语句从上到下执行，顶层在定义之前使用全局变量一定会出错；
+    // REPL 里的全局变量可能是之前输入的，不做这项检查
+    script: bool,
+    definitions: HashMap<Rc<str>, (usize, usize)>, // 全局变量第一次定义所在的顶层语句序号和行号
+    statement: usize,                              // 当前顶层语句的序号
+    diagnostics: Vec<Diagnostic>,                  // 一遍解析中发现的所有错误和警告
 }
 
 impl<'res> Resolver<'res> {
-    pub fn new(interpreter: &'res mut Interpreter) -> Resolver {
+    pub fn new(interpreter: &'res mut Interpreter, script: bool) -> Resolver<'res> {
         Resolver {
             interpreter,
             scopes: Vec::new(),
+            globals: HashMap::new(),
             current_function: FunctionType::NONE,
+            functions: 0,
+            loops: 0,
+            script,
+            definitions: HashMap::new(),
+            statement: 0,
+            diagnostics: Vec::new(),
         }
     }
 
-    pub fn resolve_statements(&mut self, statements: &Vec<Stmt>) -> Result<()> {
-        for statement in statements {
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn resolve(&mut self, statements: &Vec<Stmt>) -> Result<()> {
+        if self.script {
+            for (index, statement) in statements.iter().enumerate() {
+                if let Stmt::Var { name, .. } | Stmt::Function { name, .. } = statement {
+                    self.definitions
+                        .entry(name.lexeme.clone())
+                        .or_insert((index, name.line));
+                }
+            }
+        }
+
+        self.resolve_statements(statements)?;
+
+        // 全局函数可能在调用之后才被重新赋值，所以等整个程序解析完再检查
+        let globals = std::mem::take(&mut self.globals);
+        for global in globals.values() {
+            self.check_calls(global);
+        }
+
+        Ok(())
+    }
+
+    fn resolve_statements(&mut self, statements: &[Stmt]) -> Result<()> {
+        // 同一个语句列表里紧跟在 return 后面的语句永远执行不到，只报第一条。
+        // 不做完整的控制流分析，if 分支里的 return 不影响后面的语句
+        let mut returned: Option<&Token> = None;
+        let mut reported = false;
+        for (index, statement) in statements.iter().enumerate() {
+            if self.scopes.is_empty() {
+                self.statement = index;
+            }
+
+            match returned {
+                Some(keyword) if !reported => {
+                    let token = statement.token().unwrap_or(keyword);
+                    self.warn(token, "W003", String::from("Unreachable code."));
+                    reported = true;
+                }
+                Some(_) => (),
+                None => {
+                    if let Stmt::Return { keyword, .. } = statement {
+                        returned = Some(keyword);
+                    }
+                }
+            }
+
             self.resolve_statement(statement)?;
         }
 
@@ -49,56 +153,115 @@ impl<'res> Resolver<'res> {
     fn resolve_function(
         &mut self,
         _name: &Token,
-        params: &Vec<Token>,
-        body: &Vec<Stmt>,
+        params: &[Token],
+        body: &[Stmt],
         fun_type: FunctionType,
     ) -> Result<()> {
         let enclosing_function = self.current_function.clone();
         self.current_function = fun_type;
+        self.functions += 1;
 
         // 为函数体创建一个新的作用域，然后为每个函数参数绑定变量
         self.begin_scope();
         for param in params {
-            self.declare(param)?;
+            self.declare(param, BindingKind::Parameter)?;
             self.define(param);
+            self.mark_read(param); // 回调函数常常用不到某些参数，不对参数报警告
         }
         self.resolve_statements(body)?;
         self.end_scope();
 
+        self.functions -= 1;
         self.current_function = enclosing_function;
 
         Ok(())
     }
 
-    fn resolve_local(&mut self, expr: Expr, name: &Token) -> Result<()> {
+    fn resolve_local(&mut self, id: usize, name: &Token) -> Result<()> {
         for (nesting_layer, scope) in self.scopes.iter().enumerate().rev() {
-            if scope.contains_key(&name.lexeme) {
-                self.interpreter
-                    .resolve(expr, self.scopes.len() - 1 - nesting_layer);
+            if let Some(local) = scope.get(&name.lexeme) {
+                let depth = self.scopes.len() - 1 - nesting_layer;
+                self.interpreter.resolve(id, depth, local.slot);
                 return Ok(());
             }
         }
 
-        // 如果遍历了所有的块作用域而未找到变量，我们就假设它是全局的
+        // 如果遍历了所有的块作用域而未找到变量，我们就假设它是全局的。
+        // 函数体可能在定义之后才被调用，只检查不在函数里的使用
+        if self.functions == 0 {
+            if let Some((index, line)) = self.definitions.get(&name.lexeme) {
+                if *index >= self.statement {
+                    let message = format!(
+                        "Global '{}' is used before its definition on line {}.",
+                        name.lexeme, line
+                    );
+                    self.warn(name, "W006", message);
+                }
+            }
+        }
+
         Ok(())
     }
 
-    fn declare(&mut self, name: &Token) -> Result<()> {
+    fn declare(&mut self, name: &Token, kind: BindingKind) -> Result<()> {
+        let mut local = Local {
+            name: name.clone(),
+            kind,
+            state: VariableState::Declared,
+            reassigned: false,
+            calls: Vec::new(),
+            last_write: None,
+            captured: false,
+            functions: self.functions,
+            loops: self.loops,
+            slot: self.scopes.last().map_or(0, HashMap::len),
+        };
+
         if self.scopes.is_empty() {
+            local.reassigned = self.globals.contains_key(&name.lexeme);
+            // 和 jlox 一样允许重新定义全局变量，但给一个警告
+            if let Some(previous) = self.globals.insert(name.lexeme.clone(), local) {
+                let message = format!(
+                    "Redefining global {} '{}' declared on line {}.",
+                    previous.kind.describe(),
+                    name.lexeme,
+                    previous.name.line
+                );
+                self.warn(name, "W004", message);
+            }
             return Ok(());
         }
 
-        let scope = self.scopes.last_mut().unwrap();
-        if scope.contains_key(&name.lexeme) {
-            // 禁止在局部作用域中出现像 `var a = a;` 这样的语句
-            return Err(Error::ResolveError(
-                name.clone(),
-                String::from("Already a variable with this name in this scope."),
-            ));
+        if let Some(previous) = self.scopes.last().unwrap().get(&name.lexeme) {
+            // 禁止在局部作用域中出现像 `var a = 1; var a = 2;` 这样的语句
+            let message = match (previous.kind, kind) {
+                (BindingKind::Function(_), _) | (_, BindingKind::Function(_)) => format!(
+                    "A {} named '{}' is already declared in this scope.",
+                    previous.kind.describe(),
+                    name.lexeme
+                ),
+                _ => String::from("Already a variable with this name in this scope."),
+            };
+            self.error(name, "R001", message);
+            return Ok(());
         }
 
-        // 该变量存在, 但 false 的含义是其"尚未准备好"──"未初始化"
-        scope.insert(name.lexeme.clone(), false);
+        // 合法但常常是笔误：遮蔽了外层作用域（包括全局）里的同名变量
+        let outer = self.scopes[..self.scopes.len() - 1]
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name.lexeme))
+            .or_else(|| self.globals.get(&name.lexeme));
+        if let Some(outer) = outer {
+            let message = format!(
+                "Variable '{}' shadows a variable declared on line {}.",
+                name.lexeme, outer.name.line
+            );
+            self.warn(name, "W002", message);
+        }
+
+        let scope = self.scopes.last_mut().unwrap();
+        scope.insert(name.lexeme.clone(), local);
 
         Ok(())
     }
@@ -109,7 +272,80 @@ impl<'res> Resolver<'res> {
         }
 
         let scope = self.scopes.last_mut().unwrap();
-        scope.insert(name.lexeme.clone(), true); // 将其标记为已初始化可供使用
+        if let Some(local) = scope.get_mut(&name.lexeme) {
+            local.state = VariableState::Defined; // 将其标记为已初始化可供使用
+        }
+    }
+
+    // 从内向外找到变量所在的作用域，记下它被读取过
+    fn mark_read(&mut self, name: &Token) {
+        let functions = self.functions;
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(local) = scope.get_mut(&name.lexeme) {
+                local.state = VariableState::Read;
+                local.last_write = None;
+                if functions > local.functions {
+                    local.captured = true;
+                }
+                return;
+            }
+        }
+    }
+
+    // 记下最后一次赋值。闭包里的赋值、循环里的赋值（下一轮可能会读到）
+    // 和变量被闭包读过之后的赋值都保守地当作会被读到。
+    // 闭包里的赋值还算作用过这个变量，不报 "never used"
+    fn mark_written(&mut self, name: &Token) {
+        let (functions, loops) = (self.functions, self.loops);
+        if let Some(local) = self.binding_mut(name) {
+            local.reassigned = true;
+            if functions > local.functions {
+                local.state = VariableState::Read;
+            }
+            local.last_write =
+                if local.captured || functions > local.functions || loops > local.loops {
+                    None
+                } else {
+                    Some(name.clone())
+                };
+        }
+    }
+
+    // 报告错误之后继续解析，一次把所有问题都找出来
+    fn error(&mut self, token: &Token, code: &'static str, message: String) {
+        let diagnostic = Diagnostic::new(Severity::Error, code, token, message);
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn warn(&mut self, token: &Token, code: &'static str, message: String) {
+        let diagnostic = Diagnostic::new(Severity::Warning, code, token, message);
+        self.diagnostics.push(diagnostic);
+    }
+
+    // 从内向外找名字对应的绑定，局部作用域里没有就到已经声明过的全局变量里找
+    fn binding_mut(&mut self, name: &Token) -> Option<&mut Local> {
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(local) = scope.get_mut(&name.lexeme) {
+                return Some(local);
+            }
+        }
+
+        self.globals.get_mut(&name.lexeme)
+    }
+
+    // 只有从声明到作用域结束都没被重新赋值过的函数，才能确定调用的一定是它，提前检查实参个数
+    fn check_calls(&mut self, local: &Local) {
+        let arity = match (local.kind, local.reassigned) {
+            (BindingKind::Function(arity), false) => arity,
+            _ => return,
+        };
+
+        for (paren, count) in &local.calls {
+            if *count != arity {
+                let message = format!("Expected {arity} arguments but got {count}.");
+                self.error(paren, "R004", message);
+            }
+        }
     }
 
     fn begin_scope(&mut self) {
@@ -117,7 +353,33 @@ impl<'res> Resolver<'res> {
     }
 
     fn end_scope(&mut self) {
-        self.scopes.pop();
+        let scope = self.scopes.pop().unwrap();
+        for local in scope.values() {
+            self.check_calls(local);
+        }
+
+        // 作用域结束时还没被读取过的局部变量，以及读过但最后一次赋的值再也没被读过的，
+        // 按位置排序报告
+        let mut unused = Vec::new();
+        let mut dead_stores = Vec::new();
+        for local in scope.into_values() {
+            if local.state != VariableState::Read {
+                unused.push(local.name);
+            } else if let Some(write) = local.last_write {
+                dead_stores.push(write);
+            }
+        }
+        unused.sort_by_key(|name| (name.line, name.column));
+        dead_stores.sort_by_key(|name| (name.line, name.column));
+
+        for name in unused {
+            let message = format!("Local variable '{}' is never used.", name.lexeme);
+            self.warn(&name, "W001", message);
+        }
+        for name in dead_stores {
+            let message = format!("Value assigned to '{}' is never used.", name.lexeme);
+            self.warn(&name, "W005", message);
+        }
     }
 }
 
@@ -145,29 +407,29 @@ impl<'a> ExprVisitor<Result<()>> for Resolver<'a> {
         Ok(())
     }
 
-    fn visit_var_expr(&mut self, name: &Token) -> Result<()> {
+    fn visit_var_expr(&mut self, id: usize, name: &Token) -> Result<()> {
         if !self.scopes.is_empty() {
-            if let Some(scope) = self.scopes.iter().peekable().peek() {
-                if let Some(var) = scope.get(&name.lexeme) {
-                    if *var == false {
-                        return Err(Error::ResolveError(
-                            name.clone(),
-                            String::from("Cannot read local variable in its own initializer."),
-                        ));
+            if let Some(scope) = self.scopes.last() {
+                if let Some(local) = scope.get(&name.lexeme) {
+                    if local.state == VariableState::Declared {
+                        let message =
+                            String::from("Can't read local variable in its own initializer.");
+                        self.error(name, "R002", message);
                     }
                 }
             }
         }
 
-        let expr = Expr::Variable { name: name.clone() };
-        self.resolve_local(expr, name)?;
+        self.resolve_local(id, name)?;
+        self.mark_read(name);
 
         Ok(())
     }
 
-    fn visit_assign_expr(&mut self, name: &Token, value: &Expr) -> Result<()> {
+    fn visit_assign_expr(&mut self, id: usize, name: &Token, value: &Expr) -> Result<()> {
         self.resolve_expr(value)?;
-        self.resolve_local(value.clone(), name)?;
+        self.resolve_local(id, name)?;
+        self.mark_written(name);
 
         Ok(())
     }
@@ -182,7 +444,7 @@ impl<'a> ExprVisitor<Result<()>> for Resolver<'a> {
     fn visit_call_expr(
         &mut self,
         callee: &Expr,
-        _paren: &Token,
+        paren: &Token,
         arguments: &Vec<Expr>,
     ) -> Result<()> {
         self.resolve_expr(callee)?;
@@ -190,6 +452,18 @@ impl<'a> ExprVisitor<Result<()>> for Resolver<'a> {
             self.resolve_expr(arg)?;
         }
 
+        // 直接按名字调用时先记下来，等知道这个名字有没有被重新赋值过再检查
+        if let Expr::Variable { name, .. } = callee {
+            if let Some(local) = self.binding_mut(name) {
+                local.calls.push((paren.clone(), arguments.len()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // 错误节点没有可解析的内容，跳过它，树的其它部分照常解析
+    fn visit_error_expr(&mut self, _token: &Token) -> Result<()> {
         Ok(())
     }
 }
@@ -207,13 +481,10 @@ impl<'a> StmtVisitor<Result<()>> for Resolver<'a> {
         Ok(())
     }
 
-    fn visit_var_stmt(&mut self, name: &Token, expression: &Expr) -> Result<()> {
-        self.declare(name)?;
-        match expression {
-            Expr::Literal {
-                value: Literal::Nil,
-            } => (),
-            _ => self.resolve_expr(expression)?,
+    fn visit_var_stmt(&mut self, name: &Token, expression: &Option<Expr>) -> Result<()> {
+        self.declare(name, BindingKind::Variable)?;
+        if let Some(expression) = expression {
+            self.resolve_expr(expression)?;
         }
         self.define(name);
 
@@ -242,9 +513,11 @@ impl<'a> StmtVisitor<Result<()>> for Resolver<'a> {
         }
     }
 
-    fn visit_while_stmt(&mut self, condition: &Expr, body: &Stmt) -> Result<()> {
+    fn visit_while_stmt(&mut self, _keyword: &Token, condition: &Expr, body: &Stmt) -> Result<()> {
+        self.loops += 1;
         self.resolve_expr(condition)?;
         self.resolve_statement(body)?;
+        self.loops -= 1;
 
         Ok(())
     }
@@ -252,13 +525,14 @@ impl<'a> StmtVisitor<Result<()>> for Resolver<'a> {
     fn visit_fun_stmt(
         &mut self,
         name: &Token,
-        params: &Vec<Token>,
-        body: &Vec<Stmt>,
+        params: &Rc<[Token]>,
+        body: &Rc<[Stmt]>,
     ) -> Result<()> { // 函数既绑定名称又引入作用域
         // 在当前作用域内声明和定义函数名称
         // 在解析函数体之前就定义了函数名称，这让函数可以在它自己的体内递归地引用自己
-        self.declare(name)?;
+        self.declare(name, BindingKind::Function(params.len()))?;
         self.define(name);
+        self.mark_read(name); // 只对 var 声明的变量报未使用的警告
 
         self.resolve_function(name, params, body, FunctionType::FUNCTION)?;
         Ok(())
@@ -266,17 +540,20 @@ impl<'a> StmtVisitor<Result<()>> for Resolver<'a> {
 
     fn visit_return_stmt(&mut self, keyword: &Token, value: &Expr) -> Result<()> {
         if self.current_function == FunctionType::NONE {
-            return Err(Error::ResolveError(
-                keyword.clone(),
-                String::from("Can't return from top-level code."),
-            ));
+            let message = String::from("Can't return from top-level code.");
+            self.error(keyword, "R003", message);
         }
 
         match value {
             Expr::Literal {
                 value: Literal::Nil,
+                ..
             } => Ok(()),
             _ => self.resolve_expr(value),
         }
     }
+
+    fn visit_error_stmt(&mut self, _token: &Token) -> Result<()> {
+        Ok(())
+    }
 }
@@ -1,40 +1,21 @@
-use super::error::lexer_error;
 use super::token::{Literal, Token, TokenType};
 
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::Rc;
 use std::string::String;
 
-use lazy_static::lazy_static;
-
-lazy_static! {
-    static ref KEYWORDS: HashMap<String, TokenType> = {
-        let mut keywords = HashMap::new();
-        keywords.insert(String::from("and"), TokenType::AND);
-        keywords.insert(String::from("class"), TokenType::CLASS);
-        keywords.insert(String::from("else"), TokenType::ELSE);
-        keywords.insert(String::from("false"), TokenType::FALSE);
-        keywords.insert(String::from("for"), TokenType::FOR);
-        keywords.insert(String::from("fun"), TokenType::FUN);
-        keywords.insert(String::from("if"), TokenType::IF);
-        keywords.insert(String::from("nil"), TokenType::NIL);
-        keywords.insert(String::from("or"), TokenType::OR);
-        keywords.insert(String::from("print"), TokenType::PRINT);
-        keywords.insert(String::from("return"), TokenType::RETURN);
-        keywords.insert(String::from("super"), TokenType::SUPER);
-        keywords.insert(String::from("this"), TokenType::THIS);
-        keywords.insert(String::from("true"), TokenType::TRUE);
-        keywords.insert(String::from("var"), TokenType::VAR);
-        keywords.insert(String::from("while"), TokenType::WHILE);
-        keywords
-    };
-}
+use unicode_ident::{is_xid_continue, is_xid_start};
 
 pub struct Scanner {
     source: String,
     tokens: Vec<Token>,
-    start: usize,
-    current: usize,
+    start: usize,   // 当前 token 第一个字符的字节偏移
+    current: usize, // 下一个待读取字符的字节偏移
     line: usize,
+    column: usize,              // 下一个待读取字符所在的列（按字符计数）
+    start_line: usize,          // 当前 token 开始时所在的行
+    start_column: usize,        // 当前 token 开始时所在的列
+    interned: HashSet<Rc<str>>, // 已经出现过的词素和字符串值
 }
 
 impl Scanner {
@@ -45,23 +26,47 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
+            interned: HashSet::new(),
         }
     }
 
+    // 扫描整个源码。非法字符和没闭合的字符串成为 ERROR token，扫描总是会进行到底，
+    // 由解析器按位置和语法错误一起报告，一次就能看到所有的错误
     pub fn scan_tokens(&mut self) -> Vec<Token> {
+        self.skip_shebang();
+
         while !self.is_at_end() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.column;
             self.scan_token();
         }
 
         self.tokens.push(Token::new(
             TokenType::EOF,
-            "".to_string(),
+            Rc::from(""),
             Literal::Nil,
             self.line,
+            self.column,
+            self.current..self.current,
         ));
 
-        Vec::clone(&self.tokens)
+        // token 已经扫描完了，直接移交所有权而不是再复制一遍整个 Vec
+        std::mem::take(&mut self.tokens)
+    }
+
+    // 可执行脚本第一行的 `#!/usr/bin/env rjlox` 当作注释跳过，换行符留给 scan_token 处理以保证行号正确
+    fn skip_shebang(&mut self) {
+        if !self.source.starts_with("#!") {
+            return;
+        }
+
+        while self.peek() != '\n' && !self.is_at_end() {
+            self.advance();
+        }
     }
 
     fn scan_token(&mut self) {
@@ -115,22 +120,15 @@ impl Scanner {
                 }
             }
             ' ' | '\r' | '\t' => (),
-            '\n' => self.line += 1,
+            '\n' => self.new_line(),
             '"' => self.string(),
-            'o' => {
-                if self.match_char('r') {
-                    self.add_token(TokenType::OR);
-                } else {
-                    self.identifier();
-                }
-            }
             _ => {
                 if c.is_ascii_digit() {
                     self.number();
-                } else if c.is_alphabetic() || c == '_' {
+                } else if is_identifier_start(c) {
                     self.identifier();
                 } else {
-                    lexer_error(self.line, "Unexpected character.");
+                    self.add_token(TokenType::ERROR);
                 }
             }
         }
@@ -138,24 +136,36 @@ impl Scanner {
 
     fn string(&mut self) {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
-            lexer_error(self.line, "Unterminated string.");
+            self.unterminated_string();
             return;
         }
 
         self.advance();
 
+        // Windows 换行符 \r\n 在字符串值里统一成 \n，行号只按 \n 计算
         let slice = self.source.get(self.start + 1..self.current - 1).unwrap();
-        let value = String::from(slice);
+        let value = slice.replace("\r\n", "\n");
+        let value = intern(&mut self.interned, &value);
         self.add_token_full(TokenType::STRING, Literal::Str(value));
     }
 
+    // 从引号到这一行末尾成为一个 ERROR token，解析器在引号的位置报错。
+    // 然后从下一行重新开始扫描，这样后面几行里的错误仍然能被报告出来，而不是整个文件都被吞进这个字符串里
+    fn unterminated_string(&mut self) {
+        let rest = &self.source[self.start..];
+        let end = rest.find(['\r', '\n']).unwrap_or(rest.len());
+        self.current = self.start + end;
+        self.line = self.start_line;
+        self.column = self.start_column + rest[..end].chars().count();
+        self.add_token(TokenType::ERROR);
+    }
+
     fn number(&mut self) {
         while self.peek().is_ascii_digit() {
             self.advance();
@@ -176,33 +186,27 @@ impl Scanner {
                 self.source
                     .get(self.start..self.current)
                     .unwrap()
-                    .parse::<f32>()
+                    .parse::<f64>()
                     .unwrap(),
             ),
         )
     }
 
     fn identifier(&mut self) {
-        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
+        while is_identifier_continue(self.peek()) {
             self.advance();
         }
 
         let text = self.source.get(self.start..self.current).unwrap();
-        let token_type_option = KEYWORDS.get(text);
-
-        match token_type_option {
-            Some(token_type) => {
-                self.add_token(token_type.to_owned());
-            }
-            None => {
-                self.add_token(TokenType::IDENTIFIER);
-            }
-        }
+        let token_type = keyword(text).unwrap_or(TokenType::IDENTIFIER);
+        self.add_token(token_type);
     }
 
+    // current 按字节前进而不是按字符，这样每次取字符都是 O(1)，不必每次都从头 chars().nth()
     fn advance(&mut self) -> char {
-        let char = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
+        let char = self.source[self.current..].chars().next().unwrap();
+        self.current += char.len_utf8();
+        self.column += 1;
         char
     }
 
@@ -211,10 +215,16 @@ impl Scanner {
     }
 
     fn add_token_full(&mut self, token_type: TokenType, literal: Literal) {
-        let a = self.source.get(self.start..self.current).unwrap();
-        let text = String::from(a);
-        self.tokens
-            .push(Token::new(token_type, text, literal, self.line));
+        // 直接拿源码切片去查，括号、分号、关键字这些反复出现的词素不会再分配内存
+        let text = intern(&mut self.interned, &self.source[self.start..self.current]);
+        self.tokens.push(Token::new(
+            token_type,
+            text,
+            literal,
+            self.start_line,
+            self.start_column,
+            self.start..self.current,
+        ));
     }
 
     fn match_char(&mut self, expected: char) -> bool {
@@ -222,30 +232,194 @@ impl Scanner {
             return false;
         }
 
-        if self.source.chars().nth(self.current).unwrap() != expected {
+        if self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
+        self.advance();
         true
     }
 
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column = 1;
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
 
     fn peek(&self) -> char {
         if self.is_at_end() {
-            return '\n';
+            return '\0';
         }
-        self.source.chars().nth(self.current).unwrap()
+        self.source[self.current..].chars().next().unwrap()
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        chars.next().unwrap_or('\0')
+    }
+}
+
+// 相同的文本只分配一次，之后的 token 共享同一个 Rc。
+// 不是方法，这样调用时可以同时借用 source 和 interned 两个字段
+fn intern(interned: &mut HashSet<Rc<str>>, text: &str) -> Rc<str> {
+    if let Some(interned) = interned.get(text) {
+        return interned.clone();
+    }
+
+    let text: Rc<str> = Rc::from(text);
+    interned.insert(text.clone());
+    text
+}
+
+// 所有的关键字，和 keyword() 里的一致。REPL 补全用
+pub const KEYWORDS: [&str; 16] = [
+    "and", "class", "else", "false", "for", "fun", "if", "nil", "or", "print", "return", "super",
+    "this", "true", "var", "while",
+];
+
+// 直接 match &str，不需要为每个标识符构造 String 去查 HashMap
+fn keyword(text: &str) -> Option<TokenType> {
+    let token_type = match text {
+        "and" => TokenType::AND,
+        "class" => TokenType::CLASS,
+        "else" => TokenType::ELSE,
+        "false" => TokenType::FALSE,
+        "for" => TokenType::FOR,
+        "fun" => TokenType::FUN,
+        "if" => TokenType::IF,
+        "nil" => TokenType::NIL,
+        "or" => TokenType::OR,
+        "print" => TokenType::PRINT,
+        "return" => TokenType::RETURN,
+        "super" => TokenType::SUPER,
+        "this" => TokenType::THIS,
+        "true" => TokenType::TRUE,
+        "var" => TokenType::VAR,
+        "while" => TokenType::WHILE,
+        _ => return None,
+    };
+
+    Some(token_type)
+}
+
+// 标识符遵循 Unicode XID 规则（再加上允许以下划线开头），所以 `café`、`λ`、`变量` 都是合法的标识符，
+// 而 emoji 不属于 XID 字符，会被当作非法字符报错。关键字只有 keyword() 中的 ASCII 单词。
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || is_xid_start(c)
+}
+
+pub fn is_identifier_continue(c: char) -> bool {
+    is_xid_continue(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(source: &str) -> Vec<(TokenType, String)> {
+        Scanner::new(source.to_string())
+            .scan_tokens()
+            .into_iter()
+            .map(|token| (token.token_type, token.lexeme.to_string()))
+            .filter(|(token_type, _)| *token_type != TokenType::EOF)
+            .collect()
+    }
+
+    fn identifier(name: &str) -> (TokenType, String) {
+        (TokenType::IDENTIFIER, name.to_string())
+    }
+
+    #[test]
+    fn or_is_a_keyword_only_on_its_own() {
+        assert_eq!(scan("or"), [(TokenType::OR, String::from("or"))]);
+        assert_eq!(scan("orchid"), [identifier("orchid")]);
+        assert_eq!(scan("o r"), [identifier("o"), identifier("r")]);
+        assert_eq!(
+            scan("o or ore orange"),
+            [
+                identifier("o"),
+                (TokenType::OR, String::from("or")),
+                identifier("ore"),
+                identifier("orange"),
+            ]
+        );
+    }
+
+    #[test]
+    fn keyword_prefixes_are_identifiers() {
+        let names = [
+            "an", "andy", "classy", "f", "fo", "fork", "funny", "i", "iff", "nile", "printer",
+            "returns", "superb", "thisness", "truex", "variable", "whiles",
+        ];
+        for name in names {
+            assert_eq!(scan(name), [identifier(name)], "{name}");
         }
+    }
+
+    #[test]
+    fn every_keyword_is_recognised() {
+        for name in KEYWORDS {
+            let tokens = scan(name);
+            assert_eq!(tokens.len(), 1, "{name}");
+            assert_eq!(Some(tokens[0].0.clone()), keyword(name), "{name}");
+        }
+    }
+
+    // 以前每次取字符都从头 chars().nth()，这么大的输入要扫好几分钟
+    #[test]
+    fn large_input_scans_in_linear_time() {
+        let lines = 15_000;
+        let source: String = (0..lines)
+            .map(|i| format!("var v{i} = \"s{i}\" + \"é\"; // c\n"))
+            .collect();
+        let tokens = Scanner::new(source).scan_tokens();
+        assert_eq!(tokens.len(), lines * 7 + 1);
+
+        // 多字节字符之后的列号也要对
+        let last = &tokens[tokens.len() - 2];
+        assert_eq!((last.line, last.column), (lines, 28));
+    }
+
+    #[test]
+    fn or_between_operands() {
+        assert_eq!(
+            scan("a or(b)"),
+            [
+                identifier("a"),
+                (TokenType::OR, String::from("or")),
+                (TokenType::LeftParen, String::from("(")),
+                identifier("b"),
+                (TokenType::RightParen, String::from(")")),
+            ]
+        );
+    }
+
+    #[test]
+    fn or_after_a_for_header() {
+        assert_eq!(
+            scan("for (;;) or"),
+            [
+                (TokenType::FOR, String::from("for")),
+                (TokenType::LeftParen, String::from("(")),
+                (TokenType::SEMICOLON, String::from(";")),
+                (TokenType::SEMICOLON, String::from(";")),
+                (TokenType::RightParen, String::from(")")),
+                (TokenType::OR, String::from("or")),
+            ]
+        );
+    }
 
-        self.source.chars().nth(self.current + 1).unwrap()
+    // 没闭合的字符串到行末为止，下一行照常扫描
+    #[test]
+    fn unterminated_string_ends_at_the_line() {
+        let tokens = Scanner::new(String::from("x = \"open;\r\n@")).scan_tokens();
+        assert!(tokens[2].is_unterminated_string());
+        assert_eq!(&*tokens[2].lexeme, "\"open;");
+        assert_eq!((tokens[3].line, tokens[3].column), (2, 1));
+        assert_eq!(&*tokens[3].lexeme, "@");
     }
 }
@@ -0,0 +1,135 @@
+use chrono::Utc;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+// 原生函数读到的时间和随机数都从这里来。默认是真实的时钟和随机种子，
+// 测试和嵌入解释器的程序可以换成固定的实现，让程序每次的输出都一样
+
+pub trait TimeSource {
+    // clock() 的返回值，单位是秒，只用来计算时间间隔
+    fn clock(&mut self) -> f64;
+    // now() 的返回值：Unix 时间戳，单位是毫秒
+    fn now(&mut self) -> i64;
+}
+
+pub trait RandomSource {
+    // random() 的返回值，在 [0, 1) 里均匀分布
+    fn random(&mut self) -> f64;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemTime;
+
+impl TimeSource for SystemTime {
+    // 网页里用 performance.now()，它的起点是页面加载的时刻
+    #[cfg(all(target_arch = "wasm32", feature = "wasm-bindgen"))]
+    fn clock(&mut self) -> f64 {
+        crate::wasm::now() / 1000.0
+    }
+
+    #[cfg(not(all(target_arch = "wasm32", feature = "wasm-bindgen")))]
+    fn clock(&mut self) -> f64 {
+        (self.now() / 1000_i64) as f64
+    }
+
+    fn now(&mut self) -> i64 {
+        Utc::now().timestamp_millis()
+    }
+}
+
+// 2000-01-01T00:00:00Z
+pub const FIXED_EPOCH: i64 = 946_684_800_000;
+
+// --deterministic 的时钟：从固定的时刻开始，每读一次往后走 1 毫秒，
+// 这样等着时间流逝的循环也能结束
+#[derive(Debug)]
+pub struct FixedTime {
+    millis: i64,
+}
+
+impl FixedTime {
+    pub fn new(epoch: i64) -> FixedTime {
+        FixedTime { millis: epoch }
+    }
+
+    fn tick(&mut self) -> i64 {
+        let now = self.millis;
+        self.millis += 1;
+        now
+    }
+}
+
+impl Default for FixedTime {
+    fn default() -> FixedTime {
+        FixedTime::new(FIXED_EPOCH)
+    }
+}
+
+impl TimeSource for FixedTime {
+    fn clock(&mut self) -> f64 {
+        (self.tick() - FIXED_EPOCH) as f64 / 1000.0
+    }
+
+    fn now(&mut self) -> i64 {
+        self.tick()
+    }
+}
+
+// --deterministic 用的种子
+pub const FIXED_SEED: u64 = 0x5eed;
+
+// SplitMix64，足够 Lox 程序用，给了种子之后生成的序列是固定的
+#[derive(Debug)]
+pub struct SeededRandom {
+    state: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        SeededRandom { state: seed }
+    }
+
+    // 每个进程的 RandomState 都带着不同的随机密钥，拿它当种子
+    pub fn from_entropy() -> SeededRandom {
+        SeededRandom::new(RandomState::new().build_hasher().finish())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn random(&mut self) -> f64 {
+        // 取高 53 位，正好是 f64 尾数的精度
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let (mut a, mut b) = (SeededRandom::new(42), SeededRandom::new(42));
+        for _ in 0..100 {
+            let value = a.random();
+            assert!((0.0..1.0).contains(&value));
+            assert_eq!(value, b.random());
+        }
+        assert_ne!(SeededRandom::new(1).random(), SeededRandom::new(2).random());
+    }
+
+    #[test]
+    fn fixed_time_advances_on_every_reading() {
+        let mut time = FixedTime::default();
+        assert_eq!(time.now(), FIXED_EPOCH);
+        assert_eq!(time.clock(), 0.001);
+        assert_eq!(time.now(), FIXED_EPOCH + 2);
+    }
+}
@@ -1,10 +1,12 @@
 use super::expr::Expr;
-use super::token::Token;
+use super::token::{Span, Token};
+
+use std::rc::Rc;
 
 pub trait Visitor<T> {
     fn visit_expression_stmt(&mut self, expression: &Expr) -> T;
     fn visit_print_stmt(&mut self, expression: &Expr) -> T;
-    fn visit_var_stmt(&mut self, name: &Token, expression: &Expr) -> T;
+    fn visit_var_stmt(&mut self, name: &Token, expression: &Option<Expr>) -> T;
     fn visit_block_stmt(&mut self, stmts: &Vec<Stmt>) -> T;
     fn visit_if_stmt(
         &mut self,
@@ -12,65 +14,123 @@ pub trait Visitor<T> {
         then_branch: &Stmt,
         else_branch: &Option<Box<Stmt>>,
     ) -> T;
-    fn visit_while_stmt(&mut self, condition: &Expr, body: &Stmt) -> T;
-    fn visit_fun_stmt(&mut self, name: &Token, params: &Vec<Token>, body: &Vec<Stmt>) -> T;
+    fn visit_while_stmt(&mut self, keyword: &Token, condition: &Expr, body: &Stmt) -> T;
+    fn visit_fun_stmt(&mut self, name: &Token, params: &Rc<[Token]>, body: &Rc<[Stmt]>) -> T;
     fn visit_return_stmt(&mut self, keyword: &Token, value: &Expr) -> T;
+    fn visit_error_stmt(&mut self, token: &Token) -> T;
 }
 
 pub trait Acceptor<T> {
     fn accept(&self, visitor: &mut dyn Visitor<T>) -> T;
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone)]
 pub enum Stmt {
     Expression {
         expression: Expr,
+        span: Span,
     },
     Print {
         expression: Expr,
+        span: Span,
     },
     Return {
         keyword: Token,
         value: Expr,
+        span: Span,
     },
     Var {
         name: Token,
-        expression: Expr,
+        expression: Option<Expr>, // `var a;` 没有初始化表达式
+        span: Span,
     },
     Block {
         stmts: Vec<Stmt>,
+        span: Span,
     },
     Function {
         name: Token,
-        params: Vec<Token>,
-        body: Vec<Stmt>,
+        params: Rc<[Token]>, // 和运行时的函数值共享，声明函数时不用复制函数体
+        body: Rc<[Stmt]>,
+        span: Span,
     },
     If {
         condition: Expr,
         then_branch: Box<Stmt>,
         else_branch: Option<Box<Stmt>>,
+        span: Span,
     },
     While {
+        keyword: Token, // `while`，由 for 脱糖而来时是 `for`
         condition: Expr,
         body: Box<Stmt>,
+        span: Span,
+    },
+    Error {
+        token: Token, // 解析失败、被 synchronize 跳过的语句，token 是出错的位置
+        span: Span,
     },
 }
 
 impl<T> Acceptor<T> for Stmt {
     fn accept(&self, visitor: &mut dyn Visitor<T>) -> T {
         match self {
-            Stmt::Expression { expression } => visitor.visit_expression_stmt(expression),
-            Stmt::Print { expression } => visitor.visit_print_stmt(expression),
-            Stmt::Var { name, expression } => visitor.visit_var_stmt(name, expression),
-            Stmt::Block { stmts } => visitor.visit_block_stmt(stmts),
+            Stmt::Expression { expression, .. } => visitor.visit_expression_stmt(expression),
+            Stmt::Print { expression, .. } => visitor.visit_print_stmt(expression),
+            Stmt::Var {
+                name, expression, ..
+            } => visitor.visit_var_stmt(name, expression),
+            Stmt::Block { stmts, .. } => visitor.visit_block_stmt(stmts),
             Stmt::If {
                 condition,
                 then_branch,
                 else_branch,
+                ..
             } => visitor.visit_if_stmt(condition, then_branch, else_branch),
-            Stmt::While { condition, body } => visitor.visit_while_stmt(condition, body),
-            Stmt::Function { name, params, body } => visitor.visit_fun_stmt(name, params, body),
-            Stmt::Return { keyword, value } => visitor.visit_return_stmt(keyword, value),
+            Stmt::While {
+                keyword,
+                condition,
+                body,
+                ..
+            } => visitor.visit_while_stmt(keyword, condition, body),
+            Stmt::Function {
+                name, params, body, ..
+            } => visitor.visit_fun_stmt(name, params, body),
+            Stmt::Return { keyword, value, .. } => visitor.visit_return_stmt(keyword, value),
+            Stmt::Error { token, .. } => visitor.visit_error_stmt(token),
+        }
+    }
+}
+
+impl Stmt {
+    // 语句中最靠前的、可以用来报告位置的 token
+    pub fn token(&self) -> Option<&Token> {
+        match self {
+            Stmt::Expression { expression, .. } | Stmt::Print { expression, .. } => {
+                expression.token()
+            }
+            Stmt::Return { keyword, .. } => Some(keyword),
+            Stmt::Var { name, .. } | Stmt::Function { name, .. } => Some(name),
+            Stmt::Block { stmts, .. } => stmts.iter().find_map(Stmt::token),
+            Stmt::If { condition, .. } => condition.token(),
+            Stmt::While {
+                keyword, condition, ..
+            } => condition.token().or(Some(keyword)),
+            Stmt::Error { token, .. } => Some(token),
+        }
+    }
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Expression { span, .. }
+            | Stmt::Print { span, .. }
+            | Stmt::Return { span, .. }
+            | Stmt::Var { span, .. }
+            | Stmt::Block { span, .. }
+            | Stmt::Function { span, .. }
+            | Stmt::If { span, .. }
+            | Stmt::While { span, .. }
+            | Stmt::Error { span, .. } => *span,
         }
     }
 }
@@ -1,8 +1,11 @@
 use std::fmt;
 use std::fmt::{Debug, Formatter, Result};
 use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::rc::Rc;
 
 #[allow(clippy::upper_case_acronyms)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum TokenType {
     // Single-character tokens.
@@ -51,13 +54,15 @@ pub enum TokenType {
     VAR,
     WHILE,
 
+    ERROR, // 非法字符或没闭合的字符串，由解析器在遇到它的位置报错
     EOF,
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone)]
 pub enum Literal {
-    Str(String),
-    Num(f32),
+    Str(Rc<str>), // 字符串值不可变，复制值时只增加引用计数
+    Num(f64),
     Bool(bool),
     Nil,
 }
@@ -66,13 +71,27 @@ impl fmt::Display for Literal {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         match self {
             Literal::Str(string) => write!(f, "{string}"),
-            Literal::Num(num) => write!(f, "{num}"),
+            Literal::Num(num) => write!(f, "{}", format_number(*num)),
             Literal::Bool(bool) => write!(f, "{bool}"),
             Literal::Nil => write!(f, "nil"),
         }
     }
 }
 
+// 数字转字符串统一走这里。整数不带小数部分，-0 保留符号；
+// 和 JavaScript 一样，绝对值不小于 1e21 或小于 1e-6 时才用科学计数法
+pub fn format_number(num: f64) -> String {
+    if num.is_nan() {
+        String::from("NaN")
+    } else if num.is_infinite() {
+        String::from(if num > 0.0 { "Infinity" } else { "-Infinity" })
+    } else if num != 0.0 && (num.abs() >= 1e21 || num.abs() < 1e-6) {
+        format!("{num:e}")
+    } else {
+        format!("{num}")
+    }
+}
+
 impl Eq for Literal {}
 
 impl PartialEq for Literal {
@@ -98,12 +117,15 @@ impl Hash for Literal {
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Token {
     pub token_type: TokenType,
-    pub lexeme: String,
+    pub lexeme: Rc<str>, // 同一份源码里相同的词素共享同一个 Rc，复制 token 不用复制字符串
     pub literal: Literal,
     pub line: usize,
+    pub column: usize,      // 从 1 开始，按字符计数
+    pub span: Range<usize>, // 在源码中的偏移区间 start..end
 }
 
 impl Debug for Token {
@@ -117,17 +139,110 @@ impl Debug for Token {
 }
 
 impl Token {
+    // --dump-tokens 每行的格式：位置、类型、词素、字面量，各占固定宽度的一列。
+    // 词素里的换行等字符转义显示，一个 token 始终只占一行；只有字符串和数字有字面量
+    pub fn dump(&self) -> String {
+        let position = format!("{}:{}", self.line, self.column);
+        let token_type = format!("{:?}", self.token_type);
+        let lexeme: String = self
+            .lexeme
+            .chars()
+            .map(|c| {
+                if c.is_control() {
+                    c.escape_default().to_string()
+                } else {
+                    c.to_string()
+                }
+            })
+            .collect();
+        let literal = match (&self.token_type, &self.literal) {
+            (TokenType::STRING, Literal::Str(string)) => format!("{string:?}"),
+            (TokenType::NUMBER, literal) => literal.to_string(),
+            _ => String::new(),
+        };
+        let line = format!("{position:<8} {token_type:<13} {lexeme:<12} {literal}");
+        line.trim_end().to_string()
+    }
+
+    // 没闭合的字符串：从引号到这一行末尾的 ERROR token
+    pub fn is_unterminated_string(&self) -> bool {
+        self.token_type == TokenType::ERROR && self.lexeme.starts_with('"')
+    }
+
+    // token 最后一个字符之后的位置 (行, 列)，多行字符串结束在后面的行上
+    pub fn end(&self) -> (usize, usize) {
+        match self.lexeme.rfind('\n') {
+            Some(newline) => (
+                self.line + self.lexeme.matches('\n').count(),
+                self.lexeme[newline + 1..].chars().count() + 1,
+            ),
+            None => (self.line, self.column + self.lexeme.chars().count()),
+        }
+    }
+
     pub(crate) fn new(
         token_type: TokenType,
-        lexeme: String,
+        lexeme: Rc<str>,
         literal: Literal,
         line: usize,
+        column: usize,
+        span: Range<usize>,
     ) -> Self {
         Token {
             token_type,
             lexeme,
             literal,
             line,
+            column,
+            span,
+        }
+    }
+}
+
+// 语法树节点在源码中的范围，从第一个 token 的开头到最后一个 token 的结尾。
+// 行列都从 1 开始，end_col 是最后一个字符之后的那一列
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start_line: usize,
+    pub start_col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+impl Span {
+    pub fn new(first: &Token, last: &Token) -> Span {
+        let (end_line, end_col) = last.end();
+        Span {
+            start_line: first.line,
+            start_col: first.column,
+            end_line,
+            end_col,
         }
     }
+
+    // 从 self 的开头到 other 的结尾
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end_line: other.end_line,
+            end_col: other.end_col,
+            ..self
+        }
+    }
+}
+
+impl From<&Token> for Span {
+    fn from(token: &Token) -> Span {
+        Span::new(token, token)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(
+            f,
+            "{}:{}-{}:{}",
+            self.start_line, self.start_col, self.end_line, self.end_col
+        )
+    }
 }
@@ -0,0 +1,456 @@
+use super::callable::{self, Callable, LoxCallable};
+use super::chunk::{Capture, Op, Prototype};
+use super::compiler::Compiler;
+use super::environment::Environment;
+use super::error::Result;
+use super::expr::Expr;
+use super::interpreter::{self, Interpreter, DEFAULT_MAX_CALL_DEPTH};
+use super::object::Object;
+use super::sources::{RandomSource, TimeSource};
+use super::stmt::Stmt;
+use super::token::{Literal, Token};
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+// 执行 compiler 生成的字节码。和树遍历解释器共用原生函数、全局环境和运算的实现，
+// 输出和报错应该和它完全一致，树遍历解释器是对照的标准。
+// Lox 函数调用不占用 Rust 的栈，调用层数只受 max_call_depth 限制
+
+// 闭包捕获的变量。外层函数还没返回时变量在栈上，返回（或者离开变量的作用域）之后移到这里
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize), // 变量在栈上的位置
+    Closed(Object),
+}
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Prototype>,
+    upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+// 闭包链可能很长（每个闭包捕获了前一个），递归释放会撑爆 Rust 的栈，
+// 和 Environment 一样逐个拆开
+impl Drop for Closure {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.detach(&mut pending);
+
+        while let Some(closure) = pending.pop() {
+            if let Ok(mut closure) = Rc::try_unwrap(closure) {
+                closure.detach(&mut pending);
+            }
+        }
+    }
+}
+
+impl Closure {
+    // 把只被这个闭包引用着的、捕获到的闭包取出来，放进 pending 里
+    fn detach(&mut self, pending: &mut Vec<Rc<Closure>>) {
+        for upvalue in self.upvalues.drain(..) {
+            if let Ok(upvalue) = Rc::try_unwrap(upvalue) {
+                if let Upvalue::Closed(Object::Callable(Callable::Closure(closure))) =
+                    upvalue.into_inner()
+                {
+                    pending.push(closure);
+                }
+            }
+        }
+    }
+}
+
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    base: usize, // 这一帧在栈上的起点，那里放着被调用的函数，后面是实参和局部变量
+    line: usize, // 调用处的行号，给调用栈用
+}
+
+pub struct Vm {
+    stack: Vec<Object>,
+    frames: Vec<CallFrame>,                   // 第一帧是脚本本身
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>, // 还在栈上的被捕获变量，按位置排序
+    globals: Rc<Environment>,
+    max_call_depth: usize,
+    trace: Option<Vec<(Rc<str>, usize)>>, // 运行时错误发生时的调用栈
+    // 原生函数的函数体要拿到一个解释器，虚拟机没有自己的，借给它们这个。print 也写到它的输出里
+    host: Interpreter,
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            globals: Rc::new(Environment::new(Some(callable::prelude()))),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            trace: None,
+            host: Interpreter::new(),
+        }
+    }
+
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+
+    pub fn set_writer(&mut self, writer: Box<dyn Write>) {
+        self.host.set_writer(writer);
+    }
+
+    pub fn set_time_source(&mut self, time: Box<dyn TimeSource>) {
+        self.host.set_time_source(time);
+    }
+
+    pub fn set_random_source(&mut self, random: Box<dyn RandomSource>) {
+        self.host.set_random_source(random);
+    }
+
+    pub fn globals(&self) -> &Rc<Environment> {
+        &self.globals
+    }
+
+    // 取走上一个运行时错误的调用栈，错误发生在顶层时为空
+    pub fn take_trace(&mut self) -> Vec<(Rc<str>, usize)> {
+        self.trace.take().unwrap_or_default()
+    }
+
+    // 返回最后一条语句的值：最后一条是表达式语句时就是表达式的值，否则是 nil
+    pub fn interpret(&mut self, statements: &[Stmt]) -> Result<Object> {
+        let script = Compiler::compile(statements)?;
+        self.execute(script)
+    }
+
+    pub fn interpret_expression(&mut self, expr: &Expr) -> Result<Object> {
+        let script = Compiler::compile_expression(expr)?;
+        self.execute(script)
+    }
+
+    fn execute(&mut self, function: Rc<Prototype>) -> Result<Object> {
+        let closure = Rc::new(Closure {
+            function,
+            upvalues: Vec::new(),
+        });
+        self.stack
+            .push(Object::Callable(Callable::Closure(closure.clone())));
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base: 0,
+            line: 0,
+        });
+
+        // 出错时丢掉整个栈，REPL 的下一行从干净的状态开始
+        let result = self.run();
+        if result.is_err() {
+            let frames = self.frames.iter().skip(1);
+            let trace = frames.map(|frame| (frame.closure.function.name.clone(), frame.line));
+            self.trace = Some(trace.collect());
+            self.frames.clear();
+            self.stack.clear();
+            self.open_upvalues.clear();
+        }
+
+        result
+    }
+
+    fn run(&mut self) -> Result<Object> {
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let op = frame.closure.function.chunk.code[frame.ip];
+            frame.ip += 1;
+
+            match op {
+                Op::Constant(index) => {
+                    let value = self.frame().closure.function.chunk.constants[index].clone();
+                    self.stack.push(value);
+                }
+                Op::Nil => self.stack.push(Object::Literal(Literal::Nil)),
+                Op::Uninitialized => self.stack.push(Object::Uninitialized),
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::GetLocal(slot) => {
+                    let value = self.stack[self.frame().base + slot].clone();
+                    self.push_initialized(value)?;
+                }
+                Op::SetLocal(slot) => {
+                    let slot = self.frame().base + slot;
+                    self.stack[slot] = self.peek().clone();
+                }
+                Op::GetUpvalue(index) => {
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.push_initialized(value)?;
+                }
+                Op::SetUpvalue(index) => {
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let value = self.peek().clone();
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    };
+                }
+                Op::DefineGlobal => {
+                    let value = self.stack.pop().unwrap();
+                    self.globals.define(self.token().lexeme.clone(), &value);
+                }
+                Op::GetGlobal => {
+                    let value = self.globals.get(self.token())?;
+                    self.push_initialized(value)?;
+                }
+                Op::SetGlobal => self.globals.assign(self.token(), self.peek())?,
+                Op::Equal | Op::NotEqual => {
+                    let right = self.stack.pop().unwrap();
+                    let left = self.stack.pop().unwrap();
+                    let equal = left == right;
+                    let result = if let Op::Equal = op { equal } else { !equal };
+                    self.stack.push(Object::Literal(Literal::Bool(result)));
+                }
+                Op::Greater => self.binary(|a, b| Literal::Bool(a > b))?,
+                Op::GreaterEqual => self.binary(|a, b| Literal::Bool(a >= b))?,
+                Op::Less => self.binary(|a, b| Literal::Bool(a < b))?,
+                Op::LessEqual => self.binary(|a, b| Literal::Bool(a <= b))?,
+                Op::Add => self.binary(|a, b| Literal::Num(a + b))?,
+                Op::Subtract => self.binary(|a, b| Literal::Num(a - b))?,
+                Op::Multiply => self.binary(|a, b| Literal::Num(a * b))?,
+                Op::Divide => self.binary(|a, b| Literal::Num(a / b))?,
+                Op::Not => {
+                    let value = self.stack.pop().unwrap();
+                    let result = Literal::Bool(!value.is_truthy());
+                    self.stack.push(Object::Literal(result));
+                }
+                Op::Negate => {
+                    let value = self.stack.pop().unwrap();
+                    let result = match value {
+                        Object::Literal(Literal::Num(x)) => Object::Literal(Literal::Num(-x)),
+                        _ => interpreter::unary(self.token(), &value)?,
+                    };
+                    self.stack.push(result);
+                }
+                Op::Print => {
+                    let value = self.stack.pop().unwrap();
+                    let _ = writeln!(self.host.writer(), "{value}");
+                }
+                Op::Jump(target) => self.frames.last_mut().unwrap().ip = target,
+                Op::JumpIfFalse(target) => {
+                    if !self.peek().is_truthy() {
+                        self.frames.last_mut().unwrap().ip = target;
+                    }
+                }
+                Op::Call { argc, callee } => self.call(argc, callee)?,
+                Op::TailCall { argc, callee } => {
+                    if !self.tail_call(argc)? {
+                        self.call(argc, callee)?;
+                        if let Some(result) = self.return_from_frame() {
+                            return Ok(result);
+                        }
+                    }
+                }
+                Op::Closure(index) => {
+                    let function = self.frame().closure.function.chunk.functions[index].clone();
+                    let upvalues = function
+                        .captures
+                        .iter()
+                        .map(|capture| match *capture {
+                            Capture::Local(slot) => self.capture_upvalue(self.frame().base + slot),
+                            Capture::Upvalue(index) => self.frame().closure.upvalues[index].clone(),
+                        })
+                        .collect();
+                    let closure = Closure { function, upvalues };
+                    let value = Object::Callable(Callable::Closure(Rc::new(closure)));
+                    self.stack.push(value);
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                Op::Return => {
+                    if let Some(result) = self.return_from_frame() {
+                        return Ok(result);
+                    }
+                }
+            }
+        }
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn peek(&self) -> &Object {
+        self.stack.last().unwrap()
+    }
+
+    // 正在执行的指令对应的 token
+    fn token(&self) -> &Token {
+        let frame = self.frame();
+        let token = &frame.closure.function.chunk.tokens[frame.ip - 1];
+        token
+            .as_ref()
+            .expect("instructions that can fail carry a token")
+    }
+
+    fn push_initialized(&mut self, value: Object) -> Result<()> {
+        if let Object::Uninitialized = value {
+            return Err(interpreter::uninitialized_variable(self.token()));
+        }
+
+        self.stack.push(value);
+        Ok(())
+    }
+
+    // 两个数之间的运算直接算，其他情况（字符串拼接、类型错误）交给树遍历解释器的实现
+    fn binary(&mut self, op: fn(f64, f64) -> Literal) -> Result<()> {
+        let right = self.stack.pop().unwrap();
+        let left = self.stack.pop().unwrap();
+
+        let result = match (&left, &right) {
+            (Object::Literal(Literal::Num(a)), Object::Literal(Literal::Num(b))) => {
+                Object::Literal(op(*a, *b))
+            }
+            _ => interpreter::binary(self.token(), &left, &right)?,
+        };
+        self.stack.push(result);
+
+        Ok(())
+    }
+
+    fn call(&mut self, argc: usize, callee: Option<usize>) -> Result<()> {
+        let base = self.stack.len() - argc - 1;
+
+        let callable = match &self.stack[base] {
+            Object::Callable(callable) => callable.clone(),
+            value => {
+                let name = callee.map(|index| self.constant_name(index));
+                return Err(interpreter::not_callable(
+                    self.token(),
+                    name.as_deref(),
+                    value,
+                ));
+            }
+        };
+        callable.check_arity(self.token(), argc)?;
+
+        match callable {
+            Callable::Closure(closure) => {
+                // 脚本本身占了一帧
+                if self.frames.len() > self.max_call_depth {
+                    let max_call_depth = self.max_call_depth;
+                    return Err(interpreter::stack_overflow(self.token(), max_call_depth));
+                }
+                let line = self.token().line;
+                self.frames.push(CallFrame {
+                    closure,
+                    ip: 0,
+                    base,
+                    line,
+                });
+            }
+            native => {
+                let arguments = self.stack.split_off(base + 1);
+                self.stack.pop();
+                let paren = self.token().clone();
+                let result = native.call(&mut self.host, &paren, arguments)?;
+                self.stack.push(result);
+            }
+        }
+
+        Ok(())
+    }
+
+    // 被调用的是 Lox 函数时换掉当前的调用帧，返回 false 时按普通的调用处理
+    fn tail_call(&mut self, argc: usize) -> Result<bool> {
+        let callee = self.stack.len() - argc - 1;
+        let closure = match &self.stack[callee] {
+            Object::Callable(callable @ Callable::Closure(closure)) => {
+                callable.check_arity(self.token(), argc)?;
+                closure.clone()
+            }
+            _ => return Ok(false),
+        };
+
+        let line = self.token().line;
+        let base = self.frame().base;
+        self.close_upvalues(base);
+        self.stack.drain(base..callee);
+        *self.frames.last_mut().unwrap() = CallFrame {
+            closure,
+            ip: 0,
+            base,
+            line,
+        };
+
+        Ok(true)
+    }
+
+    // 弹出当前的调用帧，把返回值留给调用者。脚本本身返回时把值交出去
+    fn return_from_frame(&mut self) -> Option<Object> {
+        let result = self.stack.pop().unwrap();
+        let frame = self.frames.pop().unwrap();
+        self.close_upvalues(frame.base);
+        self.stack.truncate(frame.base);
+
+        if self.frames.is_empty() {
+            return Some(result);
+        }
+        self.stack.push(result);
+        None
+    }
+
+    fn constant_name(&self, index: usize) -> Rc<str> {
+        match &self.frame().closure.function.chunk.constants[index] {
+            Object::Literal(Literal::Str(name)) => name.clone(),
+            constant => unreachable!("{constant} is not a name"),
+        }
+    }
+
+    // 同一个变量只对应一个 Upvalue，捕获它的闭包之间才能看到彼此的修改
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let position = self
+            .open_upvalues
+            .iter()
+            .rposition(|upvalue| open_slot(upvalue) <= slot);
+        if let Some(position) = position {
+            let upvalue = &self.open_upvalues[position];
+            if open_slot(upvalue) == slot {
+                return upvalue.clone();
+            }
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        let index = position.map_or(0, |position| position + 1);
+        self.open_upvalues.insert(index, upvalue.clone());
+        upvalue
+    }
+
+    // 栈上从 from 开始的变量要被丢掉了，把捕获它们的 Upvalue 换成变量当前的值
+    fn close_upvalues(&mut self, from: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = open_slot(upvalue);
+            if slot < from {
+                break;
+            }
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack[slot].clone());
+            self.open_upvalues.pop();
+        }
+    }
+}
+
+fn open_slot(upvalue: &RefCell<Upvalue>) -> usize {
+    match *upvalue.borrow() {
+        Upvalue::Open(slot) => slot,
+        Upvalue::Closed(_) => unreachable!("closed upvalues are not tracked"),
+    }
+}
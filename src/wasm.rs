@@ -0,0 +1,133 @@
+// 给网页里的 playground 用的入口，`--features wasm-bindgen` 时编译：
+//
+//     wasm-pack build -- --features wasm-bindgen
+//     wasm-pack test --node -- --features wasm-bindgen --lib
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use js_sys::Reflect;
+use wasm_bindgen::prelude::*;
+
+use crate::error::{self, Error};
+use crate::{run_source, Interpreter};
+
+// 默认的调用层数上限要 70 多 MiB 的栈，wasm 里的栈只有 .cargo/config.toml 里配的 16 MiB
+const MAX_CALL_DEPTH: usize = 256;
+
+// 浏览器和 node 里都有的高精度计时，单位是毫秒。clock() 只在真正编译到 wasm 时用它
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = performance)]
+    pub(crate) fn now() -> f64;
+}
+
+// 网页里没有终端，程序的输出和错误先写进缓冲区，运行完一起交给 JS
+#[derive(Clone, Default)]
+struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Buffer {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+// 用一个新的解释器运行 `source`，返回 `{ stdout, stderr, status }`。
+// 错误的写法和 status 都和命令行一致：0 是成功，65 是静态错误，70 是运行时错误
+#[wasm_bindgen]
+pub fn run_lox(source: &str) -> JsValue {
+    let stdout = Buffer::default();
+    let mut stderr = Buffer::default();
+
+    let mut interpreter = Interpreter::new();
+    interpreter.set_max_call_depth(MAX_CALL_DEPTH);
+    interpreter.set_writer(Box::new(stdout.clone()));
+    interpreter.set_error_writer(Box::new(stderr.clone()));
+
+    let status = match run_source(source, &mut interpreter) {
+        Ok(_) => 0,
+        Err(errors) => {
+            for error in &errors {
+                let _ = error::runtime_error(&mut stderr, error);
+            }
+            let _ = error::stack_trace(&mut stderr, &interpreter.take_trace());
+            exit_status(&errors)
+        }
+    };
+
+    let result = js_sys::Object::new();
+    let _ = Reflect::set(&result, &"stdout".into(), &stdout.text().into());
+    let _ = Reflect::set(&result, &"stderr".into(), &stderr.text().into());
+    let _ = Reflect::set(&result, &"status".into(), &status.into());
+    result.into()
+}
+
+fn exit_status(errors: &[Error]) -> i32 {
+    match errors.first() {
+        Some(Error::ParseError(..)) | Some(Error::ResolveError(_)) | Some(Error::Incomplete(_)) => {
+            65
+        }
+        _ => 70,
+    }
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use super::*;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    fn field(result: &JsValue, name: &str) -> JsValue {
+        Reflect::get(result, &name.into()).unwrap()
+    }
+
+    #[wasm_bindgen_test]
+    fn print_is_captured() {
+        let result = run_lox("print 1+2;");
+        assert_eq!(field(&result, "stdout").as_string().unwrap(), "3\n");
+        assert_eq!(field(&result, "stderr").as_string().unwrap(), "");
+        assert_eq!(field(&result, "status").as_f64(), Some(0.0));
+    }
+
+    #[wasm_bindgen_test]
+    fn errors_are_reported_like_the_cli() {
+        let result = run_lox("print 1;\nprint -\"a\";");
+        assert_eq!(field(&result, "stdout").as_string().unwrap(), "1\n");
+        assert_eq!(
+            field(&result, "stderr").as_string().unwrap(),
+            "Operand of '-' must be a number, got string (\"a\").\n[line 2, col 7]\n"
+        );
+        assert_eq!(field(&result, "status").as_f64(), Some(70.0));
+
+        let result = run_lox("print ;");
+        assert_eq!(
+            field(&result, "stderr").as_string().unwrap(),
+            "[line 1, col 7] Error at ';': Expect expression.\n"
+        );
+        assert_eq!(field(&result, "status").as_f64(), Some(65.0));
+    }
+
+    #[wasm_bindgen_test]
+    fn deep_recursion_is_a_lox_error() {
+        let result = run_lox("fun f() { f(); }\nf();");
+        assert_eq!(field(&result, "status").as_f64(), Some(70.0));
+    }
+
+    #[wasm_bindgen_test]
+    fn clock_uses_performance_now() {
+        let result = run_lox("var t = clock(); print clock() >= t;");
+        assert_eq!(field(&result, "stdout").as_string().unwrap(), "true\n");
+    }
+}
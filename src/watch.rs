@@ -0,0 +1,143 @@
+use chrono::Local;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+// 一次保存往往产生好几个事件，收到第一个之后等这么久，把其余的一起收掉
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+// 等待下一次改动。测试里换成手动触发的实现，不依赖真实文件系统的时序
+pub trait Changes {
+    // 返回 false 表示不会再有改动了，停止监视
+    fn wait(&mut self) -> bool;
+}
+
+// 用 notify 监视脚本所在的目录。编辑器保存时常常是写一个新文件再改名，
+// 直接监视文件本身的话改名之后就收不到事件了。等有了 import，被导入的文件也要加进来
+pub struct FileChanges {
+    _watcher: RecommendedWatcher, // 离开作用域时停止监视
+    events: Receiver<notify::Result<Event>>,
+    path: PathBuf,
+}
+
+impl FileChanges {
+    pub fn new(path: &Path) -> notify::Result<FileChanges> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // 事件里的路径是监视的目录加上文件名，两边都用规范化的目录才能比较
+        let dir = fs::canonicalize(dir)?;
+        let path = dir.join(path.file_name().unwrap_or_default());
+
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(FileChanges {
+            _watcher: watcher,
+            events,
+            path,
+        })
+    }
+
+    // 只关心脚本被写入或者重新创建，读文件产生的访问事件不算
+    fn affects(&self, event: &Event) -> bool {
+        (event.kind.is_modify() || event.kind.is_create()) && event.paths.contains(&self.path)
+    }
+}
+
+impl Changes for FileChanges {
+    fn wait(&mut self) -> bool {
+        loop {
+            let Ok(event) = self.events.recv() else {
+                return false;
+            };
+            if !event.is_ok_and(|event| self.affects(&event)) {
+                continue;
+            }
+
+            thread::sleep(DEBOUNCE);
+            while self.events.try_recv().is_ok() {}
+            return true;
+        }
+    }
+}
+
+// 运行一次，等下一次改动，再用新的解释器重新运行。每次运行之前清屏并打印带时间的标题，
+// 运行之后打印退出码。run 返回退出码而不是退出进程，出错也不会让监视停下来
+pub fn watch(
+    path: &str,
+    changes: &mut impl Changes,
+    clear_screen: bool,
+    output: &mut impl Write,
+    mut run: impl FnMut() -> i32,
+) -> io::Result<()> {
+    loop {
+        if clear_screen {
+            write!(output, "\x1b[2J\x1b[H")?;
+        }
+        let time = Local::now().format("%H:%M:%S");
+        writeln!(output, "[{time}] Running {path}")?;
+        output.flush()?;
+
+        let status = run();
+        writeln!(output, "[exit status {status}] Waiting for changes...")?;
+        output.flush()?;
+
+        if !changes.wait() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 手动触发的改动：还剩几次改动，用完之后停止监视
+    struct Triggered(usize);
+
+    impl Changes for Triggered {
+        fn wait(&mut self) -> bool {
+            if self.0 == 0 {
+                return false;
+            }
+            self.0 -= 1;
+            true
+        }
+    }
+
+    #[test]
+    fn reruns_after_each_change_and_survives_errors() {
+        let statuses = [0, 70, 65];
+        let mut runs = statuses.iter();
+        let mut output = Vec::new();
+        watch("app.lox", &mut Triggered(2), false, &mut output, || {
+            *runs.next().unwrap()
+        })
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 6, "{output}");
+        for (run, status) in lines.chunks(2).zip(statuses) {
+            assert!(run[0].starts_with('['), "{output}");
+            assert!(run[0].ends_with("] Running app.lox"), "{output}");
+            let finished = format!("[exit status {status}] Waiting for changes...");
+            assert_eq!(run[1], finished);
+        }
+    }
+
+    #[test]
+    fn clears_the_screen_before_each_run() {
+        let mut output = Vec::new();
+        watch("app.lox", &mut Triggered(1), true, &mut output, || 0).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("\x1b[2J\x1b[H").count(), 2);
+        assert!(output.starts_with("\x1b[2J\x1b[H["));
+    }
+}
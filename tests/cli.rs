@@ -0,0 +1,475 @@
+// 命令行：脚本路径可以直接写，也可以用旧的 --run；脚本之后的参数不归 rjlox 管。
+// 查看中间结果的模式和 fixtures/ 里的 golden 文件比较输出
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+fn script(name: &str, source: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&path, source).unwrap();
+    path
+}
+
+fn rjlox(args: &[&str], stdin: impl AsRef<[u8]>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rjlox"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_ref())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn positional_path_runs_script() {
+    let path = script("positional.lox", "print \"hi\";\n");
+    let output = rjlox(&[path.to_str().unwrap()], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "hi\n");
+}
+
+#[test]
+fn run_flag_still_runs_script() {
+    let path = script("run_flag.lox", "print \"hi\";\n");
+    let output = rjlox(&["--run", path.to_str().unwrap()], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "hi\n");
+}
+
+#[test]
+fn flags_before_script_belong_to_rjlox() {
+    let path = script("backend_flag.lox", "print 1 + 2;\n");
+    let output = rjlox(&["--backend=vm", path.to_str().unwrap()], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3\n");
+}
+
+#[test]
+fn arguments_after_script_belong_to_script() {
+    let path = script("script_args.lox", "print \"hi\";\n");
+    let path = path.to_str().unwrap();
+    let output = rjlox(&[path, "a", "--backend=bogus", "-r", "--", "b"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "hi\n");
+}
+
+#[test]
+fn dash_reads_script_from_stdin() {
+    let output = rjlox(&["-"], "var a = 1;\nprint a +\n  2;\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3\n");
+}
+
+// 测试里的标准输入是管道，不是终端，所以不进入 REPL：没有提示符，也不会一行一行地运行
+#[test]
+fn piped_stdin_without_script_runs_as_script() {
+    let output = rjlox(&[], "var a = 1;\nprint a +\n  2;\n");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3\n");
+}
+
+#[test]
+fn stdin_is_named_in_errors() {
+    let output = rjlox(&["-"], [0xff]);
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("'<stdin>' is not valid UTF-8"));
+}
+
+#[test]
+fn dump_tokens_matches_golden() {
+    let path = fixture("tokens.lox");
+    let output = rjlox(&["--dump-tokens", path.to_str().unwrap()], "");
+    assert!(output.status.success());
+    let golden = fs::read_to_string(fixture("tokens.txt")).unwrap();
+    assert_eq!(stdout(&output), golden);
+}
+
+#[test]
+fn dump_tokens_reads_stdin() {
+    let source = fs::read_to_string(fixture("tokens.lox")).unwrap();
+    let output = rjlox(&["--dump-tokens"], &source);
+    assert!(output.status.success());
+    let golden = fs::read_to_string(fixture("tokens.txt")).unwrap();
+    assert_eq!(stdout(&output), golden);
+}
+
+#[test]
+fn dump_ast_matches_golden() {
+    let path = fixture("ast.lox");
+    let output = rjlox(&["--dump-ast", path.to_str().unwrap()], "");
+    assert!(output.status.success());
+    let golden = fs::read_to_string(fixture("ast.txt")).unwrap();
+    assert_eq!(stdout(&output), golden);
+}
+
+#[test]
+fn dump_ast_shows_errors_and_exits_65() {
+    let path = fixture("ast_errors.lox");
+    let output = rjlox(&["--dump-ast", path.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(65));
+    let golden = fs::read_to_string(fixture("ast_errors.txt")).unwrap();
+    assert_eq!(stdout(&output), golden);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("[line 2, col 12] Error at ';': Expect expression."));
+}
+
+// 每一层都包在上一层外面的表达式，prefix 和 suffix 从里往外排。
+// 用一个固定种子的线性同余生成器挑每一层的形状，失败时可以原样重现
+fn deep_expression(seed: u64, layers: usize) -> String {
+    let wrappers = [
+        ("(", ")"),
+        ("-", ""),
+        ("!", ""),
+        ("", " + 1"),
+        ("1 * ", ""),
+        ("", " or false"),
+        ("true and ", ""),
+        ("", " == nil"),
+        ("f(", ")"),
+        ("", "()"),
+        ("a = ", ""),
+    ];
+    let (mut prefix, mut suffix) = (Vec::new(), Vec::new());
+    let mut state = seed;
+    for _ in 0..layers {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let (before, after) = wrappers[(state >> 33) as usize % wrappers.len()];
+        prefix.push(before);
+        suffix.push(after);
+    }
+    prefix.reverse();
+    format!("print {}a{};\n", prefix.concat(), suffix.concat())
+}
+
+// 生成的深层输入在两个后端和 --dump-ast 下都只是一个静态错误，不会把 Rust 的栈撑爆
+#[test]
+fn generated_deep_expressions_are_parse_errors() {
+    let layers = 100_000;
+    let mut sources = vec![
+        format!("print {};\n", vec!["1"; layers].join(" + ")),
+        format!("print {};\n", vec!["false"; layers].join(" or ")),
+        format!("print {};\n", vec!["true"; layers].join(" and ")),
+        format!("print f{};\n", "()".repeat(layers)),
+        format!("print {}1{};\n", "(1 + ".repeat(layers), ")".repeat(layers)),
+    ];
+    sources.extend((1..=4).map(|seed| deep_expression(seed, layers)));
+
+    for (i, source) in sources.iter().enumerate() {
+        let path = script(&format!("deep_{i}.lox"), source);
+        for mode in ["--backend=tree", "--backend=vm", "--dump-ast"] {
+            let output = rjlox(&[mode, path.to_str().unwrap()], "");
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert_eq!(output.status.code(), Some(65), "case {i} {mode}: {stderr}");
+            assert!(
+                stderr.contains("Expression too deeply nested."),
+                "case {i} {mode}"
+            );
+        }
+    }
+
+    // 不太深的链照常运行
+    let source = format!("print {};\n", vec!["1"; 200].join(" + "));
+    let path = script("long_chain.lox", &source);
+    for backend in ["--backend=tree", "--backend=vm"] {
+        let output = rjlox(&[backend, path.to_str().unwrap()], "");
+        assert!(output.status.success(), "{backend}");
+        assert_eq!(stdout(&output), "200\n");
+    }
+}
+
+// 退出码遵循 sysexits：64 参数用错，65 静态错误，66 读不到输入，70 运行时错误
+#[test]
+fn exit_codes_follow_sysexits() {
+    let cases = [
+        ("lex_error.lox", "print \"unterminated;\n", 65),
+        ("syntax_error.lox", "print 1 +;\n", 65),
+        ("resolve_error.lox", "return 1;\n", 65),
+        ("undefined_variable.lox", "print missing;\n", 70),
+    ];
+    for (name, source, code) in cases {
+        let path = script(name, source);
+        let output = rjlox(&[path.to_str().unwrap()], "");
+        assert_eq!(output.status.code(), Some(code), "{name}");
+    }
+
+    let missing = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("no_such_file.lox");
+    let output = rjlox(&[missing.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(66));
+
+    let output = rjlox(&["--no-such-flag"], "");
+    assert_eq!(output.status.code(), Some(64));
+
+    // 脚本之前拼错的选项是用法错误，不当作文件名
+    let path = script("after_bad_flag.lox", "print 1;\n");
+    let output = rjlox(&["--no-such-flag", path.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(64));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn double_dash_ends_rjlox_options() {
+    let path = script("double_dash.lox", "print \"hi\";\n");
+    let output = rjlox(&["--", path.to_str().unwrap(), "--time"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "hi\n");
+    assert_eq!(output.stderr, b"");
+}
+
+#[test]
+fn eval_runs_snippet() {
+    let output = rjlox(&["--eval", "print 1 + 2;"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3\n");
+}
+
+#[test]
+fn eval_reports_errors_like_files() {
+    let output = rjlox(&["-e", "print 1 +;"], "");
+    assert_eq!(output.status.code(), Some(65));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("[line 1, col 10] Error at ';': Expect expression."));
+}
+
+#[test]
+fn eval_snippets_share_state() {
+    let output = rjlox(&["-e", "var a = 1;", "-e", "print a + 2;"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3\n");
+}
+
+#[test]
+fn eval_works_with_dump_modes() {
+    let output = rjlox(&["--dump-ast", "-e", "print 1 + 2 * 3;"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "(print (+ 1 (* 2 3)))\n");
+}
+
+#[test]
+fn dump_ast_spans_show_source_ranges() {
+    let output = rjlox(&["--dump-ast", "--spans", "-e", "print -x;"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "(print (- x@1:8-1:9)@1:7-1:9)@1:1-1:10\n");
+
+    // 没有 --dump-ast 时 --spans 是用法错误
+    let output = rjlox(&["--spans", "-e", "print 1;"], "");
+    assert_eq!(output.status.code(), Some(64));
+}
+
+#[test]
+fn deterministic_runs_repeat_exactly() {
+    let source = "print random();\nvar t = clock();\nprint clock() - t;\nprint now();";
+    let first = rjlox(&["--deterministic", "-e", source], "");
+    assert!(first.status.success());
+    let lines: Vec<String> = stdout(&first).lines().map(String::from).collect();
+    assert_eq!(lines[1..], ["0.001", "946684800002"]);
+
+    let second = rjlox(&["--deterministic", "-e", source], "");
+    assert_eq!(stdout(&second), stdout(&first));
+}
+
+#[test]
+fn time_reports_each_phase() {
+    let output = rjlox(&["--time", "-e", "print 1;"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "1\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let keys: Vec<&str> = stderr
+        .split_whitespace()
+        .map(|field| {
+            let (key, value) = field.split_once('=').unwrap();
+            assert!(value.ends_with("ms"), "{field}");
+            key
+        })
+        .collect();
+    assert_eq!(keys, ["scan", "parse", "resolve", "interpret", "total"]);
+}
+
+#[test]
+fn trace_logs_statements_and_calls() {
+    let source = "fun add(a, b) {\n  return a + b;\n}\nvar x = add(1, 2);\nprint x;\n";
+    let path = script("trace.lox", source);
+    let output = rjlox(&["--trace", path.to_str().unwrap()], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "3\n");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stderr
+        .lines()
+        .map(|line| line.split(']').next().unwrap().trim_start_matches("[line "))
+        .collect();
+    let expected = ["1", "4", "4", "2", "4", "5"];
+    assert_eq!(lines, expected, "{stderr}");
+    assert!(stderr.contains("call add(1, 2)"));
+    assert!(stderr.contains("return from add: 3"));
+}
+
+#[test]
+fn profile_counts_calls_per_function() {
+    let source = "fun a() {}\nfun b() {\n  for (var i = 0; i < 10; i = i + 1) a();\n}\nb();\n";
+    let path = script("profile.lox", source);
+    let output = rjlox(&["--profile", path.to_str().unwrap()], "");
+    assert!(output.status.success());
+
+    // 每行是 `函数名 行号 调用次数 inclusive exclusive`，按耗时排序，所以按名字找
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut lines = stderr.lines();
+    let header: Vec<&str> = lines.next().unwrap().split_whitespace().collect();
+    assert_eq!(
+        header,
+        ["function", "line", "calls", "inclusive", "exclusive"]
+    );
+    let mut rows: Vec<(String, String, String)> = lines
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields[0].into(), fields[1].into(), fields[2].into())
+        })
+        .collect();
+    rows.sort();
+    let expected = [("a", "1", "10"), ("b", "2", "1")]
+        .map(|(name, line, calls)| (name.to_string(), line.to_string(), calls.to_string()));
+    assert_eq!(rows, expected, "{stderr}");
+}
+
+#[test]
+fn prelude_runs_before_script_only_when_asked() {
+    let prelude = script("prelude.lox", "fun double(x) { return x * 2; }\n");
+    let path = script("uses_prelude.lox", "print double(21);\n");
+    let prelude = prelude.to_str().unwrap();
+    let output = rjlox(&["--prelude", prelude, path.to_str().unwrap()], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "42\n");
+
+    // 配置目录里的默认 prelude 只给 REPL 用，运行脚本时不加载
+    let config = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("config");
+    fs::create_dir_all(config.join("rjlox")).unwrap();
+    fs::copy(prelude, config.join("rjlox/prelude.lox")).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rjlox"))
+        .arg(&path)
+        .env("XDG_CONFIG_HOME", &config)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(70));
+
+    let output = rjlox(&["--prelude", "missing.lox", path.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(66));
+}
+
+#[test]
+fn later_files_see_earlier_definitions() {
+    let lib = script("lib.lox", "fun greet(name) { return \"hi \" + name; }\n");
+    let main = script("main.lox", "print greet(\"lox\");\n");
+    let output = rjlox(&[lib.to_str().unwrap(), main.to_str().unwrap(), "arg"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "hi lox\n");
+}
+
+#[test]
+fn static_errors_name_the_file() {
+    let lib = script("lib_ok.lox", "fun greet() { print \"hi\"; }\n");
+    let main = script("main_typo.lox", "greet();\nprnt \"bye\";\n");
+    let output = rjlox(&[lib.to_str().unwrap(), main.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout(&output), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let expected = format!("{}: [line 2, col 1] Error at 'prnt'", main.display());
+    assert!(stderr.contains(&expected), "{stderr}");
+
+    // 前面的文件出错时，后面的文件不再运行
+    let broken = script("lib_broken.lox", "fun greet( {}\n");
+    let main = script("main_prints.lox", "print \"ran\";\n");
+    let output = rjlox(&[broken.to_str().unwrap(), main.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout(&output), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with(broken.to_str().unwrap()), "{stderr}");
+}
+
+// stderr 的每一行都是一个带齐了字段的 JSON 对象
+#[cfg(feature = "serde")]
+fn json_diagnostics(output: &Output) -> Vec<serde_json::Value> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    stderr
+        .lines()
+        .map(|line| {
+            let value: serde_json::Value =
+                serde_json::from_str(line).unwrap_or_else(|e| panic!("not JSON ({e}): {line}"));
+            for key in ["severity", "code", "message", "file", "line", "column"] {
+                assert!(value.get(key).is_some(), "missing {key}: {line}");
+            }
+            value
+        })
+        .collect()
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn json_diagnostics_are_one_object_per_line() {
+    let path = script(
+        "json_runtime.lox",
+        "print \"before\";\n{ var unused = 1; }\nprint -\"a\";\n",
+    );
+    let output = rjlox(&["--json-diagnostics", path.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(stdout(&output), "before\n");
+    let diagnostics = json_diagnostics(&output);
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0]["severity"], "warning");
+    assert_eq!(diagnostics[0]["code"], "W001");
+    assert_eq!(diagnostics[0]["file"], path.to_str().unwrap());
+    assert_eq!(diagnostics[0]["line"], 2);
+    assert_eq!(diagnostics[0]["column"], 7);
+    assert_eq!(diagnostics[1]["severity"], "error");
+    assert!(diagnostics[1]["code"].is_null());
+    assert_eq!(
+        diagnostics[1]["message"],
+        "Operand of '-' must be a number, got string (\"a\")."
+    );
+    assert_eq!(diagnostics[1]["line"], 3);
+
+    // 语法错误一个一行；扫描和解析阶段的错误也一样
+    let output = rjlox(&["--json-diagnostics", "-"], "print ;\nvar = 1;\n");
+    assert_eq!(output.status.code(), Some(65));
+    let diagnostics = json_diagnostics(&output);
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0]["message"], "Expect expression.");
+    assert_eq!(diagnostics[0]["file"], "<stdin>");
+    assert_eq!(diagnostics[1]["line"], 2);
+
+    let output = rjlox(&["--json-diagnostics", "-e", "\"open"], "");
+    assert_eq!(output.status.code(), Some(65));
+    let diagnostics = json_diagnostics(&output);
+    assert_eq!(diagnostics[0]["message"], "Unterminated string.");
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn dump_ast_json_matches_golden() {
+    let path = fixture("ast_json.lox");
+    let output = rjlox(&["--dump-ast-json", path.to_str().unwrap()], "");
+    assert!(output.status.success());
+    let golden = fs::read_to_string(fixture("ast_json.json")).unwrap();
+    assert_eq!(stdout(&output), golden);
+}
@@ -0,0 +1,230 @@
+// 只通过库的公开接口运行 Lox，和嵌入 rjlox 的程序用的是同一套东西
+
+use rjlox::sources::{RandomSource, TimeSource};
+use rjlox::token::Literal;
+use rjlox::{run_source, Error, Interpreter, Object, Parser, Resolver, Scanner};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+#[test]
+fn pipeline_step_by_step() {
+    let source = "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\nfib(10);";
+    let tokens = Scanner::new(source.to_string()).scan_tokens();
+    let (statements, errors) = Parser::new(tokens).parse();
+    assert!(errors.is_empty(), "{errors:?}");
+
+    let mut interpreter = Interpreter::new();
+    let mut resolver = Resolver::new(&mut interpreter, true);
+    resolver.resolve(&statements).unwrap();
+    assert!(resolver.diagnostics().is_empty());
+
+    let value = interpreter.interpret(statements).unwrap();
+    assert_eq!(value, Object::Literal(Literal::Num(55.0)));
+}
+
+#[test]
+fn run_source_keeps_globals_between_calls() {
+    let mut interpreter = Interpreter::new();
+    let value = run_source("var greeting = \"hello\";", &mut interpreter).unwrap();
+    assert_eq!(value, Object::Literal(Literal::Nil));
+
+    let value = run_source("greeting + \", lox\";", &mut interpreter).unwrap();
+    assert_eq!(value.to_string(), "hello, lox");
+}
+
+#[test]
+fn run_source_returns_the_errors_of_the_failing_phase() {
+    let mut interpreter = Interpreter::new();
+
+    let errors = run_source("print 1 +;\nprint (;", &mut interpreter).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert!(errors.iter().all(|e| matches!(e, Error::ParseError(..))));
+    assert_eq!(
+        errors[0].to_string(),
+        "[line 1, col 10] Error at ';': Expect expression."
+    );
+
+    let errors = run_source("return 1;", &mut interpreter).unwrap_err();
+    assert!(matches!(errors[..], [Error::ResolveError(_)]), "{errors:?}");
+
+    let errors = run_source("print missing;", &mut interpreter).unwrap_err();
+    assert!(
+        matches!(errors[..], [Error::RuntimeError(..)]),
+        "{errors:?}"
+    );
+    assert!(errors[0]
+        .to_string()
+        .starts_with("Undefined variable 'missing'."));
+}
+
+fn define_double(interpreter: &mut Interpreter) {
+    interpreter.define_native("double", 1, |_, arguments| match &arguments[0] {
+        Object::Literal(Literal::Num(n)) => Ok(Object::Literal(Literal::Num(n * 2.0))),
+        value => Err(Error::NativeError(format!(
+            "double: expected a number but got {value}."
+        ))),
+    });
+}
+
+#[test]
+fn host_natives_are_called_from_lox() {
+    let mut interpreter = Interpreter::new();
+    define_double(&mut interpreter);
+
+    let value = run_source("double(double(5));", &mut interpreter).unwrap();
+    assert_eq!(value, Object::Literal(Literal::Num(20.0)));
+    let value = run_source("double;", &mut interpreter).unwrap();
+    assert_eq!(value.to_string(), "<native fn double>");
+    let value = run_source("clock;", &mut interpreter).unwrap();
+    assert_eq!(value.to_string(), "<native fn clock>");
+}
+
+#[test]
+fn host_native_errors_point_at_the_call() {
+    let mut interpreter = Interpreter::new();
+    define_double(&mut interpreter);
+
+    let errors = run_source("var x = 1;\nprint double(\"two\");", &mut interpreter).unwrap_err();
+    let [Error::RuntimeError(token, message)] = &errors[..] else {
+        panic!("{errors:?}");
+    };
+    assert_eq!(message, "double: expected a number but got two.");
+    assert_eq!((token.line, token.column), (2, 19));
+
+    let errors = run_source("double(1, 2);", &mut interpreter).unwrap_err();
+    assert_eq!(
+        errors[0].to_string(),
+        "Expected 1 arguments but got 2.\n[line 1, col 12]"
+    );
+}
+
+#[test]
+fn lox_functions_are_called_from_rust() {
+    let mut interpreter = Interpreter::new();
+    let source = "fun counter() {
+  var total = 0;
+  fun handle(event) {
+    total = total + event;
+    return total;
+  }
+  return handle;
+}
+var handle = counter();
+fun fail(event) {
+  return event + nil;
+}";
+    run_source(source, &mut interpreter).unwrap();
+
+    // 闭包里的 total 在两次调用之间保留下来
+    let handle = interpreter.get_global("handle").unwrap();
+    let number = |n| Object::Literal(Literal::Num(n));
+    let result = interpreter.call_function(&handle, vec![number(5.0)]);
+    assert_eq!(result.unwrap(), number(5.0));
+    let result = interpreter.call_function(&handle, vec![number(10.0)]);
+    assert_eq!(result.unwrap(), number(15.0));
+
+    let fail = interpreter.get_global("fail").unwrap();
+    match interpreter.call_function(&fail, vec![number(1.0)]) {
+        Err(Error::RuntimeError(token, _)) => assert_eq!(token.line, 11),
+        result => panic!("expected a runtime error, got {result:?}"),
+    }
+    let error = interpreter.call_function(&handle, vec![]).unwrap_err();
+    assert!(error
+        .to_string()
+        .starts_with("Expected 1 arguments but got 0."));
+
+    // 虚拟机里定义的函数交给树遍历解释器调用时报错，而不是 panic
+    let mut vm = rjlox::vm::Vm::new();
+    let tokens = Scanner::new(String::from("fun f() { return 1; }")).scan_tokens();
+    let (statements, _) = Parser::new(tokens).parse();
+    vm.interpret(&statements).unwrap();
+    let closure = vm.globals().lookup("f").unwrap();
+    let error = interpreter.call_function(&closure, vec![]).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Cannot call a VM closure from the tree interpreter.\n[line 0, col 0]"
+    );
+
+    assert!(interpreter.get_global("missing").is_none());
+    let clock = interpreter.get_global("clock").unwrap();
+    assert!(interpreter.call_function(&clock, vec![]).is_ok());
+}
+
+// 每读一次前进整整一秒的时钟
+struct Ticking(f64);
+
+impl TimeSource for Ticking {
+    fn clock(&mut self) -> f64 {
+        self.0 += 1.0;
+        self.0
+    }
+
+    fn now(&mut self) -> i64 {
+        (self.clock() * 1000.0) as i64
+    }
+}
+
+struct Sequence(Vec<f64>);
+
+impl RandomSource for Sequence {
+    fn random(&mut self) -> f64 {
+        self.0.remove(0)
+    }
+}
+
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn time_and_random_sources_are_injectable() {
+    let output = Captured::default();
+    let mut interpreter = Interpreter::new();
+    interpreter.set_writer(Box::new(output.clone()));
+    interpreter.set_time_source(Box::new(Ticking(0.0)));
+    interpreter.set_random_source(Box::new(Sequence(vec![0.25, 0.5])));
+
+    let source = r#"
+        var start = clock();
+        var sum = 0;
+        for (var i = 0; i < 100; i = i + 1) sum = sum + i;
+        print sum;
+        print clock() - start;
+        print now();
+        print random() + random();
+    "#;
+    run_source(source, &mut interpreter).unwrap();
+    let output = String::from_utf8(output.0.borrow().clone()).unwrap();
+    assert_eq!(output, "4950\n1\n3000\n0.75\n");
+}
+
+// 语法树转成 JSON 再读回来，还能照样解析变量、运行
+#[cfg(feature = "serde")]
+#[test]
+fn syntax_tree_round_trips_through_json() {
+    use rjlox::stmt::Stmt;
+
+    let source = "fun half(n) { return n / 2; }\nvar x = half(0.1 + 0.2);\nx;";
+    let tokens = Scanner::new(source.to_string()).scan_tokens();
+    let (statements, _) = Parser::new(tokens).parse();
+    let json = serde_json::to_string(&statements).unwrap();
+    let statements: Vec<Stmt> = serde_json::from_str(&json).unwrap();
+    assert_eq!(serde_json::to_string(&statements).unwrap(), json);
+
+    let mut interpreter = Interpreter::new();
+    Resolver::new(&mut interpreter, true)
+        .resolve(&statements)
+        .unwrap();
+    let value = interpreter.interpret(statements).unwrap();
+    assert_eq!(value, Object::Literal(Literal::Num((0.1 + 0.2) / 2.0)));
+}